@@ -0,0 +1,190 @@
+use std::{
+	fs::{create_dir_all, read_dir, remove_file, File, OpenOptions},
+	io::{BufWriter, Write},
+	path::{Path, PathBuf},
+	sync::OnceLock,
+};
+
+use common::Hash;
+use tempfile::NamedTempFile;
+
+const LOCK_FILE: &str = "lock";
+const TEMP_DIR: &str = "tmp";
+const PARTIAL_DIR: &str = "partial";
+
+/// zstd level new cache objects are compressed with. Set once at startup;
+/// objects are written uncompressed when unset.
+static OBJECT_COMPRESSION: OnceLock<i32> = OnceLock::new();
+
+pub fn set_object_compression(level: i32) {
+	let _ = OBJECT_COMPRESSION.set(level);
+}
+
+/// Exclusive lock over a local object cache. Held for the duration of any
+/// command that writes objects so that two concurrent `arx` invocations on the
+/// same cache don't interleave their writes. Released when dropped.
+pub struct CacheLock {
+	_file: File,
+}
+
+impl CacheLock {
+	pub fn acquire(cache: &Path) -> std::io::Result<Self> {
+		create_dir_all(cache)?;
+
+		let file = OpenOptions::new()
+			.create(true)
+			.truncate(false)
+			.write(true)
+			.open(cache.join(LOCK_FILE))?;
+
+		if file.try_lock().is_err() {
+			eprintln!("Waiting for another arx process to release the cache lock");
+			file.lock()?;
+		}
+
+		// Holding the lock means nobody else is mid-write, so anything left in
+		// the temp directory was abandoned by a crashed or interrupted run.
+		if let Ok(entries) = read_dir(cache.join(TEMP_DIR)) {
+			for entry in entries.filter_map(|x| x.ok()) {
+				let _ = remove_file(entry.path());
+			}
+		}
+
+		Ok(Self { _file: file })
+	}
+}
+
+/// Where the body of an interrupted download of `hash` is kept. Unlike the
+/// temporary files of [`write_object`] these survive between runs, so a later
+/// pull can resume the download rather than start over.
+pub fn partial_path(cache: &Path, hash: &Hash) -> std::io::Result<PathBuf> {
+	let dir = cache.join(PARTIAL_DIR);
+	create_dir_all(&dir)?;
+
+	Ok(dir.join(hash.as_str()))
+}
+
+/// Write the object for `hash` into `cache` unless it already exists.
+///
+/// The object is written to a temporary file inside the cache, synced to disk
+/// and then renamed into its content-addressed location, so a crash part way
+/// through can never leave a truncated object behind at the final path.
+pub fn write_object(
+	cache: &Path,
+	hash: &Hash,
+	write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<PathBuf> {
+	write_object_with(cache, hash, OBJECT_COMPRESSION.get().copied(), write)
+}
+
+/// [`write_object`] with an explicit zstd level rather than the process wide one
+pub fn write_object_with(
+	cache: &Path,
+	hash: &Hash,
+	compression: Option<i32>,
+	write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<PathBuf> {
+	let path = hash.get_path(cache);
+
+	if path.exists() {
+		return Ok(path);
+	}
+
+	let temp_dir = cache.join(TEMP_DIR);
+	create_dir_all(&temp_dir)?;
+	create_dir_all(path.parent().expect("object path to have a parent"))?;
+
+	let temp = NamedTempFile::new_in(&temp_dir)?;
+
+	{
+		let mut writer = BufWriter::new(temp.as_file());
+
+		match compression {
+			Some(level) => {
+				let mut encoder = zstd::stream::write::Encoder::new(&mut writer, level)?;
+				write(&mut encoder)?;
+				encoder.finish()?;
+			}
+			None => write(&mut writer)?,
+		}
+
+		writer.flush()?;
+	}
+
+	temp.as_file().sync_all()?;
+	temp.persist(&path).map_err(|err| err.error)?;
+
+	Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::{open_object_file, read_header_from_file, ZSTD_MAGIC};
+	use std::io::Read;
+	use tempfile::TempDir;
+
+	#[test]
+	fn failed_write_leaves_no_object_behind() {
+		let cache = TempDir::new().unwrap();
+		let hash = Hash::from([7u8; 32]);
+
+		let result = write_object(cache.path(), &hash, |writer| {
+			writer.write_all(b"blob 10\0hel")?;
+			Err(std::io::Error::other("interrupted"))
+		});
+
+		assert!(result.is_err());
+		assert!(!hash.get_path(cache.path()).exists());
+		assert_eq!(read_dir(cache.path().join(TEMP_DIR)).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn existing_objects_are_not_rewritten() {
+		let cache = TempDir::new().unwrap();
+		let hash = Hash::from([7u8; 32]);
+
+		write_object(cache.path(), &hash, |writer| writer.write_all(b"first")).unwrap();
+		write_object(cache.path(), &hash, |writer| writer.write_all(b"second")).unwrap();
+
+		assert_eq!(
+			std::fs::read(hash.get_path(cache.path())).unwrap(),
+			b"first"
+		);
+	}
+
+	#[test]
+	fn compressed_objects_read_back_transparently() {
+		let cache = TempDir::new().unwrap();
+		let hash = Hash::from([7u8; 32]);
+		let body = [b'a'; 4096];
+
+		let path = write_object_with(cache.path(), &hash, Some(3), |writer| {
+			writer.write_all(b"blob 4096\0")?;
+			writer.write_all(&body)
+		})
+		.unwrap();
+
+		assert!(std::fs::read(&path).unwrap().starts_with(&ZSTD_MAGIC));
+
+		let mut reader = open_object_file(&path).unwrap();
+		let header = read_header_from_file(&mut reader).unwrap();
+		let mut data = Vec::new();
+		reader.read_to_end(&mut data).unwrap();
+
+		assert_eq!(header.size, 4096);
+		assert_eq!(data, body);
+	}
+
+	#[test]
+	fn acquiring_the_lock_clears_abandoned_temp_files() {
+		let cache = TempDir::new().unwrap();
+		let temp_dir = cache.path().join(TEMP_DIR);
+		create_dir_all(&temp_dir).unwrap();
+		std::fs::write(temp_dir.join(".tmpabandoned"), b"blob 10\0hel").unwrap();
+
+		let _lock = CacheLock::acquire(cache.path()).unwrap();
+
+		assert_eq!(read_dir(&temp_dir).unwrap().count(), 0);
+	}
+}
@@ -1,27 +1,41 @@
 #![allow(dead_code)]
+use anyhow::Context;
 use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 use common::{
 	archive::{
 		Archive, ArchiveBody, ArchiveEntryData, ArchiveHeaderEntry, CompressionAlgorithm,
-		CompressionLevel, FileEntryData, RawEntryData, SourceFileEntryData, HEADER,
+		CompressionLevel, FileEntryData, RawEntryData, SourceChunkEntryData, SourceFileEntryData,
+		HEADER,
 	},
-	object_body::Object as OtherObject,
-	read_header_and_body, read_header_from_file, read_header_from_slice,
-	read_object_into_headers_sync, Hash, Header, Mode, ObjectType, BLOB_KEY, INDEX_KEY, TREE_KEY,
+	chunk::Chunker,
+	encryption::{decrypt_object, encrypt_object, Keyring},
+	object_body::{ChunkList, ChunkListEntry, Object as OtherObject},
+	open_object_file, read_header_and_body, read_header_from_file, read_object_into_headers_sync,
+	signature::{describe_key, read_signing_key, read_trusted_keys, sign_index, verify_index},
+	Hash, Header, Mode, ObjectType, BLOB_KEY, CHUNK_LIST_KEY, INDEX_KEY, TREE_KEY,
 };
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 use std::{
-	collections::HashMap,
-	fs::{create_dir, create_dir_all, read_dir, File},
-	io::{BufRead, BufReader, BufWriter, Read, Write},
+	collections::{HashMap, HashSet},
+	fs::{create_dir, create_dir_all, read_dir, remove_file, File, OpenOptions},
+	io::{BufRead, BufReader, BufWriter, Cursor, Read, Write},
+	num::NonZeroUsize,
 	ops::Deref,
 	path::{Path, PathBuf},
 	str::from_utf8,
+	sync::{Mutex, OnceLock},
+	time::Duration,
 };
 use ureq::SendBody;
 
+use crate::cache::{partial_path, set_object_compression, write_object, CacheLock};
+use crate::remote::{describe_error, Remote, RemoteConfig, RemoteTls};
+
+mod cache;
+mod remote;
+
 #[derive(Debug)]
 struct Hashed<T: Object> {
 	inner: T,
@@ -66,7 +80,7 @@ trait Object {
 	fn get_object_type(&self) -> ObjectType;
 	fn get_hash(&self) -> Hash;
 	fn get_prefix(&self) -> String;
-	fn write_to(&self, path: &Path);
+	fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()>;
 
 	// fn from_file(cache: &PathBuf, file: &PathBuf) -> Self;
 
@@ -102,9 +116,8 @@ struct CacheObject<'a> {
 }
 
 impl<'a> CacheObject<'a> {
-	fn from_file(cache: &'a PathBuf, file_path: &PathBuf) -> Self {
-		let file = File::open(file_path).unwrap();
-		let mut file = BufReader::new(file);
+	fn from_file(cache: &'a PathBuf, file_path: &Path) -> Self {
+		let mut file = open_object_file(file_path).unwrap();
 
 		let mut data = Vec::new();
 		file.read_until(b'\0', &mut data).unwrap();
@@ -123,18 +136,17 @@ impl<'a> CacheObject<'a> {
 
 		Self {
 			cache,
-			file: file_path.clone(),
+			file: file_path.to_path_buf(),
 			size: size.parse().unwrap(),
 			object_type,
 			hash,
 		}
 	}
 
-	fn to_index(&self) -> Hashed<Index> {
+	fn to_index(&self) -> anyhow::Result<Hashed<Index>> {
 		assert!(self.object_type == ObjectType::Index);
 
-		let file = File::open(&self.file).unwrap();
-		let mut file: BufReader<File> = BufReader::new(file);
+		let mut file = open_object_file(&self.file).unwrap();
 
 		let mut data = Vec::new();
 		file.read_until(b'\0', &mut data).unwrap();
@@ -164,22 +176,21 @@ impl<'a> CacheObject<'a> {
 
 		assert!(tree_object.get_object_type() == ObjectType::Tree);
 
-		Hashed {
+		Ok(Hashed {
 			hash: self.hash.clone(),
 			inner: Index {
 				timestamp: timestamp.into(),
-				tree: tree_object.to_tree(Mode::Tree, ""),
+				tree: tree_object.to_tree(Mode::Tree, "")?,
 			},
-		}
+		})
 	}
 
-	fn to_tree(&self, mode: Mode, path: &str) -> Hashed<Tree> {
+	fn to_tree(&self, mode: Mode, path: &str) -> anyhow::Result<Hashed<Tree>> {
 		assert!(self.object_type == ObjectType::Tree);
 
 		// println!("Reading tree {}", self.hash);
 
-		let file = File::open(&self.file).unwrap();
-		let mut file: BufReader<File> = BufReader::new(file);
+		let mut file = open_object_file(&self.file).unwrap();
 
 		// Read out the file header
 		let _ = read_header_from_file(&mut file).expect("File header to be correct");
@@ -213,26 +224,26 @@ impl<'a> CacheObject<'a> {
 
 			vec.push(match cache_object.object_type {
 				ObjectType::Blob => TreeObject::Blob(cache_object.to_blob(mode, name)),
-				ObjectType::Tree => TreeObject::Tree(cache_object.to_tree(mode, name)),
-				ObjectType::Index => panic!("Invalid ObjectType in tree"),
+				ObjectType::Tree => TreeObject::Tree(cache_object.to_tree(mode, name)?),
+				ObjectType::ChunkList => TreeObject::Chunked(cache_object.to_chunked(mode, name)?),
+				ObjectType::Index | ObjectType::Encrypted => panic!("Invalid ObjectType in tree"),
 			})
 		}
 
-		Hashed {
+		Ok(Hashed {
 			hash: self.hash.clone(),
 			inner: Tree {
 				mode,
 				path: path.to_owned(),
 				contents: vec,
 			},
-		}
+		})
 	}
 
 	fn to_blob(&self, mode: Mode, path: &str) -> Hashed<Blob> {
 		assert!(self.object_type == ObjectType::Blob);
 
-		let file = File::open(&self.file).unwrap();
-		let mut file: BufReader<File> = BufReader::new(file);
+		let mut file = open_object_file(&self.file).unwrap();
 
 		// Read out the file header
 		let Header { size, .. } =
@@ -249,6 +260,33 @@ impl<'a> CacheObject<'a> {
 			},
 		}
 	}
+
+	fn to_chunked(&self, mode: Mode, path: &str) -> anyhow::Result<Hashed<ChunkedBlob>> {
+		assert!(self.object_type == ObjectType::ChunkList);
+
+		let mut file = open_object_file(&self.file).unwrap();
+
+		// Read out the file header
+		let _ = read_header_from_file(&mut file).expect("File header to be correct");
+
+		let mut data = Vec::new();
+		file.read_to_end(&mut data)
+			.expect("Chunk list to be readable");
+
+		let chunks = ChunkList::parse(&data)
+			.with_context(|| format!("Chunk list {} is malformed", self.hash))?;
+
+		Ok(Hashed {
+			hash: self.hash.clone(),
+			inner: ChunkedBlob {
+				mode,
+				path: path.to_string(),
+				file: self.file.clone(),
+				size: chunks.size(),
+				chunks,
+			},
+		})
+	}
 }
 
 impl<'a> Object for CacheObject<'a> {
@@ -264,7 +302,7 @@ impl<'a> Object for CacheObject<'a> {
 		format!("{} {}\0", self.object_type.to_str(), self.size)
 	}
 
-	fn write_to(&self, _: &Path) {
+	fn write_to(&self, _: &mut dyn Write) -> std::io::Result<()> {
 		unimplemented!("Should probably fix this")
 	}
 }
@@ -315,11 +353,9 @@ impl Object for Index {
 		format!("{} {}\0", INDEX_KEY, self.get_body().len())
 	}
 
-	fn write_to(&self, path: &Path) {
-		let mut file = File::create(path).unwrap();
-
-		file.write_all(self.get_prefix().as_bytes()).unwrap();
-		file.write_all(self.get_body().as_bytes()).unwrap();
+	fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+		writer.write_all(self.get_prefix().as_bytes())?;
+		writer.write_all(self.get_body().as_bytes())
 	}
 
 	// fn from_file(cache: &PathBuf, index: &PathBuf) -> Index {
@@ -380,6 +416,11 @@ impl Tree {
 			.map(|path| {
 				if path.is_dir() {
 					TreeObject::Tree(Tree::from_dir(path, cache))
+				} else if CHUNK_THRESHOLD
+					.get()
+					.is_some_and(|threshold| path.metadata().unwrap().len() > *threshold)
+				{
+					TreeObject::Chunked(ChunkedBlob::hash_and_write(path, cache))
 				} else {
 					TreeObject::Blob(Blob::hash_and_write(path, cache))
 				}
@@ -433,11 +474,9 @@ impl Object for Tree {
 		Hash::from(hasher)
 	}
 
-	fn write_to(&self, path: &Path) {
-		let mut file = File::create(path).unwrap();
-
-		file.write_all(self.get_prefix().as_bytes()).unwrap();
-		file.write_all(&self.get_body()).unwrap();
+	fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+		writer.write_all(self.get_prefix().as_bytes())?;
+		writer.write_all(&self.get_body())
 	}
 
 	fn get_prefix(&self) -> String {
@@ -506,13 +545,11 @@ impl Blob {
 		let hash = Hash::from(hasher);
 
 		if let Some(cache) = cache {
-			let dest = hash.get_path(cache);
-			if !dest.exists() {
-				create_dir_all(dest.parent().unwrap()).unwrap();
-				let mut out = File::create(&dest).unwrap();
-				out.write_all(prefix.as_bytes()).unwrap();
-				out.write_all(&content).unwrap();
-			}
+			write_object(cache, &hash, |writer| {
+				writer.write_all(prefix.as_bytes())?;
+				writer.write_all(&content)
+			})
+			.expect("Blob to be written to the cache");
 		}
 
 		Hashed {
@@ -562,13 +599,12 @@ impl Object for Blob {
 		Hash::from(hasher)
 	}
 
-	fn write_to(&self, path: &Path) {
-		let mut file = File::create(path).unwrap();
+	fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+		writer.write_all(self.get_prefix().as_bytes())?;
 
-		file.write_all(self.get_prefix().as_bytes()).unwrap();
-
-		let mut src = File::open(&self.file).unwrap();
-		std::io::copy(&mut src, &mut file).unwrap();
+		let mut src = File::open(&self.file)?;
+		std::io::copy(&mut src, writer)?;
+		Ok(())
 	}
 
 	fn get_prefix(&self) -> String {
@@ -576,10 +612,108 @@ impl Object for Blob {
 	}
 }
 
+/// Files larger than this many bytes are committed as chunk lists rather than
+/// a single blob. Set once at startup; unset means files are never chunked.
+static CHUNK_THRESHOLD: OnceLock<u64> = OnceLock::new();
+
+/// A large file split into content-defined chunks, each stored as a blob, so a
+/// small change to the file only adds the chunks around the change.
+#[derive(Debug)]
+struct ChunkedBlob {
+	mode: Mode,
+	path: String,
+	/// Source file when committing, or the chunk list object when read from a cache
+	file: PathBuf,
+	size: u64,
+	chunks: ChunkList,
+}
+
+impl ChunkedBlob {
+	/// Chunk the file, writing each chunk's blob and then the chunk list to
+	/// `cache`. Only one chunk is held in memory at a time.
+	fn hash_and_write(src: &Path, cache: Option<&Path>) -> Hashed<Self> {
+		assert!(src.is_file());
+
+		let f = File::open(src).unwrap();
+		let mut chunks = Vec::new();
+
+		for chunk in Chunker::new(BufReader::new(f)) {
+			let chunk = chunk.expect("File to be readable");
+			let prefix = format!("{} {}\0", BLOB_KEY, chunk.len());
+
+			let mut hasher = Sha256::new();
+			hasher.write_all(prefix.as_bytes()).unwrap();
+			hasher.write_all(&chunk).unwrap();
+			let hash = Hash::from(hasher);
+
+			if let Some(cache) = cache {
+				write_object(cache, &hash, |writer| {
+					writer.write_all(prefix.as_bytes())?;
+					writer.write_all(&chunk)
+				})
+				.expect("Chunk to be written to the cache");
+			}
+
+			chunks.push(ChunkListEntry {
+				hash,
+				size: chunk.len() as u64,
+			});
+		}
+
+		let chunks = ChunkList { chunks };
+		let hashed = Hashed::from_object(Self {
+			mode: Mode::Normal,
+			path: src.file_name().unwrap().to_string_lossy().to_string(),
+			file: src.to_path_buf(),
+			size: chunks.size(),
+			chunks,
+		});
+
+		if let Some(cache) = cache {
+			hashed.write_if_not_exists(cache);
+		}
+
+		hashed
+	}
+}
+
+impl WithPath for ChunkedBlob {
+	fn get_path_component(&self) -> &String {
+		&self.path
+	}
+
+	fn get_mode(&self) -> &Mode {
+		&self.mode
+	}
+}
+
+impl Object for ChunkedBlob {
+	fn get_object_type(&self) -> ObjectType {
+		ObjectType::ChunkList
+	}
+
+	fn get_hash(&self) -> Hash {
+		let mut hasher = Sha256::new();
+		hasher.write_all(self.get_prefix().as_bytes()).unwrap();
+		hasher.write_all(&self.chunks.to_data()).unwrap();
+		Hash::from(hasher)
+	}
+
+	fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+		writer.write_all(self.get_prefix().as_bytes())?;
+		writer.write_all(&self.chunks.to_data())
+	}
+
+	fn get_prefix(&self) -> String {
+		format!("{} {}\0", CHUNK_LIST_KEY, self.chunks.chunks.len() * 40)
+	}
+}
+
 #[derive(Debug)]
 enum TreeObject {
 	Tree(Hashed<Tree>),
 	Blob(Hashed<Blob>),
+	Chunked(Hashed<ChunkedBlob>),
 }
 
 trait ObjectWithPath: WithPath + Object {}
@@ -589,6 +723,7 @@ impl TreeObject {
 		match self {
 			Self::Tree(tree) => get_bytes_from_thing(tree.deref(), &tree.hash),
 			Self::Blob(blob) => get_bytes_from_thing(blob.deref(), &blob.hash),
+			Self::Chunked(blob) => get_bytes_from_thing(blob.deref(), &blob.hash),
 		}
 	}
 
@@ -596,6 +731,7 @@ impl TreeObject {
 		match self {
 			Self::Tree(tree) => tree.get_path_component(),
 			Self::Blob(blob) => blob.get_path_component(),
+			Self::Chunked(blob) => blob.get_path_component(),
 		}
 	}
 }
@@ -614,15 +750,8 @@ fn get_bytes_from_thing<T: WithPath>(object: &T, hash: &Hash) -> Vec<u8> {
 
 impl<T: Object> Hashed<T> {
 	fn write_if_not_exists(&self, dir: &Path) {
-		let path = self.hash.get_path(dir);
-		let dir = path.parent().unwrap();
-
-		let _ = create_dir_all(dir);
-
-		if !path.exists() {
-			// println!("writing {:?} {:?}", T::get_object_type(), path);
-			self.write_to(&path);
-		}
+		write_object(dir, &self.hash, |writer| self.write_to(writer))
+			.expect("Object to be written to the cache");
 	}
 }
 
@@ -633,6 +762,7 @@ fn get_total_size(index: &Hashed<Tree>) -> u128 {
 		total += match element {
 			TreeObject::Tree(tree) => get_total_size(tree),
 			TreeObject::Blob(blob) => blob.size as u128,
+			TreeObject::Chunked(blob) => blob.size as u128,
 		}
 	}
 
@@ -653,6 +783,8 @@ fn commit_directory(cache: &PathBuf, path: &PathBuf) {
 		panic!("unable to canonicalize {path:?}");
 	};
 
+	let _lock = CacheLock::acquire(cache).expect("Cache lock to be acquired");
+
 	let index = Index::from_path(&path, Some(cache));
 
 	println!(
@@ -663,7 +795,23 @@ fn commit_directory(cache: &PathBuf, path: &PathBuf) {
 	println!("{}", index.hash);
 }
 
-fn restore_directory(cache: &PathBuf, path: &PathBuf, index: Hash, validate: bool) {
+fn restore_directory(
+	cache: &PathBuf,
+	path: &PathBuf,
+	index: Hash,
+	validate: bool,
+	trusted_keys: Option<&Path>,
+) {
+	if let Some(trusted_keys) = trusted_keys {
+		match verify_cached_index(cache, &index, trusted_keys) {
+			Ok(signer) => println!("Index signed by {signer}"),
+			Err(err) => {
+				eprintln!("Refusing to restore {index}: {err}");
+				std::process::exit(1);
+			}
+		}
+	}
+
 	if !path.exists() {
 		create_dir_all(path).expect("Directory Creation to work");
 	}
@@ -679,17 +827,74 @@ fn restore_directory(cache: &PathBuf, path: &PathBuf, index: Hash, validate: boo
 	let index_path = index.get_path(cache);
 	let index_cache = Hashed::from_object(CacheObject::from_file(cache, &index_path));
 
-	let index = index_cache.to_index();
+	let index = match index_cache.to_index() {
+		Ok(index) => index,
+		Err(err) => {
+			eprintln!("Unable to restore {index}: {err:#}");
+			std::process::exit(1);
+		}
+	};
 
 	// println!("{index:?}");
 
-	write_tree(&index.tree, path);
+	write_tree(cache, &index.tree, path);
 
 	if validate {
 		validate_tree(&index.tree, path);
 	}
 }
 
+/// Read an index out of the cache, including its metadata
+fn read_cached_index(cache: &Path, hash: &Hash) -> anyhow::Result<common::object_body::Index> {
+	let mut data = Vec::new();
+	open_object_file(&hash.get_path(cache))
+		.map_err(|err| anyhow::anyhow!("Unable to open index {hash}: {err}"))?
+		.read_to_end(&mut data)?;
+
+	let (header, body) =
+		read_header_and_body(&data).ok_or_else(|| anyhow::anyhow!("Object {hash} is corrupt"))?;
+
+	if header.object_type != ObjectType::Index {
+		anyhow::bail!("Object {hash} is not an index");
+	}
+
+	Ok(common::object_body::Index::from_data(body))
+}
+
+/// Sign an index in the cache with the OpenSSH private key at `key`. The
+/// signature is part of the index's metadata, so this writes a new index and
+/// returns its hash.
+fn sign_cached_index(cache: &Path, hash: &Hash, key: &Path) -> anyhow::Result<Hash> {
+	let _lock = CacheLock::acquire(cache)?;
+
+	let mut index = read_cached_index(cache, hash)?;
+	sign_index(&mut index, &read_signing_key(key)?)?;
+
+	let body = index.to_data();
+	let prefix = format!("{INDEX_KEY} {}\0", body.len());
+
+	let mut hasher = Sha256::new();
+	hasher.update(prefix.as_bytes());
+	hasher.update(&body);
+	let signed = Hash::from(hasher);
+
+	write_object(cache, &signed, |writer| {
+		writer.write_all(prefix.as_bytes())?;
+		writer.write_all(&body)
+	})?;
+
+	Ok(signed)
+}
+
+/// Check an index in the cache is signed by one of the keys in the
+/// `trusted_keys` file, returning a description of the signer
+fn verify_cached_index(cache: &Path, hash: &Hash, trusted_keys: &Path) -> anyhow::Result<String> {
+	let index = read_cached_index(cache, hash)?;
+	let trusted_keys = read_trusted_keys(trusted_keys)?;
+
+	Ok(describe_key(verify_index(&index, &trusted_keys)?))
+}
+
 fn validate_tree(tree: &Tree, path: &Path) {
 	for item in tree.contents.iter() {
 		if let TreeObject::Tree(tree) = item {
@@ -698,6 +903,12 @@ fn validate_tree(tree: &Tree, path: &Path) {
 			continue;
 		}
 
+		if let TreeObject::Chunked(blob) = item {
+			let blob_path = path.join(&blob.path);
+			assert!(ChunkedBlob::hash_and_write(&blob_path, None).hash == blob.hash);
+			continue;
+		}
+
 		let TreeObject::Blob(blob) = item else {
 			unreachable!();
 		};
@@ -710,14 +921,29 @@ fn validate_tree(tree: &Tree, path: &Path) {
 	}
 }
 
-fn write_tree(tree: &Tree, path: &Path) {
+fn write_tree(cache: &Path, tree: &Tree, path: &Path) {
 	for item in tree.contents.iter() {
 		if let TreeObject::Tree(tree) = item {
 			let tree_path = path.join(&tree.path);
 
 			create_dir(&tree_path).expect("Directory creation to work");
 
-			write_tree(tree, &tree_path);
+			write_tree(cache, tree, &tree_path);
+			continue;
+		}
+
+		if let TreeObject::Chunked(blob) = item {
+			let file = File::create(path.join(&blob.path)).expect("File to be created");
+			let mut writer = BufWriter::new(file);
+
+			for chunk in &blob.chunks.chunks {
+				let mut reader = open_object_file(&chunk.hash.get_path(cache))
+					.expect("Chunk to exist in the cache");
+				let _ = read_header_from_file(&mut reader);
+				std::io::copy(&mut reader, &mut writer).expect("Chunk to be written");
+			}
+
+			writer.flush().expect("File to be written");
 			continue;
 		}
 
@@ -730,8 +956,7 @@ fn write_tree(tree: &Tree, path: &Path) {
 		let file = File::create(blob_path).expect("File to be created");
 		let mut writer = BufWriter::new(file);
 
-		let cache_file = File::open(&blob.file).unwrap();
-		let mut reader = BufReader::new(cache_file);
+		let mut reader = open_object_file(&blob.file).unwrap();
 
 		let _ = read_header_from_file(&mut reader);
 
@@ -748,8 +973,7 @@ fn write_tree(tree: &Tree, path: &Path) {
 fn cat_object(cache: &Path, hash: &Hash) {
 	let object_path = hash.get_path(cache);
 
-	let file = File::open(&object_path).unwrap();
-	let mut reader: BufReader<File> = BufReader::new(file);
+	let mut reader = open_object_file(&object_path).unwrap();
 
 	read_header_from_file(&mut reader).expect("file to contain a valid header");
 
@@ -764,14 +988,25 @@ fn cat_object(cache: &Path, hash: &Hash) {
 	println!();
 }
 
-fn push_cache(cache: &PathBuf, url: &String, hash: Option<Hash>) {
+/// Keyring archives and pushed objects are encrypted with, and encrypted
+/// archives and objects are decrypted with. Set once at startup; unset means
+/// nothing is encrypted.
+static ENCRYPTION: OnceLock<Keyring> = OnceLock::new();
+
+fn push_cache(
+	cache: &PathBuf,
+	remote: &Remote,
+	hash: Option<Hash>,
+	jobs: NonZeroUsize,
+) -> anyhow::Result<()> {
 	if let Some(hash) = hash {
 		let file = hash.get_path(cache);
-		upload_object(&hash, &file, url);
-		return;
+		return upload_object(&hash, &file, remote)
+			.with_context(|| format!("Unable to push {hash}"));
 	}
 
-	for entry in read_dir(cache).unwrap().filter_map(|x| x.ok()) {
+	let mut objects = Vec::new();
+	for entry in read_dir(cache)?.filter_map(|x| x.ok()) {
 		let Ok(metadata) = entry.metadata() else {
 			continue;
 		};
@@ -782,7 +1017,13 @@ fn push_cache(cache: &PathBuf, url: &String, hash: Option<Hash>) {
 
 		let prefix = entry.file_name();
 
-		for entry in read_dir(entry.path()).unwrap().filter_map(|x| x.ok()) {
+		// Only the two character fan-out directories hold objects. Anything
+		// else (such as the temporary write directory) is cache bookkeeping.
+		if prefix.len() != 2 {
+			continue;
+		}
+
+		for entry in read_dir(entry.path())?.filter_map(|x| x.ok()) {
 			let Ok(metadata) = entry.metadata() else {
 				continue;
 			};
@@ -796,159 +1037,488 @@ fn push_cache(cache: &PathBuf, url: &String, hash: Option<Hash>) {
 				prefix.to_string_lossy(),
 				entry.file_name().to_string_lossy()
 			);
-			let hash = Hash::try_from(name).expect("Hash to be valid");
+			let Ok(hash) = Hash::try_from(name) else {
+				continue;
+			};
 
-			upload_object(&hash, &entry.path(), url);
+			objects.push((hash, entry.path()));
 		}
 	}
+
+	let failures = Failures::default();
+	transfer_pool(jobs).install(|| {
+		objects.par_iter().for_each(|(hash, file)| {
+			if let Err(err) = upload_object(hash, file, remote) {
+				failures.record(hash, err);
+			}
+		})
+	});
+
+	failures.finish("push", objects.len())
 }
 
-fn pull_tree(cache: &PathBuf, url: &String, tree_hash: &Hash) {
-	let tree_path = tree_hash.get_path(cache);
+/// Threads objects are pushed and pulled on. The transfers block on the
+/// network, so these are kept apart from rayon's global pool, which is sized
+/// for hashing.
+fn transfer_pool(jobs: NonZeroUsize) -> rayon::ThreadPool {
+	rayon::ThreadPoolBuilder::new()
+		.num_threads(jobs.get())
+		.thread_name(|index| format!("arx-transfer-{index}"))
+		.build()
+		.expect("Transfer threads to start")
+}
 
-	// tree already exists locally so we can skip downloading it
-	if !tree_path.exists() {
-		let Some(Header { object_type, .. }) = download_object(tree_hash, &tree_path, url) else {
-			eprintln!("Unable to download object with hash {tree_hash}");
-			return;
-		};
-		assert!(object_type == ObjectType::Tree);
-	}
+/// Objects a push or pull was unable to transfer, and why
+#[derive(Default)]
+struct Failures(Mutex<Vec<(Hash, anyhow::Error)>>);
+
+impl Failures {
+	fn record(&self, hash: &Hash, err: anyhow::Error) {
+		self.0
+			.lock()
+			.expect("failures lock to not be poisoned")
+			.push((hash.clone(), err));
+	}
+
+	/// Nothing if every one of the `total` objects was transferred, otherwise
+	/// an error listing those that weren't
+	fn finish(self, action: &str, total: usize) -> anyhow::Result<()> {
+		let mut failures = self
+			.0
+			.into_inner()
+			.expect("failures lock to not be poisoned");
+		if failures.is_empty() {
+			return Ok(());
+		}
 
-	let mut file = File::open(tree_path).expect("Index file to exist");
-	let mut index_data = Vec::new();
-	let _ = file
-		.read_to_end(&mut index_data)
-		.expect("file to be readable");
+		failures.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
 
-	let (_, data) = read_header_and_body(&index_data).expect("Index to be in the correct format");
+		let mut message = format!(
+			"Unable to {action} {} of {total} objects, {action} again to finish:",
+			failures.len()
+		);
+		for (hash, err) in failures {
+			message.push_str(&format!("\n  {hash}: {err:#}"));
+		}
 
-	let index_body = common::object_body::Tree::from_data(data);
+		Err(anyhow::anyhow!(message))
+	}
+}
 
-	for entry in index_body.contents {
-		let obj_path = entry.hash.get_path(cache);
-		let Some(Header { object_type, .. }) = download_object(&entry.hash, &obj_path, url) else {
-			eprintln!("Unable to download object with hash {}", entry.hash);
-			return;
+/// Pulls the objects under a tree, downloading the children of each tree or
+/// chunk list as soon as it arrives rather than after its siblings.
+struct TreePull<'a> {
+	cache: &'a Path,
+	remote: &'a Remote,
+	/// Objects already queued, as trees can share subtrees and blobs
+	queued: Mutex<HashSet<Hash>>,
+	failures: Failures,
+}
+
+impl<'a> TreePull<'a> {
+	fn run(
+		cache: &'a Path,
+		remote: &'a Remote,
+		tree_hash: &Hash,
+		jobs: NonZeroUsize,
+	) -> anyhow::Result<()> {
+		let pull = TreePull {
+			cache,
+			remote,
+			queued: Default::default(),
+			failures: Default::default(),
 		};
 
-		assert!(object_type != ObjectType::Index);
+		transfer_pool(jobs).scope(|scope| pull.queue(scope, tree_hash.clone(), ObjectType::Index));
+
+		let total = pull
+			.queued
+			.into_inner()
+			.expect("queued objects lock to not be poisoned")
+			.len();
+		pull.failures.finish("pull", total)
+	}
+
+	/// Download `hash`, referenced by an object of type `parent`, and then
+	/// everything under it
+	fn queue<'s>(&'s self, scope: &rayon::Scope<'s>, hash: Hash, parent: ObjectType) {
+		if !self
+			.queued
+			.lock()
+			.expect("queued objects lock to not be poisoned")
+			.insert(hash.clone())
+		{
+			return;
+		}
+
+		scope.spawn(move |scope| {
+			if let Err(err) = self.pull(scope, &hash, parent) {
+				self.failures.record(&hash, err);
+			}
+		});
+	}
+
+	fn pull<'s>(
+		&'s self,
+		scope: &rayon::Scope<'s>,
+		hash: &Hash,
+		parent: ObjectType,
+	) -> anyhow::Result<()> {
+		let Header { object_type, .. } = download_object(hash, self.cache, self.remote)?;
 
-		if object_type == ObjectType::Tree {
-			pull_tree(cache, url, &entry.hash);
+		let expected = match parent {
+			ObjectType::Index => object_type == ObjectType::Tree,
+			ObjectType::ChunkList => object_type == ObjectType::Blob,
+			_ => object_type != ObjectType::Index,
+		};
+		if !expected {
+			anyhow::bail!("Object {hash} is a {object_type:?}, which a {parent:?} can't refer to");
 		}
+
+		match object_type {
+			ObjectType::Tree => {
+				let tree = common::object_body::Tree::parse(&self.read_body(hash)?)
+					.with_context(|| format!("Tree {hash} is malformed"))?;
+				for entry in tree.contents {
+					self.queue(scope, entry.hash, ObjectType::Tree);
+				}
+			}
+			ObjectType::ChunkList => {
+				let chunks = ChunkList::parse(&self.read_body(hash)?)
+					.with_context(|| format!("Chunk list {hash} is malformed"))?;
+				for chunk in chunks.chunks {
+					self.queue(scope, chunk.hash, ObjectType::ChunkList);
+				}
+			}
+			_ => {}
+		}
+
+		Ok(())
+	}
+
+	fn read_body(&self, hash: &Hash) -> anyhow::Result<Vec<u8>> {
+		let mut data = Vec::new();
+		open_object_file(&hash.get_path(self.cache))
+			.with_context(|| format!("Unable to open object {hash}"))?
+			.read_to_end(&mut data)?;
+
+		let (_, body) = read_header_and_body(&data)
+			.ok_or_else(|| anyhow::anyhow!("Object {hash} is corrupt"))?;
+		Ok(body.to_vec())
 	}
 }
 
-fn pull_cache(cache: &PathBuf, url: &String, hash: Hash) {
-	let index_path = hash.get_path(cache);
-	let Some(Header { object_type, .. }) = download_object(&hash, &index_path, url) else {
-		eprintln!("Unable to download object with hash {hash}");
-		return;
-	};
+/// Compression to ask the server to build a bundle with, where it differs
+/// from the server's own settings
+struct BundleCompression {
+	algorithm: Option<CompressionAlgorithm>,
+	level: Option<CompressionLevel>,
+}
 
-	assert!(object_type == ObjectType::Index);
+/// Pull an index and every object under it. With `bundle`, they are fetched
+/// in a single bundle, falling back to one request per object if the server
+/// can't provide it.
+fn pull_cache(
+	cache: &Path,
+	remote: &Remote,
+	hash: Hash,
+	bundle: Option<BundleCompression>,
+	jobs: NonZeroUsize,
+) -> anyhow::Result<()> {
+	let _lock = CacheLock::acquire(cache)?;
 
-	let mut file = File::open(index_path).expect("Index file to exist");
-	let mut index_data = Vec::new();
-	let _ = file
-		.read_to_end(&mut index_data)
-		.expect("file to be readable");
+	if let Some(compression) = bundle {
+		if pull_bundle(cache, remote, &hash, &compression) {
+			return Ok(());
+		}
+		println!("Pulling the objects of {hash} one at a time instead");
+	}
 
-	let (_, data) = read_header_and_body(&index_data).expect("Index to be in the correct format");
+	download_object(&hash, cache, remote).with_context(|| format!("Unable to pull {hash}"))?;
+	let index = read_cached_index(cache, &hash)?;
 
-	let index_body = common::object_body::Index::from_data(data);
+	TreePull::run(cache, remote, &index.tree, jobs)
+}
 
-	pull_tree(cache, url, &index_body.tree);
+/// A compression level as the server's `level` query parameter expects it.
+/// `Display` gives `default`, `fast` and `best` as they are, but writes exact
+/// levels as `exact(<n>)`, where the server wants just the number.
+fn level_query_value(level: CompressionLevel) -> String {
+	match level {
+		CompressionLevel::Exact(level) => level.to_string(),
+		level => level.to_string(),
+	}
 }
 
-fn upload_object(hash: &Hash, file: &PathBuf, url: &String) {
-	let file = File::open(file).expect("File to exist");
-	let mut reader = BufReader::new(file);
+/// Fetch the bundle of an index and unpack it into the cache, returning
+/// whether that worked
+fn pull_bundle(
+	cache: &Path,
+	remote: &Remote,
+	hash: &Hash,
+	compression: &BundleCompression,
+) -> bool {
+	let mut query = Vec::new();
+	if let Some(algorithm) = compression.algorithm {
+		query.push(format!("compression={algorithm}"));
+	}
+	if let Some(level) = compression.level {
+		query.push(format!("level={}", level_query_value(level)));
+	}
 
-	let Header { object_type, size } =
-		read_header_from_file(&mut reader).expect("file to be a valid object");
+	let mut path = format!("/bundle/{hash}");
+	if !query.is_empty() {
+		path = format!("{path}?{}", query.join("&"));
+	}
 
-	let url = format!("{url}/object/{hash}");
+	println!("Sending get request to {}", remote.url(&path));
+	let response = remote.retry(&format!("download of the bundle of {hash}"), || {
+		remote.get(&path).call()
+	});
+	let mut response = match response {
+		Ok(response) => response,
+		Err(err) => {
+			eprintln!(
+				"Unable to fetch the bundle of {hash}, {}",
+				describe_error(&err)
+			);
+			return false;
+		}
+	};
 
-	println!("Sending put request to {url}");
+	match unpack_into_cache(cache, &mut response.body_mut().as_reader()) {
+		Ok(unpacked) if unpacked == *hash => true,
+		Ok(unpacked) => {
+			eprintln!("Bundle requested for {hash} holds index {unpacked}");
+			false
+		}
+		Err(err) => {
+			eprintln!("Unable to unpack the bundle of {hash}: {err:#}");
+			false
+		}
+	}
+}
+
+fn upload_object(hash: &Hash, file: &Path, remote: &Remote) -> anyhow::Result<()> {
+	let open = || {
+		let mut reader =
+			open_object_file(file).with_context(|| format!("Unable to open object {hash}"))?;
+		let header = read_header_from_file(&mut reader)
+			.ok_or_else(|| anyhow::anyhow!("Object {hash} is corrupt"))?;
+		anyhow::Ok((header, reader))
+	};
+
+	// Encrypted objects are addressed by the hash of their plaintext, with
+	// their real type and size hidden inside the encrypted bytes
+	let (Header { object_type, size }, encrypted) = match ENCRYPTION.get() {
+		None => (open()?.0, None),
+		Some(keyring) => {
+			let mut reader =
+				open_object_file(file).with_context(|| format!("Unable to open object {hash}"))?;
+			let encrypted = encrypt_object(keyring, hash, &mut reader)?;
+			(
+				Header::new(ObjectType::Encrypted, encrypted.len() as u64),
+				Some(encrypted),
+			)
+		}
+	};
+
+	let path = format!("/object/{hash}");
+
+	println!("Sending put request to {}", remote.url(&path));
 
-	let response = ureq::put(url)
-		.header("Object-Type", object_type.to_str())
-		.header("Object-Size", size.to_string())
-		.send(SendBody::from_reader(&mut reader));
+	let response = remote
+		.retry(&format!("upload of {hash}"), || {
+			// Every attempt sends the body from its start
+			let body = match &encrypted {
+				Some(encrypted) => SendBody::from_owned_reader(Cursor::new(encrypted.clone())),
+				None => {
+					let (_, reader) = open().map_err(|err| ureq::Error::Other(err.into()))?;
+					SendBody::from_owned_reader(reader)
+				}
+			};
+
+			remote
+				.put(&path)
+				.header("Object-Type", object_type.to_str())
+				.header("Object-Size", size.to_string())
+				.send(body)
+		})
+		.map_err(|err| anyhow::anyhow!("Unable to upload, {}", describe_error(&err)))?;
 
-	if let Err(err) = response {
-		eprintln!("There was an error sending request {err:?}")
+	for warning in response.headers().get_all("Quota-Warning") {
+		eprintln!("Warning: {}", warning.to_str().unwrap_or_default());
 	}
+
+	Ok(())
 }
 
-fn download_object(hash: &Hash, file: &PathBuf, url: &String) -> Option<Header> {
-	let url = format!("{url}/object/{hash}");
+fn download_object(hash: &Hash, cache: &Path, remote: &Remote) -> anyhow::Result<Header> {
+	let path = format!("/object/{hash}");
 
-	let dir = file.parent().expect("Path to not be at root");
-	create_dir_all(dir).expect("Directory to be created");
+	let file = hash.get_path(cache);
 
 	if file.exists() {
-		let file = File::open(file).expect("File to exist");
-		let mut reader = BufReader::new(file);
-
-		let mut buffer = Vec::new();
-		reader
-			.read_until(0, &mut buffer)
-			.expect("Header to exist within file");
+		let mut reader =
+			open_object_file(&file).with_context(|| format!("Unable to open object {hash}"))?;
 
-		// subtract one to get rid of the null byte
-		return read_header_from_slice(&buffer[..buffer.len() - 1]);
+		return read_header_from_file(&mut reader)
+			.ok_or_else(|| anyhow::anyhow!("Object {hash} in the cache is corrupt"));
 	}
 
-	println!("Sending get request to {url}");
+	let partial =
+		partial_path(cache, hash).context("Unable to create the partial download directory")?;
 
-	let response = ureq::get(url).call();
+	let header = fetch_object(hash, &path, &partial, remote)?;
 
-	let mut response = match response {
-		Ok(v) => v,
-		Err(err) => {
-			eprintln!("There was an error sending request {err:?}");
-			return None;
+	let mut reader = File::open(&partial)
+		.map(BufReader::new)
+		.context("Unable to read the download")?;
+
+	let header = store_download(hash, header, &mut reader, cache)?;
+	let _ = remove_file(&partial);
+
+	Ok(header)
+}
+
+/// Download the body of an object into `partial`, picking up where an earlier
+/// download left off if there is one. A transfer that breaks after making
+/// progress is resumed straight away, a few times, and one that breaks
+/// without any is retried like a failed request.
+fn fetch_object(
+	hash: &Hash,
+	path: &str,
+	partial: &Path,
+	remote: &Remote,
+) -> anyhow::Result<Header> {
+	const RESUME_ATTEMPTS: usize = 3;
+
+	let mut attempts = 0;
+	let mut stalls = 0;
+
+	loop {
+		let offset = partial.metadata().map(|m| m.len()).unwrap_or(0);
+
+		if offset > 0 {
+			println!("Resuming download of {hash} from byte {offset}");
+		} else {
+			println!("Sending get request to {}", remote.url(path));
 		}
-	};
 
-	let file = File::create(file).expect("File to exist");
-	let mut writer = BufWriter::new(file);
-
-	let response_headers = response.headers();
-	let object_type: ObjectType = ObjectType::from_str(
-		response_headers
-			.get("Object-Type")
-			.expect("Object-Type Header to be present in the response")
-			.to_str()
-			.expect("Header to be valid ascii"),
-	)
-	.expect("Header to be a valid ObjectType");
-	let object_size: u64 = response_headers
-		.get("Object-Size")
-		.expect("Object-Size header to be present in the response")
-		.to_str()
-		.expect("Header to be valid ascii")
-		.parse()
-		.expect("Header to be a valid number");
-
-	let header = Header::new(object_type, object_size);
-
-	writer.write_all(header.to_string().as_bytes()).unwrap();
+		let response = remote.retry(&format!("download of {hash}"), || {
+			let request = remote.get(path);
+			match offset {
+				0 => request.call(),
+				// The hash is the object's ETag, so the server sends everything
+				// again rather than a range of some other bytes
+				offset => request
+					.header("Range", format!("bytes={offset}-"))
+					.header("If-Range", format!("\"{hash}\""))
+					.call(),
+			}
+		});
 
-	let mut data: [u8; 1024] = [0; 1024];
-	let mut reader = response.body_mut().as_reader();
-	while let Ok(num) = reader.read(&mut data) {
-		if num == 0 {
-			break;
+		let mut response = match response {
+			Ok(response) => response,
+			Err(ureq::Error::StatusCode(416)) if attempts < RESUME_ATTEMPTS => {
+				// The partial download is somehow longer than the object
+				let _ = remove_file(partial);
+				attempts += 1;
+				continue;
+			}
+			Err(err) => anyhow::bail!("Unable to download, {}", describe_error(&err)),
+		};
+
+		let resumed = match response.status().as_u16() {
+			200 => false,
+			206 => true,
+			status => anyhow::bail!("Unexpected response {status}"),
+		};
+
+		let header = response_object_header(response.headers()).ok_or_else(|| {
+			anyhow::anyhow!("Response has a missing or invalid Object-Type or Object-Size")
+		})?;
+
+		let mut file = OpenOptions::new()
+			.create(true)
+			.write(true)
+			.append(resumed)
+			.truncate(!resumed)
+			.open(partial)
+			.context("Unable to write the download")?;
+
+		let copied = std::io::copy(&mut response.body_mut().as_reader(), &mut file);
+		let length = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+		match copied {
+			Ok(_) if length == header.size => return Ok(header),
+			Ok(_) if length > header.size => {
+				let _ = remove_file(partial);
+				anyhow::bail!("Download is longer than the object, discarding it");
+			}
+			result => {
+				if length > offset && attempts < RESUME_ATTEMPTS {
+					attempts += 1;
+					continue;
+				}
+
+				if length == offset && stalls < remote.retries() {
+					let delay = remote::backoff(stalls);
+					eprintln!(
+						"Retrying download of {hash} in {}s, no bytes were received",
+						delay.as_secs_f32()
+					);
+					std::thread::sleep(delay);
+					stalls += 1;
+					continue;
+				}
+
+				match result {
+					Ok(_) => anyhow::bail!("Download ended early at byte {length}"),
+					Err(err) => anyhow::bail!("Download failed at byte {length}, {err}"),
+				}
+			}
 		}
+	}
+}
+
+/// Write a downloaded object body into the cache, decrypting it first if the
+/// server held it encrypted
+fn store_download(
+	hash: &Hash,
+	header: Header,
+	mut reader: &mut impl Read,
+	cache: &Path,
+) -> anyhow::Result<Header> {
+	if header.object_type == ObjectType::Encrypted {
+		let Some(keyring) = ENCRYPTION.get() else {
+			anyhow::bail!("Object {hash} is encrypted, pass --passphrase or --key-file to pull it");
+		};
 
-		writer.write_all(&data[..num]).unwrap();
+		let (header, object) = decrypt_object(keyring, hash, &mut reader)?;
+
+		write_object(cache, hash, |writer| writer.write_all(&object))
+			.with_context(|| format!("Unable to write object {hash} to the cache"))?;
+
+		return Ok(header);
 	}
 
-	Some(header)
+	write_object(cache, hash, |writer| {
+		writer.write_all(header.to_string().as_bytes())?;
+		std::io::copy(&mut reader, writer)?;
+		Ok(())
+	})
+	.with_context(|| format!("Unable to write object {hash} to the cache"))?;
+
+	Ok(header)
+}
+
+/// The object header sent in the `Object-Type` and `Object-Size` headers
+fn response_object_header(headers: &ureq::http::HeaderMap) -> Option<Header> {
+	let object_type = ObjectType::from_str(headers.get("Object-Type")?.to_str().ok()?)?;
+	let size = headers.get("Object-Size")?.to_str().ok()?.parse().ok()?;
+
+	Some(Header::new(object_type, size))
 }
 
 fn pack_archive(
@@ -965,8 +1535,7 @@ fn pack_archive(
 	assert!(index_path.exists());
 
 	let index = {
-		let file = File::open(index_path).expect("file to exist");
-		let mut reader = BufReader::new(file);
+		let mut reader = open_object_file(&index_path).expect("file to exist");
 		let mut data = Vec::new();
 		let _ = reader.read_to_end(&mut data).expect("File to be readable");
 
@@ -1009,6 +1578,7 @@ fn pack_archive(
 				.map(|hash| FileEntryData(hash.get_path(cache)))
 				.collect(),
 		},
+		encryption: ENCRYPTION.get().cloned(),
 	};
 
 	let arx_file = File::create(path)?;
@@ -1025,7 +1595,17 @@ fn unpack_archive(cache: &Path, path: &Path) -> anyhow::Result<()> {
 	let file = File::open(path)?;
 	let mut file = BufReader::new(file);
 
-	let archive = Archive::<RawEntryData>::from_data(&mut file)?;
+	let _lock = CacheLock::acquire(cache)?;
+
+	unpack_into_cache(cache, &mut file)?;
+
+	Ok(())
+}
+
+/// Write the index and objects of an archive into the cache, returning the
+/// index's hash. The caller must hold the cache lock.
+fn unpack_into_cache(cache: &Path, reader: &mut impl Read) -> anyhow::Result<Hash> {
+	let archive = Archive::<RawEntryData>::from_data_with_key(reader, ENCRYPTION.get())?;
 
 	assert!(archive.body.entries.len() == archive.body.header.len());
 
@@ -1039,27 +1619,27 @@ fn unpack_archive(cache: &Path, path: &Path) -> anyhow::Result<()> {
 	hasher.write_all(&index_data)?;
 	assert!(Hash::from(hasher) == archive.hash);
 
-	let path = archive.hash.get_path(cache);
-	let _ = create_dir_all(path.parent().unwrap());
-
-	{
-		let index_file = File::create(path)?;
-		let mut writer = BufWriter::new(index_file);
+	write_object(cache, &archive.hash, |writer| {
 		writer.write_all(index_header.to_string().as_bytes())?;
-		writer.write_all(&index_data)?;
-	}
+		writer.write_all(&index_data)
+	})?;
 
 	for (header, entry) in archive.body.header.into_iter().zip(archive.body.entries) {
-		let path = header.hash.get_path(cache);
-		let _ = create_dir_all(path.parent().unwrap());
-
-		let file = File::create(path)?;
-		let mut writer = BufWriter::new(file);
+		let data = entry.turn_into_vec();
+
+		// Bundles from a server hold objects as it stores them, so encrypted
+		// ones are decrypted as they would be when downloaded on their own
+		if let Some((object_header, mut body)) = read_header_and_body(&data)
+			.filter(|(object_header, _)| object_header.object_type == ObjectType::Encrypted)
+		{
+			store_download(&header.hash, object_header, &mut body, cache)?;
+			continue;
+		}
 
-		writer.write_all(&entry.turn_into_vec())?;
+		write_object(cache, &header.hash, |writer| writer.write_all(&data))?;
 	}
 
-	Ok(())
+	Ok(archive.hash)
 }
 
 /// Archive body entry: either an already-serialised byte buffer (for tree and
@@ -1068,6 +1648,7 @@ fn unpack_archive(cache: &Path, path: &Path) -> anyhow::Result<()> {
 enum ArchiveEntry {
 	Raw(RawEntryData, u64),
 	Source(SourceFileEntryData, u64),
+	Chunk(SourceChunkEntryData, u64),
 }
 
 impl ArchiveEntry {
@@ -1075,6 +1656,7 @@ impl ArchiveEntry {
 		match self {
 			ArchiveEntry::Raw(_, len) => *len,
 			ArchiveEntry::Source(_, len) => *len,
+			ArchiveEntry::Chunk(_, len) => *len,
 		}
 	}
 }
@@ -1084,6 +1666,7 @@ impl ArchiveEntryData for ArchiveEntry {
 		match self {
 			ArchiveEntry::Raw(data, _) => data.turn_into_vec(),
 			ArchiveEntry::Source(data, _) => data.turn_into_vec(),
+			ArchiveEntry::Chunk(data, _) => data.turn_into_vec(),
 		}
 	}
 }
@@ -1122,6 +1705,39 @@ fn collect_archive_entries(tree: &Hashed<Tree>, entries: &mut HashMap<Hash, Arch
 					),
 				);
 			}
+			TreeObject::Chunked(blob) => {
+				if entries.contains_key(&blob.hash) {
+					continue;
+				}
+
+				let mut bytes = blob.get_prefix().into_bytes();
+				bytes.extend_from_slice(&blob.chunks.to_data());
+				let length = bytes.len() as u64;
+				entries.insert(
+					blob.hash.clone(),
+					ArchiveEntry::Raw(RawEntryData::new(bytes), length),
+				);
+
+				let mut offset = 0;
+				for chunk in &blob.chunks.chunks {
+					if !entries.contains_key(&chunk.hash) {
+						let header = Header::new(ObjectType::Blob, chunk.size);
+						let length = header.to_string().len() as u64 + chunk.size;
+						entries.insert(
+							chunk.hash.clone(),
+							ArchiveEntry::Chunk(
+								SourceChunkEntryData {
+									source_path: blob.file.clone(),
+									offset,
+									header,
+								},
+								length,
+							),
+						);
+					}
+					offset += chunk.size;
+				}
+			}
 		}
 	}
 }
@@ -1188,6 +1804,7 @@ fn archive_directory(
 			header: header_entries,
 			entries: body_entries,
 		},
+		encryption: ENCRYPTION.get().cloned(),
 	};
 
 	let out = File::create(out_file)?;
@@ -1217,6 +1834,85 @@ struct Cli {
 	#[arg(short, long, action = clap::ArgAction::Count)]
 	quiet: u8,
 
+	/// Compress objects written to the store with zstd. Objects already in the
+	/// store stay readable either way.
+	#[arg(
+		long,
+		global = true,
+		value_name = "LEVEL",
+		num_args = 0..=1,
+		require_equals = true,
+		default_missing_value = "default"
+	)]
+	compress: Option<CompressionLevel>,
+
+	/// Split files larger than this many bytes into content-defined chunks, so
+	/// a small change to a large file only stores the chunks that changed.
+	#[arg(long, global = true, value_name = "BYTES")]
+	chunk_threshold: Option<u64>,
+
+	/// Bearer token sent to the server when pushing and pulling
+	#[arg(long, global = true, env = "ARX_TOKEN", hide_env_values = true)]
+	token: Option<String>,
+
+	/// Number of objects pushed or pulled at once
+	#[arg(short, long, global = true, value_name = "N", default_value = "8")]
+	jobs: NonZeroUsize,
+
+	/// Seconds to wait for a connection to the server to open
+	#[arg(long, global = true, value_name = "SECONDS", default_value_t = 10)]
+	connect_timeout: u64,
+
+	/// Seconds to wait for the server to respond to a request
+	#[arg(long, global = true, value_name = "SECONDS", default_value_t = 60)]
+	timeout: u64,
+
+	/// Times a request that failed in a way that may not happen again, such as
+	/// a timeout or a 503, is retried, waiting longer before each retry
+	#[arg(long, global = true, value_name = "N", default_value_t = 4)]
+	retries: u32,
+
+	/// Encrypt archives and pushed objects with a key derived from this
+	/// passphrase, and decrypt them when unpacking and pulling
+	#[arg(
+		long,
+		global = true,
+		env = "ARX_PASSPHRASE",
+		hide_env_values = true,
+		conflicts_with = "key_file"
+	)]
+	passphrase: Option<String>,
+
+	/// Like --passphrase, but derive the key from the contents of a file
+	#[arg(long, global = true, value_name = "FILE")]
+	key_file: Option<PathBuf>,
+
+	/// PEM bundle of the CAs to trust for https servers, in place of the
+	/// system's trusted roots
+	#[arg(long, global = true, env = "ARX_CA_CERT", value_name = "FILE")]
+	ca_cert: Option<PathBuf>,
+
+	/// PEM certificate chain presented to servers that require client
+	/// certificates
+	#[arg(
+		long,
+		global = true,
+		env = "ARX_CLIENT_CERT",
+		value_name = "FILE",
+		requires = "client_key"
+	)]
+	client_cert: Option<PathBuf>,
+
+	/// PEM private key of --client-cert
+	#[arg(
+		long,
+		global = true,
+		env = "ARX_CLIENT_KEY",
+		value_name = "FILE",
+		requires = "client_cert"
+	)]
+	client_key: Option<PathBuf>,
+
 	#[command(subcommand)]
 	command: Commands,
 }
@@ -1234,6 +1930,33 @@ enum Commands {
 		index: Hash,
 		#[arg(long)]
 		validate: bool,
+
+		/// Refuse to restore unless the index is signed by a trusted key
+		#[arg(long, requires = "trusted_keys")]
+		require_signature: bool,
+
+		/// File of trusted OpenSSH public keys, one per line
+		#[arg(long, requires = "require_signature")]
+		trusted_keys: Option<PathBuf>,
+	},
+
+	/// Sign an index with an OpenSSH ed25519 private key. The signature is
+	/// stored in the index's metadata, so this prints the hash of the new,
+	/// signed index.
+	Sign {
+		index: Hash,
+
+		#[arg(long)]
+		key: PathBuf,
+	},
+
+	/// Check an index is signed by one of the trusted keys
+	Verify {
+		index: Hash,
+
+		/// File of trusted OpenSSH public keys, one per line
+		#[arg(long)]
+		trusted_keys: PathBuf,
 	},
 
 	Cat {
@@ -1255,6 +1978,19 @@ enum Commands {
 
 		#[arg(long)]
 		index: Hash,
+
+		/// Fetch the index and its objects as a single bundle rather than
+		/// one request per object
+		#[arg(long)]
+		bundle: bool,
+
+		/// Compression to ask the server to build the bundle with
+		#[arg(long, requires = "bundle", alias = "compression", alias = "alg")]
+		algorithm: Option<CompressionAlgorithm>,
+
+		/// Compression level of the bundle, which the server may limit
+		#[arg(long, requires = "bundle", allow_hyphen_values = true)]
+		level: Option<CompressionLevel>,
 	},
 
 	Pack {
@@ -1297,16 +2033,111 @@ fn main() {
 		.into_owned()
 		.into();
 
+	if let Some(level) = cli.compress {
+		set_object_compression(
+			level
+				.get_compression_level(CompressionAlgorithm::Zstd)
+				.expect("Compression level to be valid for zstd"),
+		);
+	}
+
+	if let Some(threshold) = cli.chunk_threshold {
+		let _ = CHUNK_THRESHOLD.set(threshold);
+	}
+
+	let keyring = match (&cli.passphrase, &cli.key_file) {
+		(Some(passphrase), _) => Some(Keyring::from_passphrase(passphrase)),
+		(None, Some(key_file)) => Some(Keyring::from_key_file(key_file)),
+		(None, None) => None,
+	};
+
+	match keyring {
+		Some(Ok(keyring)) => {
+			let _ = ENCRYPTION.set(keyring);
+		}
+		Some(Err(err)) => {
+			eprintln!("{err:#}");
+			std::process::exit(1);
+		}
+		None => {}
+	}
+
+	let remote_config = RemoteConfig {
+		tls: RemoteTls {
+			ca_cert: cli.ca_cert.clone(),
+			client_cert: cli.client_cert.clone().zip(cli.client_key.clone()),
+		},
+		connections: cli.jobs,
+		connect_timeout: Duration::from_secs(cli.connect_timeout),
+		timeout: Duration::from_secs(cli.timeout),
+		retries: cli.retries,
+	};
+	let remote = |url: &str| match Remote::new(url, cli.token.clone(), &remote_config) {
+		Ok(remote) => remote,
+		Err(err) => {
+			eprintln!("{err:#}");
+			std::process::exit(1);
+		}
+	};
+
 	match cli.command {
 		Commands::Commit { directory } => commit_directory(&cli.store, &directory),
 		Commands::Restore {
 			directory,
 			index,
 			validate,
-		} => restore_directory(&cli.store, &directory, index, validate),
+			require_signature,
+			trusted_keys,
+		} => restore_directory(
+			&cli.store,
+			&directory,
+			index,
+			validate,
+			trusted_keys.as_deref().filter(|_| require_signature),
+		),
+		Commands::Sign { index, key } => match sign_cached_index(&cli.store, &index, &key) {
+			Ok(signed) => println!("{signed}"),
+			Err(err) => {
+				eprintln!("Unable to sign {index}: {err}");
+				std::process::exit(1);
+			}
+		},
+		Commands::Verify {
+			index,
+			trusted_keys,
+		} => match verify_cached_index(&cli.store, &index, &trusted_keys) {
+			Ok(signer) => println!("Good signature on {index} from {signer}"),
+			Err(err) => {
+				eprintln!("Bad signature on {index}: {err}");
+				std::process::exit(1);
+			}
+		},
 		Commands::Cat { hash } => cat_object(&cli.store, &hash),
-		Commands::Push { url, index } => push_cache(&cli.store, &url, index),
-		Commands::Pull { url, index } => pull_cache(&cli.store, &url, index),
+		Commands::Push { url, index } => {
+			if let Err(err) = push_cache(&cli.store, &remote(&url), index, cli.jobs) {
+				eprintln!("{err:#}");
+				std::process::exit(1);
+			}
+		}
+		Commands::Pull {
+			url,
+			index,
+			bundle,
+			algorithm,
+			level,
+		} => {
+			let pulled = pull_cache(
+				&cli.store,
+				&remote(&url),
+				index,
+				bundle.then_some(BundleCompression { algorithm, level }),
+				cli.jobs,
+			);
+			if let Err(err) = pulled {
+				eprintln!("{err:#}");
+				std::process::exit(1);
+			}
+		}
 		Commands::Pack {
 			index,
 			file,
@@ -1336,6 +2167,16 @@ mod tests {
 		dir
 	}
 
+	#[test]
+	fn exact_levels_are_sent_as_plain_numbers() {
+		assert_eq!(level_query_value(CompressionLevel::Exact(19)), "19");
+		assert_eq!(level_query_value(CompressionLevel::Best), "best");
+		assert_eq!(
+			"19".parse::<CompressionLevel>().unwrap(),
+			CompressionLevel::Exact(19)
+		);
+	}
+
 	#[test]
 	fn tree_entries_are_sorted_by_name() {
 		// Created in deliberately non-alphabetical order.
@@ -1400,6 +2241,101 @@ mod tests {
 		assert_eq!(archive.body.header.len(), 4);
 	}
 
+	#[test]
+	fn archives_unpack_into_a_cache_that_restores() {
+		let src = make_dir_with_files(&["alpha.txt", "beta.txt"]);
+		std::fs::create_dir(src.path().join("sub")).unwrap();
+		std::fs::write(src.path().join("sub/gamma.txt"), b"gamma").unwrap();
+
+		let out_dir = TempDir::new().unwrap();
+		let archive = out_dir.path().join("out.arx");
+		archive_directory(
+			src.path(),
+			&archive,
+			CompressionAlgorithm::Zstd,
+			CompressionLevel::Default,
+		)
+		.unwrap();
+
+		let cache = TempDir::new().unwrap();
+		let mut reader = BufReader::new(File::open(&archive).unwrap());
+		let index = unpack_into_cache(cache.path(), &mut reader).unwrap();
+
+		let restored = out_dir.path().join("restored");
+		restore_directory(&cache.path().to_path_buf(), &restored, index, true, None);
+		assert_eq!(
+			std::fs::read(restored.join("sub/gamma.txt")).unwrap(),
+			b"gamma"
+		);
+	}
+
+	fn pseudo_random(length: usize) -> Vec<u8> {
+		let mut state: u64 = 1;
+		(0..length)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				(state >> 56) as u8
+			})
+			.collect()
+	}
+
+	#[test]
+	fn chunked_files_restore_from_their_chunks() {
+		let src = TempDir::new().unwrap();
+		let cache = TempDir::new().unwrap();
+		let out = TempDir::new().unwrap();
+		let data = pseudo_random(6 * 1024 * 1024);
+		std::fs::write(src.path().join("image.bin"), &data).unwrap();
+
+		let blob = ChunkedBlob::hash_and_write(&src.path().join("image.bin"), Some(cache.path()));
+		assert!(blob.chunks.chunks.len() > 1);
+		assert_eq!(blob.size, data.len() as u64);
+
+		let mut headers = HashMap::new();
+		read_object_into_headers_sync(cache.path(), &mut headers, &blob.hash).unwrap();
+		assert_eq!(headers.len(), blob.chunks.chunks.len() + 1);
+		assert_eq!(headers[&blob.hash].object_type, ObjectType::ChunkList);
+
+		let cache_path = cache.path().to_path_buf();
+		let restored = CacheObject::from_file(&cache_path, &blob.hash.get_path(cache.path()))
+			.to_chunked(Mode::Normal, "image.bin")
+			.unwrap();
+		let tree = Tree {
+			mode: Mode::Tree,
+			path: String::new(),
+			contents: vec![TreeObject::Chunked(restored)],
+		};
+
+		write_tree(cache.path(), &tree, out.path());
+		validate_tree(&tree, out.path());
+
+		assert_eq!(std::fs::read(out.path().join("image.bin")).unwrap(), data);
+	}
+
+	#[test]
+	fn editing_a_chunked_file_reuses_most_chunks() {
+		let src = TempDir::new().unwrap();
+		let path = src.path().join("image.bin");
+		let mut data = pseudo_random(6 * 1024 * 1024);
+		std::fs::write(&path, &data).unwrap();
+		let before = ChunkedBlob::hash_and_write(&path, None);
+
+		data[3 * 1024 * 1024] ^= 0xFF;
+		std::fs::write(&path, &data).unwrap();
+		let after = ChunkedBlob::hash_and_write(&path, None);
+
+		assert_ne!(before.hash, after.hash);
+		let changed = after
+			.chunks
+			.chunks
+			.iter()
+			.filter(|chunk| !before.chunks.chunks.contains(chunk))
+			.count();
+		assert_eq!(changed, 1);
+	}
+
 	#[test]
 	fn archive_dedups_identical_file_contents() {
 		// Two files with the same content → one blob entry in the archive.
@@ -1429,4 +2365,51 @@ mod tests {
 			"duplicate-content files must share a single blob entry"
 		);
 	}
+
+	#[test]
+	fn failed_transfers_are_listed_by_hash() {
+		assert!(Failures::default().finish("pull", 3).is_ok());
+
+		let failures = Failures::default();
+		failures.record(&Hash::from([2u8; 32]), anyhow::anyhow!("timed out"));
+		failures.record(&Hash::from([1u8; 32]), anyhow::anyhow!("not found"));
+
+		let message = failures.finish("pull", 3).unwrap_err().to_string();
+		let lines: Vec<_> = message.lines().collect();
+		assert_eq!(
+			lines[0],
+			"Unable to pull 2 of 3 objects, pull again to finish:"
+		);
+		assert_eq!(lines[1], format!("  {}: not found", "01".repeat(32)));
+		assert_eq!(lines[2], format!("  {}: timed out", "02".repeat(32)));
+	}
+
+	#[test]
+	fn signed_indexes_verify_against_trusted_keys() {
+		let src = make_dir_with_files(&["alpha.txt"]);
+		let cache = TempDir::new().unwrap();
+		let keys = TempDir::new().unwrap();
+
+		let key = ssh_key::PrivateKey::from(ssh_key::private::Ed25519Keypair::from_seed(&[7; 32]));
+		let key_path = keys.path().join("id_ed25519");
+		key.write_openssh_file(&key_path, ssh_key::LineEnding::LF)
+			.unwrap();
+		let trusted_path = keys.path().join("trusted_keys");
+		std::fs::write(&trusted_path, key.public_key().to_openssh().unwrap()).unwrap();
+		let other_path = keys.path().join("other_keys");
+		let other =
+			ssh_key::PrivateKey::from(ssh_key::private::Ed25519Keypair::from_seed(&[8; 32]));
+		std::fs::write(&other_path, other.public_key().to_openssh().unwrap()).unwrap();
+
+		let index = Index::from_path(src.path(), Some(cache.path()));
+		assert!(verify_cached_index(cache.path(), &index.hash, &trusted_path).is_err());
+
+		let signed = sign_cached_index(cache.path(), &index.hash, &key_path).unwrap();
+		assert_ne!(signed, index.hash);
+		assert!(verify_cached_index(cache.path(), &signed, &trusted_path).is_ok());
+		assert!(verify_cached_index(cache.path(), &signed, &other_path).is_err());
+
+		let signed_index = read_cached_index(cache.path(), &signed).unwrap();
+		assert_eq!(signed_index.tree, index.tree.hash);
+	}
 }
@@ -0,0 +1,229 @@
+use std::{
+	num::NonZeroUsize,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use ureq::{
+	tls::{parse_pem, Certificate, ClientCert, PemItem, PrivateKey, RootCerts, TlsConfig},
+	typestate::{WithBody, WithoutBody},
+	Agent, RequestBuilder,
+};
+
+/// Longest wait between retries of a request
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// An arx server objects are pushed to and pulled from, along with the
+/// credentials sent on every request to it.
+pub struct Remote {
+	url: String,
+	token: Option<String>,
+	agent: Agent,
+	retries: u32,
+}
+
+/// How to connect to a remote and how patient to be with it
+pub struct RemoteConfig {
+	pub tls: RemoteTls,
+	/// Connections kept open between requests
+	pub connections: NonZeroUsize,
+	/// Longest wait for a connection to be opened
+	pub connect_timeout: Duration,
+	/// Longest wait for the server to respond to a request, not counting the
+	/// transfer of the response body
+	pub timeout: Duration,
+	/// Times a request failing in a way that may not happen again is retried
+	pub retries: u32,
+}
+
+/// Certificates for talking to servers over https
+#[derive(Default)]
+pub struct RemoteTls {
+	/// PEM bundle of the CAs to trust instead of the system's roots
+	pub ca_cert: Option<PathBuf>,
+	/// PEM certificate chain and private key to present to servers that
+	/// verify client certificates
+	pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl Remote {
+	pub fn new(url: &str, token: Option<String>, config: &RemoteConfig) -> anyhow::Result<Self> {
+		let tls = &config.tls;
+		let mut tls_config = TlsConfig::builder();
+
+		if let Some(path) = &tls.ca_cert {
+			tls_config =
+				tls_config.root_certs(RootCerts::new_with_certs(&read_certificates(path)?));
+		}
+
+		if let Some((cert, key)) = &tls.client_cert {
+			let key_pem = std::fs::read(key)
+				.with_context(|| format!("Unable to read client key {}", key.display()))?;
+			let key = PrivateKey::from_pem(&key_pem)
+				.map_err(|err| anyhow!("Invalid client key {}: {err}", key.display()))?;
+
+			tls_config = tls_config.client_cert(Some(ClientCert::new_with_certs(
+				&read_certificates(cert)?,
+				key,
+			)));
+		}
+
+		let agent = Agent::config_builder()
+			.tls_config(tls_config.build())
+			.max_idle_connections(config.connections.get())
+			.max_idle_connections_per_host(config.connections.get())
+			.timeout_connect(Some(config.connect_timeout))
+			.timeout_send_request(Some(config.timeout))
+			.timeout_recv_response(Some(config.timeout))
+			.build()
+			.new_agent();
+
+		Ok(Self {
+			url: url.trim_end_matches('/').to_string(),
+			token,
+			agent,
+			retries: config.retries,
+		})
+	}
+
+	pub fn retries(&self) -> u32 {
+		self.retries
+	}
+
+	/// Send the request built by `send`, sending it again after a growing
+	/// delay while it fails in a way that may not happen next time. `what` is
+	/// the request as described when retrying it.
+	pub fn retry<T>(
+		&self,
+		what: &str,
+		mut send: impl FnMut() -> Result<T, ureq::Error>,
+	) -> Result<T, ureq::Error> {
+		let mut attempt = 0;
+		loop {
+			match send() {
+				Err(err) if attempt < self.retries && is_transient(&err) => {
+					let delay = backoff(attempt);
+					eprintln!(
+						"Retrying {what} in {}s, {}",
+						delay.as_secs_f32(),
+						describe_error(&err)
+					);
+					std::thread::sleep(delay);
+					attempt += 1;
+				}
+				result => return result,
+			}
+		}
+	}
+
+	/// Full url of `path` on the server. `path` must start with a `/`
+	pub fn url(&self, path: &str) -> String {
+		format!("{}{path}", self.url)
+	}
+
+	pub fn get(&self, path: &str) -> RequestBuilder<WithoutBody> {
+		self.authorize(self.agent.get(self.url(path)))
+	}
+
+	pub fn put(&self, path: &str) -> RequestBuilder<WithBody> {
+		self.authorize(self.agent.put(self.url(path)))
+	}
+
+	fn authorize<B>(&self, request: RequestBuilder<B>) -> RequestBuilder<B> {
+		match &self.token {
+			Some(token) => request.header("Authorization", format!("Bearer {token}")),
+			None => request,
+		}
+	}
+}
+
+fn read_certificates(path: &Path) -> anyhow::Result<Vec<Certificate<'static>>> {
+	let pem = std::fs::read(path)
+		.with_context(|| format!("Unable to read certificates from {}", path.display()))?;
+
+	let mut certificates = Vec::new();
+	for item in parse_pem(&pem) {
+		match item {
+			Ok(PemItem::Certificate(certificate)) => certificates.push(certificate),
+			Ok(_) => {}
+			Err(err) => return Err(anyhow!("Invalid certificate in {}: {err}", path.display())),
+		}
+	}
+
+	if certificates.is_empty() {
+		return Err(anyhow!("No certificates found in {}", path.display()));
+	}
+
+	Ok(certificates)
+}
+
+/// Whether a request that failed with `err` might succeed if sent again
+pub fn is_transient(err: &ureq::Error) -> bool {
+	match err {
+		ureq::Error::StatusCode(status) => matches!(status, 408 | 429 | 500 | 502 | 503 | 504),
+		ureq::Error::Io(_)
+		| ureq::Error::Timeout(_)
+		| ureq::Error::ConnectionFailed
+		| ureq::Error::HostNotFound
+		| ureq::Error::BodyStalled => true,
+		_ => false,
+	}
+}
+
+/// Wait before retry number `attempt`, doubling from half a second
+pub fn backoff(attempt: u32) -> Duration {
+	Duration::from_millis(500)
+		.saturating_mul(2u32.saturating_pow(attempt))
+		.min(MAX_BACKOFF)
+}
+
+/// Describe a failed request, pointing at how to pass credentials when the
+/// server rejected the ones sent (or their absence).
+pub fn describe_error(err: &ureq::Error) -> String {
+	match err {
+		ureq::Error::StatusCode(401) => {
+			"the server requires valid credentials, set ARX_TOKEN or pass --token".to_string()
+		}
+		ureq::Error::StatusCode(400) => "the server rejected the request as invalid".to_string(),
+		ureq::Error::StatusCode(404) => {
+			"the server has no such object, or it isn't visible in this namespace".to_string()
+		}
+		ureq::Error::StatusCode(403) => {
+			"the provided token is not allowed to perform this request".to_string()
+		}
+		ureq::Error::StatusCode(413) => {
+			"the object is larger than the storage quota allows".to_string()
+		}
+		ureq::Error::StatusCode(507) => {
+			"the storage quota is full, see /admin/usage on the server".to_string()
+		}
+		ureq::Error::StatusCode(status @ 500..=599) => {
+			format!("the server failed with status {status}, see its logs")
+		}
+		ureq::Error::Io(_)
+		| ureq::Error::Timeout(_)
+		| ureq::Error::ConnectionFailed
+		| ureq::Error::HostNotFound => format!("unable to reach the server, {err}"),
+		err => format!("{err:?}"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_transient_failures_are_retried_with_growing_delays() {
+		assert!(is_transient(&ureq::Error::StatusCode(503)));
+		assert!(is_transient(&ureq::Error::Timeout(
+			ureq::Timeout::RecvResponse
+		)));
+		assert!(!is_transient(&ureq::Error::StatusCode(404)));
+		assert!(!is_transient(&ureq::Error::StatusCode(401)));
+
+		assert_eq!(backoff(0), Duration::from_millis(500));
+		assert_eq!(backoff(3), Duration::from_secs(4));
+		assert_eq!(backoff(40), MAX_BACKOFF);
+	}
+}
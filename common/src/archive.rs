@@ -1,7 +1,8 @@
 use std::{
+	collections::HashMap,
 	fmt::{self, Display},
 	fs::File,
-	io::{BufRead, BufReader, Read, Write},
+	io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
 	num::NonZero,
 	path::PathBuf,
 	str::FromStr,
@@ -14,14 +15,20 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
+	delta,
+	encryption::Keyring,
 	object_body::{Index, Object},
-	pipe,
+	open_object_file, pipe,
 	store::Store,
 	Hash,
 };
 
 pub const HEADER: [u8; 4] = [b'a', b'r', b'x', b'a'];
 
+/// Set alongside the compression algorithm when the compressed body is
+/// encrypted
+pub const ENCRYPTED_FLAG: u16 = 0x8000;
+
 #[repr(u16)]
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub enum CompressionAlgorithm {
@@ -160,6 +167,9 @@ where
 	pub hash: Hash,
 	pub index: Index,
 	pub body: ArchiveBody<T>,
+	/// Encrypt the compressed body with a key from this keyring. The header
+	/// and index stay readable, and are authenticated along with the body.
+	pub encryption: Option<Keyring>,
 }
 
 impl<T> Archive<T>
@@ -171,44 +181,26 @@ where
 		compression_level: CompressionLevel,
 		writer: &mut impl Write,
 	) -> anyhow::Result<()> {
-		writer.write_all(&HEADER)?;
-		writer.write_all(&(self.compression as u16).to_be_bytes())?;
-		writer.write_all(&self.hash.hash)?;
-		writer.write_all(&self.index.to_data())?;
-		writer.write_all(&[0])?;
+		let mut flags = self.compression as u16;
+		if self.encryption.is_some() {
+			flags |= ENCRYPTED_FLAG;
+		}
+
+		let mut prefix = HEADER.to_vec();
+		prefix.extend_from_slice(&flags.to_be_bytes());
+		prefix.extend_from_slice(&self.hash.hash);
+		prefix.extend_from_slice(&self.index.to_data());
+		prefix.push(0);
+		writer.write_all(&prefix)?;
 
 		let numerical_level = compression_level.get_compression_level(self.compression)?;
 
-		match self.compression {
-			CompressionAlgorithm::None => self.body.to_data(writer)?,
-			CompressionAlgorithm::Deflate => {
-				let mut gz_encoder = flate2::write::DeflateEncoder::new(
-					writer,
-					flate2::Compression::new(numerical_level as u32),
-				);
-				self.body.to_data(&mut gz_encoder)?;
-				gz_encoder.finish()?.flush()?;
-			}
-			CompressionAlgorithm::LZMA2 => self.body.to_data(
-				&mut lzma_rust2::Lzma2WriterMt::new(
-					writer,
-					lzma_rust2::Lzma2Options {
-						lzma_options: LzmaOptions::with_preset(numerical_level as u32),
-						chunk_size: NonZero::new(1024 * 64),
-					},
-					std::thread::available_parallelism().unwrap().get() as u32,
-				)?
-				.auto_finish(),
-			)?,
-			CompressionAlgorithm::Zstd => {
-				let mut encoder = zstd::stream::write::Encoder::new(writer, numerical_level)?;
-				encoder.multithread(
-					std::thread::available_parallelism()
-						.map(|n| n.get() as u32)
-						.unwrap_or(1),
-				)?;
-				self.body.to_data(&mut encoder)?;
-				encoder.finish()?.flush()?;
+		match self.encryption {
+			None => write_body(self.body, self.compression, numerical_level, writer)?,
+			Some(keyring) => {
+				let mut encrypted = keyring.encrypt(&mut *writer, &Sha256::digest(&prefix))?;
+				write_body(self.body, self.compression, numerical_level, &mut encrypted)?;
+				encrypted.finish()?.flush()?;
 			}
 		}
 
@@ -216,43 +208,54 @@ where
 	}
 
 	pub fn from_data(reader: &mut impl Read) -> anyhow::Result<Archive<RawEntryData>> {
+		Self::from_data_with_key(reader, None)
+	}
+
+	/// Read an archive that may be encrypted, decrypting it with `keyring`.
+	/// Fails rather than guessing if an encrypted archive is read without a
+	/// keyring or with the wrong one.
+	pub fn from_data_with_key(
+		reader: &mut impl Read,
+		keyring: Option<&Keyring>,
+	) -> anyhow::Result<Archive<RawEntryData>> {
 		let mut reader = BufReader::new(reader);
 
 		let mut header: [u8; 4] = [0; 4];
 		reader.read_exact(&mut header)?;
 		assert!(header == HEADER);
 
-		let mut compression: [u8; 2] = [0; 2];
-		reader.read_exact(&mut compression)?;
-
-		let compression: CompressionAlgorithm = u16::from_be_bytes(compression)
-			.try_into()
-			.map_err(|_| anyhow!("Invalid Compression"))?;
+		let mut flags: [u8; 2] = [0; 2];
+		reader.read_exact(&mut flags)?;
 
 		let mut hash: [u8; 32] = [0; 32];
 		reader.read_exact(&mut hash)?;
-		let hash: Hash = hash.into();
 
 		let mut index_bytes = Vec::new();
 		let index_bytes_read = reader.read_until(0, &mut index_bytes)?;
 
-		let index = Index::from_data(&index_bytes[..index_bytes_read - 1]);
+		let mut prefix = HEADER.to_vec();
+		prefix.extend_from_slice(&flags);
+		prefix.extend_from_slice(&hash);
+		prefix.extend_from_slice(&index_bytes);
 
-		let body = match compression {
-			CompressionAlgorithm::None => ArchiveBody::<RawEntryData>::from_data(&mut reader)?,
-			CompressionAlgorithm::Deflate => ArchiveBody::<RawEntryData>::from_data(
-				&mut flate2::read::DeflateDecoder::new(&mut reader),
-			)?,
-			CompressionAlgorithm::LZMA2 => ArchiveBody::<RawEntryData>::from_data({
-				&mut lzma_rust2::Lzma2ReaderMt::new(
-					&mut reader,
-					lzma_rust2::LzmaOptions::DICT_SIZE_DEFAULT,
-					None,
-					std::thread::available_parallelism().unwrap().get() as u32,
-				)
-			})?,
-			CompressionAlgorithm::Zstd => ArchiveBody::<RawEntryData>::from_data(
-				&mut zstd::stream::read::Decoder::new(&mut reader)?,
+		let flags = u16::from_be_bytes(flags);
+		let compression: CompressionAlgorithm = (flags & !ENCRYPTED_FLAG)
+			.try_into()
+			.map_err(|_| anyhow!("Invalid Compression"))?;
+
+		let hash: Hash = hash.into();
+		let index = Index::parse(&index_bytes[..index_bytes_read - 1])?;
+
+		let body = match (flags & ENCRYPTED_FLAG != 0, keyring) {
+			(false, _) => read_body(compression, &mut reader)?,
+			(true, None) => {
+				return Err(anyhow!(
+					"Archive is encrypted, a passphrase or key file is needed to read it"
+				))
+			}
+			(true, Some(keyring)) => read_body(
+				compression,
+				&mut keyring.decrypt(&mut reader, &Sha256::digest(&prefix))?,
 			)?,
 		};
 
@@ -262,10 +265,76 @@ where
 			hash,
 			index,
 			body,
+			encryption: keyring.cloned(),
 		})
 	}
 }
 
+fn write_body<T: ArchiveEntryData>(
+	body: ArchiveBody<T>,
+	compression: CompressionAlgorithm,
+	numerical_level: i32,
+	writer: &mut impl Write,
+) -> anyhow::Result<()> {
+	match compression {
+		CompressionAlgorithm::None => body.to_data(writer)?,
+		CompressionAlgorithm::Deflate => {
+			let mut gz_encoder = flate2::write::DeflateEncoder::new(
+				writer,
+				flate2::Compression::new(numerical_level as u32),
+			);
+			body.to_data(&mut gz_encoder)?;
+			gz_encoder.finish()?.flush()?;
+		}
+		CompressionAlgorithm::LZMA2 => body.to_data(
+			&mut lzma_rust2::Lzma2WriterMt::new(
+				writer,
+				lzma_rust2::Lzma2Options {
+					lzma_options: LzmaOptions::with_preset(numerical_level as u32),
+					chunk_size: NonZero::new(1024 * 64),
+				},
+				std::thread::available_parallelism().unwrap().get() as u32,
+			)?
+			.auto_finish(),
+		)?,
+		CompressionAlgorithm::Zstd => {
+			let mut encoder = zstd::stream::write::Encoder::new(writer, numerical_level)?;
+			encoder.multithread(
+				std::thread::available_parallelism()
+					.map(|n| n.get() as u32)
+					.unwrap_or(1),
+			)?;
+			body.to_data(&mut encoder)?;
+			encoder.finish()?.flush()?;
+		}
+	}
+
+	Ok(())
+}
+
+fn read_body(
+	compression: CompressionAlgorithm,
+	reader: &mut impl Read,
+) -> anyhow::Result<ArchiveBody<RawEntryData>> {
+	match compression {
+		CompressionAlgorithm::None => ArchiveBody::<RawEntryData>::from_data(reader),
+		CompressionAlgorithm::Deflate => {
+			ArchiveBody::<RawEntryData>::from_data(&mut flate2::read::DeflateDecoder::new(reader))
+		}
+		CompressionAlgorithm::LZMA2 => ArchiveBody::<RawEntryData>::from_data({
+			&mut lzma_rust2::Lzma2ReaderMt::new(
+				reader,
+				lzma_rust2::LzmaOptions::DICT_SIZE_DEFAULT,
+				None,
+				std::thread::available_parallelism().unwrap().get() as u32,
+			)
+		}),
+		CompressionAlgorithm::Zstd => {
+			ArchiveBody::<RawEntryData>::from_data(&mut zstd::stream::read::Decoder::new(reader)?)
+		}
+	}
+}
+
 // /// Create a new `Body` from a [`Stream`].
 // ///
 // /// [`Stream`]: https://docs.rs/futures-core/latest/futures_core/stream/trait.Stream.html
@@ -342,12 +411,12 @@ where
 	}
 }
 
+/// An archive entry for an object file in a local cache
 pub struct FileEntryData(pub PathBuf);
 
 impl ArchiveEntryData for FileEntryData {
 	fn turn_into_vec(self) -> Vec<u8> {
-		let file = File::open(self.0).expect("File to be avaliable for read");
-		let mut reader = BufReader::new(file);
+		let mut reader = open_object_file(&self.0).expect("File to be avaliable for read");
 		let mut data = Vec::new();
 		pipe(&mut reader, &mut data).expect("reading to work");
 		data
@@ -376,6 +445,29 @@ impl ArchiveEntryData for SourceFileEntryData {
 	}
 }
 
+/// An archive entry for one chunk of a large file in the user's source tree.
+/// Like [`SourceFileEntryData`] the range is read lazily at serialisation time
+/// and prefixed with the chunk's blob header.
+pub struct SourceChunkEntryData {
+	pub source_path: PathBuf,
+	pub offset: u64,
+	pub header: crate::Header,
+}
+
+impl ArchiveEntryData for SourceChunkEntryData {
+	fn turn_into_vec(self) -> Vec<u8> {
+		let mut file = File::open(&self.source_path).expect("source file to be readable");
+		file.seek(SeekFrom::Start(self.offset))
+			.expect("source file to be seekable");
+		let mut reader = BufReader::new(file).take(self.header.size);
+		let prefix = self.header.to_string();
+		let mut data = Vec::with_capacity(prefix.len() + self.header.size as usize);
+		data.extend_from_slice(prefix.as_bytes());
+		pipe(&mut reader, &mut data).expect("reading to work");
+		data
+	}
+}
+
 pub struct StoreEntryData {
 	pub store: Store,
 	pub hash: Hash,
@@ -386,7 +478,7 @@ impl ArchiveEntryData for StoreEntryData {
 		let mut object = futures::executor::block_on(self.store.get_object(&self.hash))
 			.expect("Object to be available in store");
 
-		let mut data: Vec<u8> = Vec::new();
+		let mut data: Vec<u8> = object.header.to_string().into_bytes();
 		futures::executor::block_on(object.read_to_end(&mut data)).expect("Reading to work");
 
 		data
@@ -469,6 +561,7 @@ where
 		assert!(header_entries[0].index == 0);
 
 		let mut entries: Vec<RawEntryData> = Vec::with_capacity(header_entries.len());
+		let mut deltas = Vec::new();
 		for entry in &header_entries {
 			assert!(entry.index == counter);
 
@@ -476,15 +569,24 @@ where
 			let mut data: Vec<u8> = vec![0; amount as usize];
 			reader.read_exact(&mut data[..])?;
 
-			let mut hasher = Sha256::new();
-			hasher.write_all(&data)?;
-			assert!(Hash::from(hasher) == entry.hash);
+			if delta::is_entry(&data) {
+				// Verified once reconstructed from its base
+				deltas.push(entries.len());
+			} else {
+				let mut hasher = Sha256::new();
+				hasher.write_all(&data)?;
+				assert!(Hash::from(hasher) == entry.hash);
+			}
 
 			entries.push(RawEntryData(data.to_vec()));
 
 			counter += amount;
 		}
 
+		if !deltas.is_empty() {
+			resolve_deltas(&header_entries, &mut entries, deltas)?;
+		}
+
 		Ok(ArchiveBody {
 			header: header_entries,
 			entries,
@@ -492,6 +594,55 @@ where
 	}
 }
 
+/// Replace the delta entries at `pending` with the objects they encode. A
+/// delta's base must be another entry in the same archive, and may itself be
+/// a delta as long as it is resolvable.
+fn resolve_deltas(
+	header: &[ArchiveHeaderEntry],
+	entries: &mut [RawEntryData],
+	mut pending: Vec<usize>,
+) -> anyhow::Result<()> {
+	let positions: HashMap<&Hash, usize> = header
+		.iter()
+		.enumerate()
+		.map(|(position, entry)| (&entry.hash, position))
+		.collect();
+
+	while !pending.is_empty() {
+		let mut unresolved = Vec::new();
+
+		for &position in &pending {
+			let (base, delta) = delta::from_entry(&entries[position].0)
+				.ok_or_else(|| anyhow!("Invalid delta entry in archive"))?;
+
+			let base_position = *positions
+				.get(&base)
+				.ok_or_else(|| anyhow!("Delta base {base} is missing from the archive"))?;
+
+			if delta::is_entry(&entries[base_position].0) {
+				unresolved.push(position);
+				continue;
+			}
+
+			let data = delta::apply(&entries[base_position].0, delta)?;
+
+			let mut hasher = Sha256::new();
+			hasher.write_all(&data)?;
+			assert!(Hash::from(hasher) == header[position].hash);
+
+			entries[position] = RawEntryData(data);
+		}
+
+		if unresolved.len() == pending.len() {
+			return Err(anyhow!("Archive contains deltas that form a cycle"));
+		}
+
+		pending = unresolved;
+	}
+
+	Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -513,6 +664,7 @@ mod tests {
 				header: Vec::new(),
 				entries: Vec::new(),
 			},
+			encryption: None,
 		}
 	}
 
@@ -599,4 +751,107 @@ mod tests {
 		assert!(decoded.body.header.is_empty());
 		assert!(decoded.body.entries.is_empty());
 	}
+
+	#[test]
+	fn encrypted_archives_need_the_right_key() {
+		let keyring = Keyring::from_passphrase("correct horse").unwrap();
+		let object = b"blob 5\0hello".to_vec();
+
+		let mut archive = empty_archive(CompressionAlgorithm::Zstd);
+		archive.body.header.push(ArchiveHeaderEntry {
+			hash: hash_of(&object),
+			index: 0,
+			length: object.len() as u64,
+		});
+		archive.body.entries.push(RawEntryData::new(object.clone()));
+		archive.encryption = Some(keyring.clone());
+
+		let mut bytes = Vec::new();
+		archive
+			.to_data(CompressionLevel::Default, &mut bytes)
+			.expect("encode");
+
+		let decoded =
+			Archive::<RawEntryData>::from_data_with_key(&mut bytes.as_slice(), Some(&keyring))
+				.expect("decode");
+		let entries: Vec<Vec<u8>> = decoded
+			.body
+			.entries
+			.into_iter()
+			.map(|entry| entry.turn_into_vec())
+			.collect();
+		assert_eq!(entries, vec![object]);
+
+		let err = Archive::<RawEntryData>::from_data(&mut bytes.as_slice())
+			.err()
+			.expect("missing key to fail");
+		assert!(err.to_string().contains("encrypted"), "{err}");
+
+		let wrong = Keyring::from_passphrase("battery staple").unwrap();
+		assert!(
+			Archive::<RawEntryData>::from_data_with_key(&mut bytes.as_slice(), Some(&wrong))
+				.is_err()
+		);
+
+		// The header is authenticated along with the body
+		let mut tampered = bytes.clone();
+		tampered[HEADER.len() + 2] ^= 1;
+		assert!(Archive::<RawEntryData>::from_data_with_key(
+			&mut tampered.as_slice(),
+			Some(&keyring)
+		)
+		.is_err());
+	}
+
+	fn hash_of(data: &[u8]) -> Hash {
+		let mut hasher = Sha256::new();
+		hasher.update(data);
+		hasher.into()
+	}
+
+	#[test]
+	fn delta_entries_are_reconstructed_when_read() {
+		let base = b"blob 43\0the quick brown fox jumps over the lazy dog".to_vec();
+		let middle = b"blob 43\0the quick brown cat jumps over the lazy dog".to_vec();
+		let target = b"blob 43\0the quick brown cat jumps over the busy dog".to_vec();
+
+		// The chained delta comes first so resolution can't rely on entry order
+		let stored = [
+			(
+				hash_of(&target),
+				delta::to_entry(&hash_of(&middle), &delta::encode(&middle, &target)),
+			),
+			(
+				hash_of(&middle),
+				delta::to_entry(&hash_of(&base), &delta::encode(&base, &middle)),
+			),
+			(hash_of(&base), base.clone()),
+		];
+
+		let mut archive = empty_archive(CompressionAlgorithm::None);
+		let mut offset = 0;
+		for (hash, data) in stored {
+			archive.body.header.push(ArchiveHeaderEntry {
+				hash,
+				index: offset,
+				length: data.len() as u64,
+			});
+			offset += data.len() as u64;
+			archive.body.entries.push(RawEntryData::new(data));
+		}
+
+		let mut bytes = Vec::new();
+		archive
+			.to_data(CompressionLevel::Default, &mut bytes)
+			.expect("encode");
+		let decoded = Archive::<RawEntryData>::from_data(&mut bytes.as_slice()).expect("decode");
+
+		let entries: Vec<Vec<u8>> = decoded
+			.body
+			.entries
+			.into_iter()
+			.map(|entry| entry.turn_into_vec())
+			.collect();
+		assert_eq!(entries, vec![target, middle, base]);
+	}
 }
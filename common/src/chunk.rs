@@ -0,0 +1,207 @@
+use std::io::Read;
+
+/// Chunks are never cut shorter than this, other than the last one
+pub const MIN_CHUNK_SIZE: usize = 256 * 1024;
+/// Size chunk boundaries are normalised towards
+pub const AVERAGE_CHUNK_SIZE: usize = 1024 * 1024;
+/// Chunks are always cut at this size if no boundary was found before it
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Random values mixed into the rolling hash for each byte value. Generated
+/// with splitmix64 from a fixed seed, so must never change: doing so would move
+/// every chunk boundary and defeat deduplication against existing chunks.
+const GEAR: [u64; 256] = {
+	let mut table = [0u64; 256];
+	let mut state: u64 = 0x6172_7863_6463_6368;
+	let mut i = 0;
+	while i < 256 {
+		state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		let mut z = state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		table[i] = z ^ (z >> 31);
+		i += 1;
+	}
+	table
+};
+
+/// Splits a stream into content-defined chunks using FastCDC.
+///
+/// Boundaries depend only on the bytes around them, so inserting or removing
+/// data in a large file only changes the chunks near the edit and every other
+/// chunk keeps its hash.
+pub struct Chunker<R>
+where
+	R: Read,
+{
+	reader: R,
+	buffer: Vec<u8>,
+	eof: bool,
+	min_size: usize,
+	average_size: usize,
+	max_size: usize,
+	/// Mask used before the average size is reached. Has more bits set than
+	/// `mask_large`, making an early cut less likely.
+	mask_small: u64,
+	mask_large: u64,
+}
+
+impl<R> Chunker<R>
+where
+	R: Read,
+{
+	pub fn new(reader: R) -> Self {
+		Self::with_sizes(reader, MIN_CHUNK_SIZE, AVERAGE_CHUNK_SIZE, MAX_CHUNK_SIZE)
+	}
+
+	pub fn with_sizes(reader: R, min_size: usize, average_size: usize, max_size: usize) -> Self {
+		assert!(min_size <= average_size && average_size <= max_size);
+
+		let bits = average_size.max(2).ilog2();
+
+		Self {
+			reader,
+			buffer: Vec::new(),
+			eof: false,
+			min_size,
+			average_size,
+			max_size,
+			mask_small: mask(bits + 2),
+			mask_large: mask(bits.saturating_sub(2)),
+		}
+	}
+
+	/// Position of the end of the first chunk in `data`
+	fn cut_point(&self, data: &[u8]) -> usize {
+		if data.len() <= self.min_size {
+			return data.len();
+		}
+
+		let normal = data.len().min(self.average_size);
+		let end = data.len().min(self.max_size);
+
+		let mut hash: u64 = 0;
+		for (position, byte) in data.iter().enumerate().take(end).skip(self.min_size) {
+			hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+
+			let mask = if position < normal {
+				self.mask_small
+			} else {
+				self.mask_large
+			};
+
+			if hash & mask == 0 {
+				return position + 1;
+			}
+		}
+
+		end
+	}
+
+	fn fill(&mut self) -> std::io::Result<()> {
+		let mut buffer = [0u8; 64 * 1024];
+
+		while !self.eof && self.buffer.len() < self.max_size {
+			let read = self.reader.read(&mut buffer)?;
+
+			if read == 0 {
+				self.eof = true;
+			}
+
+			self.buffer.extend_from_slice(&buffer[..read]);
+		}
+
+		Ok(())
+	}
+}
+
+impl<R> Iterator for Chunker<R>
+where
+	R: Read,
+{
+	type Item = std::io::Result<Vec<u8>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Err(err) = self.fill() {
+			return Some(Err(err));
+		}
+
+		if self.buffer.is_empty() {
+			return None;
+		}
+
+		let cut = self.cut_point(&self.buffer);
+		let rest = self.buffer.split_off(cut);
+
+		Some(Ok(std::mem::replace(&mut self.buffer, rest)))
+	}
+}
+
+/// A mask with `bits` bits set, taken from the top of the hash as those bits
+/// depend on the most bytes.
+fn mask(bits: u32) -> u64 {
+	match bits {
+		0 => 0,
+		64.. => u64::MAX,
+		bits => u64::MAX << (64 - bits),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pseudo_random(length: usize) -> Vec<u8> {
+		let mut state: u64 = 1;
+		(0..length)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				(state >> 56) as u8
+			})
+			.collect()
+	}
+
+	fn chunk(data: &[u8]) -> Vec<Vec<u8>> {
+		Chunker::with_sizes(data, 1024, 4096, 16 * 1024)
+			.collect::<std::io::Result<_>>()
+			.unwrap()
+	}
+
+	#[test]
+	fn chunks_reassemble_to_the_input() {
+		let data = pseudo_random(200 * 1024);
+
+		let chunks = chunk(&data);
+
+		assert!(chunks.len() > 10);
+		assert!(chunks.iter().all(|chunk| chunk.len() <= 16 * 1024));
+		assert!(chunks[..chunks.len() - 1]
+			.iter()
+			.all(|chunk| chunk.len() >= 1024));
+		assert_eq!(chunks.concat(), data);
+	}
+
+	#[test]
+	fn edits_only_change_nearby_chunks() {
+		let data = pseudo_random(200 * 1024);
+		let mut edited = data.clone();
+		edited.splice(100_000..100_000, b"inserted".iter().copied());
+
+		let before = chunk(&data);
+		let after = chunk(&edited);
+
+		let unchanged = after.iter().filter(|chunk| before.contains(chunk)).count();
+		assert!(
+			unchanged >= after.len() - 2,
+			"only {unchanged} of {} chunks were unchanged",
+			after.len()
+		);
+	}
+
+	#[test]
+	fn empty_input_has_no_chunks() {
+		assert!(chunk(&[]).is_empty());
+	}
+}
@@ -1,5 +1,12 @@
 pub const INDEX_KEY: &str = "indx";
 pub const TREE_KEY: &str = "tree";
 pub const BLOB_KEY: &str = "blob";
+pub const CHUNK_LIST_KEY: &str = "chnk";
+pub const ENCRYPTED_KEY: &str = "encr";
+
+/// Magic number every zstd frame starts with. Uncompressed objects always begin
+/// with their ascii object type, so this doubles as the marker for a compressed
+/// object.
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
 
 pub const SERVER_PORT: u16 = 1287;
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::Hash;
+
+/// Marks stored bytes as a delta against another object rather than a full
+/// object. Headers never start with a null byte and zstd frames start with
+/// their own magic number, so the three can't be confused.
+pub const DELTA_MAGIC: [u8; 4] = [0, b'd', b'l', b't'];
+
+/// Length of a delta entry's prefix: the marker followed by the base hash
+pub const DELTA_PREFIX_LENGTH: usize = DELTA_MAGIC.len() + 32;
+
+/// Deltas are chained at most this deep so reconstructing an object never
+/// requires walking an unbounded number of bases.
+pub const MAX_DELTA_DEPTH: usize = 10;
+
+/// Size of the blocks of the base that are indexed when looking for matches.
+const BLOCK_SIZE: usize = 16;
+
+const COPY: u8 = 1;
+const INSERT: u8 = 2;
+
+/// Encode `target` as a sequence of copies out of `base` and literal inserts.
+///
+/// The delta starts with the base and target lengths (u64 big endian) followed
+/// by instructions: `COPY offset length` copies a range of the base, while
+/// `INSERT length bytes` inserts literal data.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+	let mut delta = Vec::new();
+	delta.extend_from_slice(&(base.len() as u64).to_be_bytes());
+	delta.extend_from_slice(&(target.len() as u64).to_be_bytes());
+
+	let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+	for (position, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+		blocks.entry(block).or_insert(position * BLOCK_SIZE);
+	}
+
+	let mut position = 0;
+	let mut pending = 0;
+
+	while position + BLOCK_SIZE <= target.len() {
+		let Some(&offset) = blocks.get(&target[position..position + BLOCK_SIZE]) else {
+			position += 1;
+			continue;
+		};
+
+		let mut length = BLOCK_SIZE;
+		while position + length < target.len()
+			&& offset + length < base.len()
+			&& target[position + length] == base[offset + length]
+		{
+			length += 1;
+		}
+
+		// Grow the match backwards over bytes we were about to insert literally
+		let mut back = 0;
+		while back < position - pending
+			&& back < offset
+			&& target[position - back - 1] == base[offset - back - 1]
+		{
+			back += 1;
+		}
+
+		push_insert(&mut delta, &target[pending..position - back]);
+		push_copy(&mut delta, (offset - back) as u64, (length + back) as u64);
+
+		position += length;
+		pending = position;
+	}
+
+	push_insert(&mut delta, &target[pending..]);
+
+	delta
+}
+
+/// Reconstruct the target of a delta produced by [`encode`] from its base
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+	let mut cursor = delta;
+
+	let base_length = read_u64(&mut cursor)?;
+	let target_length = read_u64(&mut cursor)?;
+
+	if base_length != base.len() as u64 {
+		return Err(anyhow!(
+			"Delta base length mismatch. Expected {base_length} got {}",
+			base.len()
+		));
+	}
+
+	let mut target = Vec::with_capacity(target_length as usize);
+
+	while let Some((&instruction, rest)) = cursor.split_first() {
+		cursor = rest;
+
+		match instruction {
+			COPY => {
+				let offset = read_u64(&mut cursor)? as usize;
+				let length = read_u64(&mut cursor)? as usize;
+				let range = base
+					.get(offset..offset + length)
+					.ok_or_else(|| anyhow!("Delta copies outside of its base"))?;
+				target.extend_from_slice(range);
+			}
+			INSERT => {
+				let length = read_u64(&mut cursor)? as usize;
+				if cursor.len() < length {
+					return Err(anyhow!("Delta insert runs past the end of the delta"));
+				}
+				let (data, rest) = cursor.split_at(length);
+				target.extend_from_slice(data);
+				cursor = rest;
+			}
+			other => return Err(anyhow!("Invalid delta instruction {other}")),
+		}
+	}
+
+	if target.len() as u64 != target_length {
+		return Err(anyhow!(
+			"Delta produced {} bytes but expected {target_length}",
+			target.len()
+		));
+	}
+
+	Ok(target)
+}
+
+/// Serialise a stored delta entry: the marker, the base hash, then the delta
+pub fn to_entry(base: &Hash, delta: &[u8]) -> Vec<u8> {
+	let mut entry = Vec::with_capacity(DELTA_PREFIX_LENGTH + delta.len());
+	entry.extend_from_slice(&DELTA_MAGIC);
+	entry.extend_from_slice(&base.hash);
+	entry.extend_from_slice(delta);
+	entry
+}
+
+/// Split a stored delta entry into its base hash and delta. Returns `None` if
+/// the data isn't a delta entry.
+pub fn from_entry(data: &[u8]) -> Option<(Hash, &[u8])> {
+	if !is_entry(data) {
+		return None;
+	}
+
+	let base = Hash::try_from(&data[DELTA_MAGIC.len()..DELTA_PREFIX_LENGTH]).ok()?;
+
+	Some((base, &data[DELTA_PREFIX_LENGTH..]))
+}
+
+/// Length of the object a delta produces, read from its start without
+/// applying it
+pub fn target_length(delta: &[u8]) -> Result<u64> {
+	let mut cursor = delta
+		.get(8..)
+		.ok_or_else(|| anyhow!("Delta is truncated"))?;
+	read_u64(&mut cursor)
+}
+
+pub fn is_entry(data: &[u8]) -> bool {
+	data.len() >= DELTA_PREFIX_LENGTH && data.starts_with(&DELTA_MAGIC)
+}
+
+fn push_insert(delta: &mut Vec<u8>, data: &[u8]) {
+	if data.is_empty() {
+		return;
+	}
+
+	delta.push(INSERT);
+	delta.extend_from_slice(&(data.len() as u64).to_be_bytes());
+	delta.extend_from_slice(data);
+}
+
+fn push_copy(delta: &mut Vec<u8>, offset: u64, length: u64) {
+	delta.push(COPY);
+	delta.extend_from_slice(&offset.to_be_bytes());
+	delta.extend_from_slice(&length.to_be_bytes());
+}
+
+fn read_u64(cursor: &mut &[u8]) -> Result<u64> {
+	if cursor.len() < 8 {
+		return Err(anyhow!("Delta is truncated"));
+	}
+
+	let (value, rest) = cursor.split_at(8);
+	*cursor = rest;
+
+	Ok(u64::from_be_bytes(value.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pseudo_random(length: usize, seed: u64) -> Vec<u8> {
+		let mut state = seed;
+		(0..length)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				(state >> 56) as u8
+			})
+			.collect()
+	}
+
+	#[test]
+	fn small_edits_produce_small_deltas() {
+		let base = pseudo_random(64 * 1024, 1);
+		let mut target = base.clone();
+		target[1000..1010].copy_from_slice(b"0123456789");
+		target.splice(30_000..30_000, b"inserted bytes".iter().copied());
+		target.drain(50_000..50_100);
+
+		let delta = encode(&base, &target);
+
+		assert!(delta.len() < 512, "delta was {} bytes", delta.len());
+		assert_eq!(apply(&base, &delta).unwrap(), target);
+	}
+
+	#[test]
+	fn unrelated_data_round_trips() {
+		let base = pseudo_random(4096, 1);
+		let target = pseudo_random(3000, 2);
+
+		assert_eq!(apply(&base, &encode(&base, &target)).unwrap(), target);
+		assert_eq!(apply(&base, &encode(&base, &[])).unwrap(), b"");
+		assert_eq!(apply(&[], &encode(&[], &target)).unwrap(), target);
+	}
+
+	#[test]
+	fn applying_to_the_wrong_base_fails() {
+		let base = pseudo_random(4096, 1);
+		let target = pseudo_random(4096, 2);
+		let delta = encode(&base, &target);
+
+		assert!(apply(&base[..100], &delta).is_err());
+		assert!(apply(&base, &delta[..delta.len() - 1]).is_err());
+	}
+
+	#[test]
+	fn entries_round_trip() {
+		let base = Hash::from([4u8; 32]);
+		let entry = to_entry(&base, b"delta");
+
+		let (decoded, delta) = from_entry(&entry).unwrap();
+
+		assert_eq!(decoded, base);
+		assert_eq!(delta, b"delta");
+		assert!(from_entry(b"blob 5\0hello").is_none());
+	}
+}
@@ -0,0 +1,417 @@
+use std::{
+	collections::HashMap,
+	fmt,
+	io::{self, BufRead, BufReader, Read, Write},
+	path::Path,
+	sync::{Arc, Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+	aead::{
+		rand_core::RngCore,
+		stream::{DecryptorBE32, EncryptorBE32},
+		KeyInit, OsRng, Payload,
+	},
+	XChaCha20Poly1305,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{read_header_and_body, Hash, Header};
+
+/// Every encrypted stream starts with this, followed by the key derivation
+/// used, its salt and the nonce prefix of the stream
+pub const ENCRYPTION_MAGIC: [u8; 4] = [b'a', b'r', b'x', b'e'];
+
+/// Argon2id with its default parameters
+const KDF_ARGON2ID: u8 = 1;
+
+const SALT_LENGTH: usize = 16;
+const NONCE_PREFIX_LENGTH: usize = 19;
+const TAG_LENGTH: usize = 16;
+
+/// Plaintext bytes in each encrypted segment. Every segment but the last is
+/// exactly this long.
+const SEGMENT_LENGTH: usize = 64 * 1024;
+
+type Salt = [u8; SALT_LENGTH];
+
+/// A key derived from a secret for one salt
+struct Key {
+	salt: Salt,
+	cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+	fn derive(secret: &[u8], salt: Salt) -> Result<Self> {
+		let mut key = [0u8; 32];
+		Argon2::default()
+			.hash_password_into(secret, &salt, &mut key)
+			.map_err(|err| anyhow!("Unable to derive encryption key: {err}"))?;
+
+		Ok(Self {
+			salt,
+			cipher: XChaCha20Poly1305::new(&key.into()),
+		})
+	}
+}
+
+/// Keys derived from a passphrase or key file.
+///
+/// Data is encrypted with XChaCha20-Poly1305 in 64KiB segments (the STREAM
+/// construction), under a key derived from the secret with Argon2id. Each
+/// stream records the salt its key was derived with, so streams written with
+/// the same secret at different times can all be read. Deriving a key is
+/// deliberately slow, so keys are derived once per salt and new streams share
+/// a single salt for the life of the keyring.
+#[derive(Clone)]
+pub struct Keyring {
+	secret: Arc<Vec<u8>>,
+	current: Arc<OnceLock<Arc<Key>>>,
+	derived: Arc<Mutex<HashMap<Salt, Arc<Key>>>>,
+}
+
+impl fmt::Debug for Keyring {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Keyring(..)")
+	}
+}
+
+impl Keyring {
+	pub fn from_passphrase(passphrase: &str) -> Result<Self> {
+		Self::new(passphrase.as_bytes().to_vec())
+	}
+
+	/// Use the whole contents of the file at `path` as the secret
+	pub fn from_key_file(path: &Path) -> Result<Self> {
+		let secret = std::fs::read(path)
+			.with_context(|| format!("Unable to read key file {}", path.display()))?;
+
+		Self::new(secret)
+	}
+
+	fn new(secret: Vec<u8>) -> Result<Self> {
+		if secret.is_empty() {
+			return Err(anyhow!("Encryption secret must not be empty"));
+		}
+
+		Ok(Self {
+			secret: Arc::new(secret),
+			current: Arc::new(OnceLock::new()),
+			derived: Arc::new(Mutex::new(HashMap::new())),
+		})
+	}
+
+	/// Encrypt everything written to the returned writer into `writer`.
+	/// `associated_data` is authenticated alongside every segment, so the
+	/// stream only decrypts when given the same associated data.
+	pub fn encrypt<W: Write>(
+		&self,
+		mut writer: W,
+		associated_data: &[u8],
+	) -> Result<EncryptWriter<W>> {
+		let key = match self.current.get() {
+			Some(key) => key.clone(),
+			None => {
+				let mut salt = Salt::default();
+				OsRng.fill_bytes(&mut salt);
+				let key = self.key_for(salt)?;
+				self.current.get_or_init(|| key).clone()
+			}
+		};
+
+		let mut nonce = [0u8; NONCE_PREFIX_LENGTH];
+		OsRng.fill_bytes(&mut nonce);
+
+		writer.write_all(&ENCRYPTION_MAGIC)?;
+		writer.write_all(&[KDF_ARGON2ID])?;
+		writer.write_all(&key.salt)?;
+		writer.write_all(&nonce)?;
+
+		Ok(EncryptWriter {
+			writer,
+			encryptor: Some(EncryptorBE32::from_aead(key.cipher.clone(), &nonce.into())),
+			associated_data: associated_data.to_vec(),
+			buffer: Vec::with_capacity(SEGMENT_LENGTH),
+		})
+	}
+
+	/// Decrypt a stream written by [`Keyring::encrypt`] with the same
+	/// associated data. Reads fail with [`io::ErrorKind::InvalidData`] if the
+	/// key is wrong or the stream was modified or truncated.
+	pub fn decrypt<R: Read>(&self, reader: R, associated_data: &[u8]) -> Result<DecryptReader<R>> {
+		let mut reader = BufReader::new(reader);
+
+		let mut magic = [0u8; ENCRYPTION_MAGIC.len()];
+		reader.read_exact(&mut magic)?;
+		if magic != ENCRYPTION_MAGIC {
+			return Err(anyhow!("Data is not encrypted"));
+		}
+
+		let mut kdf = [0u8; 1];
+		reader.read_exact(&mut kdf)?;
+		if kdf[0] != KDF_ARGON2ID {
+			return Err(anyhow!("Unsupported key derivation {}", kdf[0]));
+		}
+
+		let mut salt = Salt::default();
+		reader.read_exact(&mut salt)?;
+		let mut nonce = [0u8; NONCE_PREFIX_LENGTH];
+		reader.read_exact(&mut nonce)?;
+
+		let key = self.key_for(salt)?;
+
+		Ok(DecryptReader {
+			reader,
+			decryptor: Some(DecryptorBE32::from_aead(key.cipher.clone(), &nonce.into())),
+			associated_data: associated_data.to_vec(),
+			plaintext: Vec::new(),
+			position: 0,
+		})
+	}
+
+	fn key_for(&self, salt: Salt) -> Result<Arc<Key>> {
+		let mut derived = self.derived.lock().unwrap();
+
+		if let Some(key) = derived.get(&salt) {
+			return Ok(key.clone());
+		}
+
+		let key = Arc::new(Key::derive(&self.secret, salt)?);
+		derived.insert(salt, key.clone());
+
+		Ok(key)
+	}
+}
+
+/// Writer encrypting into another writer. [`EncryptWriter::finish`] must be
+/// called once everything is written, or the stream can't be decrypted.
+pub struct EncryptWriter<W: Write> {
+	writer: W,
+	encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+	associated_data: Vec<u8>,
+	buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+	/// Write the final segment, returning the inner writer
+	pub fn finish(mut self) -> io::Result<W> {
+		let encryptor = self.encryptor.take().expect("Stream to not be finished");
+		let segment = encryptor
+			.encrypt_last(Payload {
+				msg: &self.buffer,
+				aad: &self.associated_data,
+			})
+			.map_err(|_| io::Error::other("Unable to encrypt segment"))?;
+		self.writer.write_all(&segment)?;
+
+		Ok(self.writer)
+	}
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// A full segment is only written once more data arrives, as the final
+		// segment has to be encrypted differently
+		if self.buffer.len() == SEGMENT_LENGTH && !buf.is_empty() {
+			let segment = self
+				.encryptor
+				.as_mut()
+				.expect("Stream to not be finished")
+				.encrypt_next(Payload {
+					msg: &self.buffer,
+					aad: &self.associated_data,
+				})
+				.map_err(|_| io::Error::other("Unable to encrypt segment"))?;
+			self.writer.write_all(&segment)?;
+			self.buffer.clear();
+		}
+
+		let length = buf.len().min(SEGMENT_LENGTH - self.buffer.len());
+		self.buffer.extend_from_slice(&buf[..length]);
+
+		Ok(length)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+/// Reader decrypting a stream read from another reader
+pub struct DecryptReader<R: Read> {
+	reader: BufReader<R>,
+	decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+	associated_data: Vec<u8>,
+	plaintext: Vec<u8>,
+	position: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+	fn read_segment(&mut self) -> io::Result<()> {
+		let mut segment = Vec::with_capacity(SEGMENT_LENGTH + TAG_LENGTH);
+		(&mut self.reader)
+			.take((SEGMENT_LENGTH + TAG_LENGTH) as u64)
+			.read_to_end(&mut segment)?;
+
+		let payload = Payload {
+			msg: &segment,
+			aad: &self.associated_data,
+		};
+
+		let is_last =
+			segment.len() < SEGMENT_LENGTH + TAG_LENGTH || self.reader.fill_buf()?.is_empty();
+
+		let plaintext = if is_last {
+			self.decryptor
+				.take()
+				.expect("Stream to not be finished")
+				.decrypt_last(payload)
+		} else {
+			self.decryptor
+				.as_mut()
+				.expect("Stream to not be finished")
+				.decrypt_next(payload)
+		};
+
+		self.plaintext = plaintext.map_err(|_| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Unable to decrypt, the key is wrong or the data is corrupt",
+			)
+		})?;
+		self.position = 0;
+
+		Ok(())
+	}
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.position == self.plaintext.len() {
+			if self.decryptor.is_none() {
+				return Ok(0);
+			}
+
+			self.read_segment()?;
+		}
+
+		let length = buf.len().min(self.plaintext.len() - self.position);
+		buf[..length].copy_from_slice(&self.plaintext[self.position..self.position + length]);
+		self.position += length;
+
+		Ok(length)
+	}
+}
+
+/// Encrypt a whole object, header and body, so it can be stored under its
+/// plaintext `hash` without the store learning its type or contents
+pub fn encrypt_object(keyring: &Keyring, hash: &Hash, object: &mut impl Read) -> Result<Vec<u8>> {
+	let mut writer = keyring.encrypt(Vec::new(), &hash.hash)?;
+	io::copy(object, &mut writer)?;
+
+	Ok(writer.finish()?)
+}
+
+/// Decrypt an object encrypted by [`encrypt_object`], checking it is the
+/// object `hash` addresses. Returns the object's header along with the full
+/// plaintext.
+pub fn decrypt_object(
+	keyring: &Keyring,
+	hash: &Hash,
+	encrypted: &mut impl Read,
+) -> Result<(Header, Vec<u8>)> {
+	let mut object = Vec::new();
+	keyring
+		.decrypt(encrypted, &hash.hash)?
+		.read_to_end(&mut object)
+		.with_context(|| format!("Unable to decrypt object {hash}"))?;
+
+	let mut hasher = Sha256::new();
+	hasher.update(&object);
+	if Hash::from(hasher) != *hash {
+		return Err(anyhow!("Decrypted object does not match its hash {hash}"));
+	}
+
+	let (header, _) = read_header_and_body(&object)
+		.ok_or_else(|| anyhow!("Decrypted object {hash} has an invalid header"))?;
+
+	Ok((header, object))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn round_trip(keyring: &Keyring, data: &[u8]) -> Vec<u8> {
+		let mut writer = keyring.encrypt(Vec::new(), b"context").unwrap();
+		writer.write_all(data).unwrap();
+		let encrypted = writer.finish().unwrap();
+
+		let mut decrypted = Vec::new();
+		keyring
+			.decrypt(encrypted.as_slice(), b"context")
+			.unwrap()
+			.read_to_end(&mut decrypted)
+			.unwrap();
+		decrypted
+	}
+
+	#[test]
+	fn streams_round_trip_across_segment_boundaries() {
+		let keyring = Keyring::from_passphrase("correct horse").unwrap();
+
+		for length in [0, 1, SEGMENT_LENGTH, SEGMENT_LENGTH + 1, 3 * SEGMENT_LENGTH] {
+			let data: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+			assert_eq!(round_trip(&keyring, &data), data, "length {length}");
+		}
+	}
+
+	#[test]
+	fn wrong_keys_and_tampering_fail_cleanly() {
+		let keyring = Keyring::from_passphrase("correct horse").unwrap();
+
+		let mut writer = keyring.encrypt(Vec::new(), b"context").unwrap();
+		writer.write_all(&vec![7u8; 2 * SEGMENT_LENGTH]).unwrap();
+		let encrypted = writer.finish().unwrap();
+
+		let read = |keyring: &Keyring, data: &[u8], context: &[u8]| {
+			let mut out = Vec::new();
+			keyring.decrypt(data, context)?.read_to_end(&mut out)?;
+			anyhow::Ok(out)
+		};
+
+		let wrong = Keyring::from_passphrase("battery staple").unwrap();
+		let err = read(&wrong, &encrypted, b"context").unwrap_err();
+		assert!(err.to_string().contains("key is wrong"), "{err}");
+
+		assert!(read(&keyring, &encrypted, b"other context").is_err());
+
+		// Dropping the final segment must not go unnoticed
+		let truncated = &encrypted[..encrypted.len() - TAG_LENGTH];
+		assert!(read(&keyring, truncated, b"context").is_err());
+
+		assert!(read(&keyring, b"blob 3\0abc", b"context").is_err());
+	}
+
+	#[test]
+	fn objects_decrypt_only_under_their_hash() {
+		let keyring = Keyring::from_passphrase("correct horse").unwrap();
+		let object = b"blob 5\0hello".to_vec();
+		let mut hasher = Sha256::new();
+		hasher.update(&object);
+		let hash = Hash::from(hasher);
+
+		let encrypted = encrypt_object(&keyring, &hash, &mut object.as_slice()).unwrap();
+		assert!(encrypted.starts_with(&ENCRYPTION_MAGIC));
+
+		let (header, decrypted) =
+			decrypt_object(&keyring, &hash, &mut encrypted.as_slice()).unwrap();
+		assert_eq!(header, Header::new(crate::ObjectType::Blob, 5));
+		assert_eq!(decrypted, object);
+
+		let other = Hash::from([1u8; 32]);
+		assert!(decrypt_object(&keyring, &other, &mut encrypted.as_slice()).is_err());
+	}
+}
@@ -1,6 +1,6 @@
 use anyhow::anyhow;
 use serde::de::{self, Visitor};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::digest::FixedOutput;
 
 use sha2::Sha256;
@@ -9,10 +9,9 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 // use std::hash::Hash;
 
-#[derive(Clone, Serialize)]
+#[derive(Clone)]
 pub struct Hash {
 	// Sha256 Hash value
-	#[serde(skip)]
 	pub hash: [u8; 32],
 	hash_string: String,
 }
@@ -165,6 +164,16 @@ impl Display for Hash {
 	}
 }
 
+/// Serialised as the hex string, matching what [`Deserialize`] accepts
+impl Serialize for Hash {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		serializer.serialize_str(&self.hash_string)
+	}
+}
+
 impl<'de> Deserialize<'de> for Hash {
 	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
 	where
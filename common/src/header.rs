@@ -4,7 +4,10 @@ use std::{
 };
 
 use anyhow::{anyhow, Result};
-use futures::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use futures::{
+	AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite,
+	AsyncWriteExt,
+};
 
 use crate::ObjectType;
 
@@ -87,16 +90,35 @@ impl Header {
 		let buffer = &buffer[..bytes_read];
 
 		// Find the null marker of the header. If its not available then we just gotta assume the whole buffer is a valid utf8 header
-		let null_position = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+		let (null_position, body_start) = match buffer.iter().position(|x| *x == 0) {
+			Some(position) => (position, position + 1),
+			None => (buffer.len(), buffer.len()),
+		};
 		let buffer = &buffer[..null_position];
 
+		// The body begins after the null marker, not on it
 		reader
-			.seek(std::io::SeekFrom::Start(null_position as u64))
+			.seek(std::io::SeekFrom::Start(body_start as u64))
 			.await?;
 
 		Self::from_data(buffer)
 	}
 
+	/// Read a header from a stream that can't seek (such as a decompressor),
+	/// leaving the reader positioned at the start of the body.
+	pub async fn read_from_async_buf(
+		reader: &mut (impl AsyncBufRead + std::marker::Unpin),
+	) -> Result<Self> {
+		let mut buffer = Vec::new();
+		reader.read_until(0, &mut buffer).await?;
+
+		if buffer.last() != Some(&0) {
+			return Err(anyhow!("Invalid Header: missing null terminator"));
+		}
+
+		Self::from_data(&buffer)
+	}
+
 	pub fn read_from(reader: &mut impl Read) -> Result<Self> {
 		let mut buffer = [0u8; 32];
 		let bytes_read = reader.read(&mut buffer)?;
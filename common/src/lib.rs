@@ -1,27 +1,34 @@
 use std::{
 	collections::HashMap,
 	fs::File,
-	io::{BufRead, BufReader, Read, Write},
+	io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
 	path::Path,
 	str::from_utf8,
 };
 
 use futures::AsyncReadExt;
 
-pub use crate::constants::{BLOB_KEY, INDEX_KEY, TREE_KEY};
+pub use crate::constants::{
+	BLOB_KEY, CHUNK_LIST_KEY, ENCRYPTED_KEY, INDEX_KEY, TREE_KEY, ZSTD_MAGIC,
+};
 pub use crate::hash::Hash;
 pub use crate::header::Header;
 pub use crate::object::Object;
 pub use crate::primitives::{Mode, ObjectType};
-use crate::{object_body::Object as ObjectTrait, store::Store};
+use crate::{object_body::MalformedObject, store::Store};
 
 pub mod archive;
+pub mod chunk;
 pub mod constants;
+pub mod delta;
+pub mod encryption;
 pub mod hash;
 pub mod header;
 pub mod object;
 pub mod object_body;
+pub mod pack;
 pub mod primitives;
+pub mod signature;
 pub mod store;
 
 pub fn read_slice_until_byte(data: &[u8], byte: u8) -> Option<&[u8]> {
@@ -52,11 +59,35 @@ pub fn read_header_from_slice(slice: &[u8]) -> Option<Header> {
 	))
 }
 
-pub fn read_header_from_file(reader: &mut BufReader<File>) -> Option<Header> {
+pub fn read_header_from_file(reader: &mut impl BufRead) -> Option<Header> {
 	let mut vec = Vec::new();
 	reader.read_until(b'\0', &mut vec).ok()?;
 
-	read_header_from_slice(&vec[..vec.len() - 1])
+	// A file without a complete header, such as an empty one, has no header
+	let header = vec.strip_suffix(&[0]).filter(|header| !header.is_empty())?;
+	read_header_from_slice(header)
+}
+
+/// Reader over the contents of a loose object file. Objects that were written
+/// compressed are transparently decompressed.
+pub type ObjectFileReader = BufReader<Box<dyn Read + Send>>;
+
+pub fn open_object_file(path: &Path) -> std::io::Result<ObjectFileReader> {
+	let mut file = File::open(path)?;
+
+	let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+	(&mut file)
+		.take(ZSTD_MAGIC.len() as u64)
+		.read_to_end(&mut magic)?;
+	file.seek(SeekFrom::Start(0))?;
+
+	if magic == ZSTD_MAGIC {
+		Ok(BufReader::new(Box::new(zstd::stream::read::Decoder::new(
+			file,
+		)?)))
+	} else {
+		Ok(BufReader::new(Box::new(file)))
+	}
 }
 
 pub async fn read_object_into_headers(
@@ -93,11 +124,11 @@ pub async fn read_object_into_headers(
 			"Read size must match header size"
 		);
 
-		let tree = crate::object_body::Tree::from_data(&data);
-
-		for entry in &tree.contents {
-			stack.push(entry.hash.clone());
-		}
+		stack.extend(referenced_objects(
+			&current_hash,
+			object.header.object_type,
+			&data,
+		)?);
 	}
 
 	Ok(())
@@ -116,8 +147,7 @@ pub fn read_object_into_headers_sync(
 		}
 
 		let object_path = current_hash.get_path(cache);
-		let file = File::open(object_path)?;
-		let mut reader = BufReader::new(file);
+		let mut reader = open_object_file(&object_path)?;
 		let mut data = Vec::new();
 		let bytes_read = reader.read_until(0, &mut data)?;
 
@@ -137,16 +167,38 @@ pub fn read_object_into_headers_sync(
 		data.clear();
 		reader.read_to_end(&mut data)?;
 
-		let tree = crate::object_body::Tree::from_data(&data);
-
-		for entry in &tree.contents {
-			stack.push(entry.hash.clone());
-		}
+		stack.extend(referenced_objects(
+			&current_hash,
+			header.object_type,
+			&data,
+		)?);
 	}
 
 	Ok(())
 }
 
+/// Hashes of the objects referenced by the body of a tree or chunk list
+fn referenced_objects(
+	hash: &Hash,
+	object_type: ObjectType,
+	data: &[u8],
+) -> Result<Vec<Hash>, MalformedObject> {
+	match object_type {
+		ObjectType::ChunkList => crate::object_body::ChunkList::parse(data)
+			.map(|list| list.chunks.into_iter().map(|chunk| chunk.hash).collect())
+			.map_err(|error| MalformedObject {
+				hash: hash.clone(),
+				error,
+			}),
+		_ => crate::object_body::Tree::parse(data)
+			.map(|tree| tree.contents.into_iter().map(|entry| entry.hash).collect())
+			.map_err(|error| MalformedObject {
+				hash: hash.clone(),
+				error,
+			}),
+	}
+}
+
 pub fn pipe(reader: &mut dyn Read, writer: &mut dyn Write) -> anyhow::Result<()> {
 	let mut buffer: [u8; 1024] = [0; 1024];
 	loop {
@@ -1,5 +1,6 @@
-use std::{collections::HashMap, io::Write, str::from_utf8};
+use std::{collections::HashMap, fmt::Display, io::Write, str::from_utf8};
 
+use anyhow::{anyhow, bail};
 use chrono::{DateTime, Utc};
 
 use crate::{Hash, Mode};
@@ -9,6 +10,21 @@ pub trait Object {
 	fn to_data(&self) -> Vec<u8>;
 }
 
+/// An object whose body doesn't parse as its type
+#[derive(Debug)]
+pub struct MalformedObject {
+	pub hash: Hash,
+	pub error: anyhow::Error,
+}
+
+impl Display for MalformedObject {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Object {} is malformed: {:#}", self.hash, self.error)
+	}
+}
+
+impl std::error::Error for MalformedObject {}
+
 const TREE_KEY: &str = "tree";
 const TIMESTAMP_KEY: &str = "timestamp";
 #[derive(Debug)]
@@ -18,64 +34,76 @@ pub struct Index {
 	pub metadata: HashMap<String, String>,
 }
 
-impl Object for Index {
-	//TODO: This HAS to return a result. We need to fix that
-	fn from_data(data: &[u8]) -> Self {
-		let string_data = from_utf8(data).expect("Data to be in valid utf8 format");
+impl Index {
+	/// Parses an index, failing rather than panicking on malformed data, so
+	/// indexes from elsewhere can be checked before they're trusted
+	pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+		let string_data =
+			from_utf8(data).map_err(|_| anyhow!("Index must be in valid utf8 format"))?;
 
-		assert!(
-			&string_data[string_data.len() - 2..] == "\n\n",
-			"Index MUST end in a double newline"
-		);
-		let string_data = &string_data[..string_data.len() - 2];
+		let Some(string_data) = string_data.strip_suffix("\n\n") else {
+			bail!("Index MUST end in a double newline");
+		};
 
 		let mut tree_hash: Option<Hash> = None;
 		let mut timestamp: Option<DateTime<Utc>> = None;
 		let mut metadata = HashMap::new();
 
-		let lines: Vec<&str> = string_data.split('\n').collect();
-		for line in lines {
+		for line in string_data.split('\n') {
 			if line.trim() == "" {
-				panic!("Index CANNOT contain a blank line")
+				bail!("Index CANNOT contain a blank line");
 			}
 
-			let (key, value) = line
-				.split_once(':')
-				.expect("Each line to be properly formatted");
+			let Some((key, value)) = line.split_once(':') else {
+				bail!("Index line {line:?} is not a `key: value` pair");
+			};
 			let key = key.trim();
 			let value = value.trim();
 
-			match key.trim() {
-				TREE_KEY => tree_hash = Some(Hash::try_from(value).expect("Hash to be valid")),
+			match key {
+				TREE_KEY => {
+					tree_hash = Some(
+						Hash::try_from(value)
+							.map_err(|_| anyhow!("Invalid tree hash {value:?}"))?,
+					)
+				}
 				TIMESTAMP_KEY => {
 					timestamp = Some(
 						DateTime::parse_from_rfc3339(value)
-							.expect("Timestamp to be in the rfc3339 format")
+							.map_err(|_| {
+								anyhow!("Timestamp {value:?} is not in the rfc3339 format")
+							})?
 							.into(),
 					)
 				}
 				_ => {
 					if tree_hash.is_none() {
-						panic!("Tree MUST come first");
+						bail!("Tree MUST come first");
 					}
 					if timestamp.is_none() {
-						panic!("Timestamp MUST come second");
+						bail!("Timestamp MUST come second");
 					}
 					if metadata
-						.insert(key.to_string(), value.trim().to_string())
+						.insert(key.to_string(), value.to_string())
 						.is_some()
 					{
-						panic!("No duplicate keys allowed within Index Metadata");
+						bail!("No duplicate keys allowed within Index Metadata");
 					}
 				}
 			}
 		}
 
-		Index {
-			tree: tree_hash.expect("tree to exist within artifact metadata"),
-			timestamp: timestamp.expect("timestamp to exist within artifact metadata"),
+		Ok(Index {
+			tree: tree_hash.ok_or_else(|| anyhow!("Index has no tree"))?,
+			timestamp: timestamp.ok_or_else(|| anyhow!("Index has no timestamp"))?,
 			metadata,
-		}
+		})
+	}
+}
+
+impl Object for Index {
+	fn from_data(data: &[u8]) -> Self {
+		Self::parse(data).expect("Index to be valid")
 	}
 
 	fn to_data(&self) -> Vec<u8> {
@@ -114,42 +142,48 @@ pub struct TreeEntry {
 pub struct Tree {
 	pub contents: Vec<TreeEntry>,
 }
-impl Object for Tree {
-	fn from_data(data: &[u8]) -> Self {
+impl Tree {
+	/// Parses a tree, failing rather than panicking on malformed data
+	pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
 		let mut contents = Vec::new();
 
 		let mut index: usize = 0;
-		loop {
-			if index == data.len() {
-				break;
-			}
-
+		while index < data.len() {
 			let remaining = &data[index..];
 
 			let Some(position) = remaining.iter().position(|v| *v == 0) else {
-				panic!("Entry must contain null char");
+				bail!("Tree entry at byte {index} has no null terminator");
 			};
 
-			let string = from_utf8(&remaining[..position]).expect("Entry must be valid utf8");
+			let string = from_utf8(&remaining[..position])
+				.map_err(|_| anyhow!("Tree entry at byte {index} is not valid utf8"))?;
 			let position = position + 1;
 
-			let (mode, name) = string
-				.split_once(' ')
-				.expect("mode and filename to be seperated by space");
-			let mode = Mode::from_str(mode).expect("valid mode");
+			let Some((mode, name)) = string.split_once(' ') else {
+				bail!("Tree entry {string:?} has no space between mode and filename");
+			};
+			let mode = Mode::from_str(mode)
+				.ok_or_else(|| anyhow!("Tree entry {string:?} has an unknown mode"))?;
 
-			let hash =
-				Hash::try_from(&remaining[position..position + 64]).expect("Hash to be valid");
+			let Some(hash) = remaining.get(position..position + 32) else {
+				bail!("Tree entry {string:?} is cut short before its hash");
+			};
 			contents.push(TreeEntry {
-				hash,
+				hash: Hash::try_from(hash).expect("Hash to be valid"),
 				mode,
 				path: name.to_string(),
 			});
 
-			index += position + 64;
+			index += position + 32;
 		}
 
-		Tree { contents }
+		Ok(Tree { contents })
+	}
+}
+
+impl Object for Tree {
+	fn from_data(data: &[u8]) -> Self {
+		Self::parse(data).expect("Tree to be valid")
 	}
 
 	fn to_data(&self) -> Vec<u8> {
@@ -172,3 +206,141 @@ impl Object for Tree {
 		data
 	}
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkListEntry {
+	pub hash: Hash,
+	pub size: u64,
+}
+
+/// A file stored as a sequence of blobs. Each entry is the chunk's blob hash
+/// followed by the chunk size as a big endian u64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkList {
+	pub chunks: Vec<ChunkListEntry>,
+}
+
+impl ChunkList {
+	/// Size of the file the chunks make up
+	pub fn size(&self) -> u64 {
+		self.chunks.iter().map(|chunk| chunk.size).sum()
+	}
+
+	/// Parses a chunk list, failing rather than panicking on malformed data
+	pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+		if !data.len().is_multiple_of(40) {
+			bail!(
+				"Chunk list of {} bytes is not made of 40 byte entries",
+				data.len()
+			);
+		}
+
+		let chunks = data
+			.chunks_exact(40)
+			.map(|entry| ChunkListEntry {
+				hash: Hash::try_from(&entry[..32]).expect("Hash to be valid"),
+				size: u64::from_be_bytes(entry[32..].try_into().expect("Size to be 8 bytes")),
+			})
+			.collect();
+
+		Ok(ChunkList { chunks })
+	}
+}
+
+impl Object for ChunkList {
+	fn from_data(data: &[u8]) -> Self {
+		Self::parse(data).expect("Chunk list to be valid")
+	}
+
+	fn to_data(&self) -> Vec<u8> {
+		let mut data = Vec::with_capacity(self.chunks.len() * 40);
+
+		for chunk in &self.chunks {
+			data.extend_from_slice(&chunk.hash.hash);
+			data.extend_from_slice(&chunk.size.to_be_bytes());
+		}
+
+		data
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn malformed_indexes_are_errors() {
+		let index = Index {
+			tree: Hash::from([1u8; 32]),
+			timestamp: DateTime::from_timestamp(100, 0).unwrap(),
+			metadata: HashMap::from([("branch".to_string(), "main".to_string())]),
+		};
+		let parsed = Index::parse(&index.to_data()).unwrap();
+		assert_eq!(parsed.tree, index.tree);
+		assert_eq!(parsed.metadata, index.metadata);
+
+		for data in [
+			&b""[..],
+			b"\n",
+			b"\xff\n\n",
+			b"tree: nonsense\n\n",
+			b"branch: main\n\n",
+			b"timestamp: 1970-01-01T00:01:40+00:00\n\n",
+		] {
+			assert!(Index::parse(data).is_err(), "{data:?} parsed");
+		}
+	}
+
+	#[test]
+	fn tree_round_trip() {
+		let tree = Tree {
+			contents: vec![
+				TreeEntry {
+					mode: Mode::Normal,
+					path: "a.txt".into(),
+					hash: Hash::from([1u8; 32]),
+				},
+				TreeEntry {
+					mode: Mode::Tree,
+					path: "sub".into(),
+					hash: Hash::from([2u8; 32]),
+				},
+			],
+		};
+
+		let decoded = Tree::from_data(&tree.to_data());
+
+		assert_eq!(decoded.contents.len(), 2);
+		assert_eq!(decoded.contents[0].path, "a.txt");
+		assert_eq!(decoded.contents[0].hash, Hash::from([1u8; 32]));
+		assert_eq!(decoded.contents[1].path, "sub");
+		assert_eq!(decoded.contents[1].hash, Hash::from([2u8; 32]));
+
+		let data = tree.to_data();
+		assert!(Tree::parse(&data[..data.len() - 1]).is_err());
+		assert!(Tree::parse(b"100644 a.txt").is_err());
+		assert!(Tree::parse(b"nonsense a.txt\0").is_err());
+	}
+
+	#[test]
+	fn chunk_list_round_trip() {
+		let list = ChunkList {
+			chunks: vec![
+				ChunkListEntry {
+					hash: Hash::from([1u8; 32]),
+					size: 1024,
+				},
+				ChunkListEntry {
+					hash: Hash::from([2u8; 32]),
+					size: 17,
+				},
+			],
+		};
+
+		let decoded = ChunkList::from_data(&list.to_data());
+
+		assert_eq!(decoded, list);
+		assert_eq!(decoded.size(), 1041);
+		assert!(ChunkList::parse(&list.to_data()[1..]).is_err());
+	}
+}
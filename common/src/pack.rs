@@ -0,0 +1,353 @@
+use std::io::Read;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use futures::{AsyncWrite, AsyncWriteExt};
+use sha2::{Digest, Sha256};
+
+use crate::{Hash, ObjectType};
+
+pub const PACK_HEADER: [u8; 4] = [b'a', b'r', b'x', b'p'];
+pub const PACK_INDEX_HEADER: [u8; 4] = [b'a', b'r', b'x', b'i'];
+pub const PACK_ROOTS_HEADER: [u8; 4] = [b'a', b'r', b'x', b'r'];
+
+/// Directory (relative to the store root) holding pack and pack index files.
+pub const PACK_DIR: &str = "pack/";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackIndexEntry {
+	pub hash: Hash,
+	/// Type of the object, so listing a pack's contents never requires reading it
+	pub object_type: ObjectType,
+	/// Offset of the object from the start of the pack file
+	pub offset: u64,
+	/// Length of the stored object, including its header
+	pub length: u64,
+}
+
+/// Lookup table for a single pack file. Entries are kept sorted by hash so an
+/// object can be located with a binary search.
+#[derive(Debug, Default, Clone)]
+pub struct PackIndex {
+	entries: Vec<PackIndexEntry>,
+}
+
+impl PackIndex {
+	pub fn new(mut entries: Vec<PackIndexEntry>) -> Self {
+		entries.sort_by_key(|entry| entry.hash.hash);
+		Self { entries }
+	}
+
+	pub fn find(&self, hash: &Hash) -> Option<&PackIndexEntry> {
+		self.entries
+			.binary_search_by(|entry| entry.hash.hash.cmp(&hash.hash))
+			.ok()
+			.map(|position| &self.entries[position])
+	}
+
+	pub fn entries(&self) -> &[PackIndexEntry] {
+		&self.entries
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// Name shared by a pack and its index. Derived from the sorted object
+	/// hashes so that packing the same set of objects twice yields the same name.
+	pub fn name(&self) -> Hash {
+		let mut hasher = Sha256::new();
+		for entry in &self.entries {
+			hasher.update(entry.hash.hash);
+		}
+		hasher.into()
+	}
+
+	pub fn to_data(&self) -> Vec<u8> {
+		let mut data = Vec::with_capacity(4 + 8 + self.entries.len() * 52);
+		data.extend_from_slice(&PACK_INDEX_HEADER);
+		data.extend_from_slice(&(self.entries.len() as u64).to_be_bytes());
+
+		for entry in &self.entries {
+			data.extend_from_slice(&entry.hash.hash);
+			data.extend_from_slice(entry.object_type.to_str().as_bytes());
+			data.extend_from_slice(&entry.offset.to_be_bytes());
+			data.extend_from_slice(&entry.length.to_be_bytes());
+		}
+
+		data
+	}
+
+	pub fn from_data(reader: &mut impl Read) -> anyhow::Result<Self> {
+		let mut header: [u8; 4] = [0; 4];
+		reader.read_exact(&mut header)?;
+
+		if header != PACK_INDEX_HEADER {
+			return Err(anyhow!("Invalid pack index header"));
+		}
+
+		let mut long: [u8; 8] = [0; 8];
+		reader.read_exact(&mut long)?;
+		let count = u64::from_be_bytes(long);
+
+		let mut entries = Vec::with_capacity(count as usize);
+		for _ in 0..count {
+			let mut hash: [u8; 32] = [0; 32];
+			reader.read_exact(&mut hash)?;
+
+			let mut object_type: [u8; 4] = [0; 4];
+			reader.read_exact(&mut object_type)?;
+			let object_type = std::str::from_utf8(&object_type)
+				.ok()
+				.and_then(ObjectType::from_str)
+				.ok_or_else(|| anyhow!("Invalid object type in pack index"))?;
+
+			reader.read_exact(&mut long)?;
+			let offset = u64::from_be_bytes(long);
+
+			reader.read_exact(&mut long)?;
+			let length = u64::from_be_bytes(long);
+
+			entries.push(PackIndexEntry {
+				hash: hash.into(),
+				object_type,
+				offset,
+				length,
+			});
+		}
+
+		if !entries.is_sorted_by(|a, b| a.hash.hash < b.hash.hash) {
+			return Err(anyhow!("Pack index entries are not sorted"));
+		}
+
+		Ok(Self { entries })
+	}
+}
+
+/// When an index in a pack was made and its root tree, so a later repack can
+/// find the files it held without reading every index in the store again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackRoot {
+	pub timestamp: DateTime<Utc>,
+	pub tree: Hash,
+}
+
+pub fn roots_to_data(roots: &[PackRoot]) -> Vec<u8> {
+	let mut data = Vec::with_capacity(4 + 8 + roots.len() * 44);
+	data.extend_from_slice(&PACK_ROOTS_HEADER);
+	data.extend_from_slice(&(roots.len() as u64).to_be_bytes());
+
+	for root in roots {
+		data.extend_from_slice(&root.timestamp.timestamp().to_be_bytes());
+		data.extend_from_slice(&root.timestamp.timestamp_subsec_nanos().to_be_bytes());
+		data.extend_from_slice(&root.tree.hash);
+	}
+
+	data
+}
+
+pub fn roots_from_data(reader: &mut impl Read) -> anyhow::Result<Vec<PackRoot>> {
+	let mut header: [u8; 4] = [0; 4];
+	reader.read_exact(&mut header)?;
+
+	if header != PACK_ROOTS_HEADER {
+		return Err(anyhow!("Invalid pack roots header"));
+	}
+
+	let mut long: [u8; 8] = [0; 8];
+	reader.read_exact(&mut long)?;
+	let count = u64::from_be_bytes(long);
+
+	let mut roots = Vec::new();
+	for _ in 0..count {
+		reader.read_exact(&mut long)?;
+		let seconds = i64::from_be_bytes(long);
+
+		let mut int: [u8; 4] = [0; 4];
+		reader.read_exact(&mut int)?;
+		let nanos = u32::from_be_bytes(int);
+
+		let mut tree: [u8; 32] = [0; 32];
+		reader.read_exact(&mut tree)?;
+
+		roots.push(PackRoot {
+			timestamp: DateTime::from_timestamp(seconds, nanos)
+				.ok_or_else(|| anyhow!("Invalid timestamp in pack roots"))?,
+			tree: tree.into(),
+		});
+	}
+
+	Ok(roots)
+}
+
+/// Incrementally builds a pack file, recording where each object lands so the
+/// matching [`PackIndex`] can be produced once every object has been written.
+pub struct PackWriter<W>
+where
+	W: AsyncWrite + Unpin,
+{
+	writer: W,
+	offset: u64,
+	entries: Vec<PackIndexEntry>,
+}
+
+impl<W> PackWriter<W>
+where
+	W: AsyncWrite + Unpin,
+{
+	pub async fn new(mut writer: W) -> std::io::Result<Self> {
+		writer.write_all(&PACK_HEADER).await?;
+
+		Ok(Self {
+			writer,
+			offset: PACK_HEADER.len() as u64,
+			entries: Vec::new(),
+		})
+	}
+
+	/// Append a stored object (header and body, or a delta entry) to the pack
+	pub async fn add(
+		&mut self,
+		hash: Hash,
+		object_type: ObjectType,
+		data: &[u8],
+	) -> std::io::Result<()> {
+		self.writer.write_all(data).await?;
+
+		self.entries.push(PackIndexEntry {
+			hash,
+			object_type,
+			offset: self.offset,
+			length: data.len() as u64,
+		});
+		self.offset += data.len() as u64;
+
+		Ok(())
+	}
+
+	pub async fn finish(mut self) -> std::io::Result<(W, PackIndex)> {
+		self.writer.close().await?;
+		Ok((self.writer, PackIndex::new(self.entries)))
+	}
+}
+
+pub fn pack_path(name: &Hash) -> String {
+	format!("{PACK_DIR}{name}.pack")
+}
+
+pub fn pack_index_path(name: &Hash) -> String {
+	format!("{PACK_DIR}{name}.idx")
+}
+
+pub fn pack_roots_path(name: &Hash) -> String {
+	format!("{PACK_DIR}{name}.roots")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hash(byte: u8) -> Hash {
+		Hash::from([byte; 32])
+	}
+
+	#[tokio::test]
+	async fn index_round_trip_preserves_sorted_entries() {
+		let mut writer = PackWriter::new(Vec::new()).await.unwrap();
+		writer
+			.add(hash(3), ObjectType::Blob, b"blob 1\0c")
+			.await
+			.unwrap();
+		writer
+			.add(hash(1), ObjectType::Tree, b"tree 1\0a")
+			.await
+			.unwrap();
+		writer
+			.add(hash(2), ObjectType::Blob, b"blob 2\0bb")
+			.await
+			.unwrap();
+		let (pack, index) = writer.finish().await.unwrap();
+
+		let decoded = PackIndex::from_data(&mut index.to_data().as_slice()).unwrap();
+		let hashes: Vec<&Hash> = decoded.entries().iter().map(|e| &e.hash).collect();
+		assert_eq!(hashes, vec![&hash(1), &hash(2), &hash(3)]);
+
+		assert_eq!(
+			decoded.find(&hash(1)).unwrap().object_type,
+			ObjectType::Tree
+		);
+
+		let entry = decoded.find(&hash(2)).expect("object to be in the pack");
+		let start = entry.offset as usize;
+		let end = start + entry.length as usize;
+		assert_eq!(&pack[start..end], b"blob 2\0bb");
+	}
+
+	#[test]
+	fn roots_round_trip() {
+		let roots = vec![
+			PackRoot {
+				timestamp: DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap(),
+				tree: hash(1),
+			},
+			PackRoot {
+				timestamp: DateTime::from_timestamp(-5, 0).unwrap(),
+				tree: hash(2),
+			},
+		];
+
+		let data = roots_to_data(&roots);
+		assert_eq!(roots_from_data(&mut data.as_slice()).unwrap(), roots);
+		assert!(roots_from_data(&mut &data[..data.len() - 1]).is_err());
+	}
+
+	#[test]
+	fn find_misses_objects_not_in_the_pack() {
+		let index = PackIndex::new(vec![PackIndexEntry {
+			hash: hash(1),
+			object_type: ObjectType::Blob,
+			offset: 4,
+			length: 8,
+		}]);
+
+		assert!(index.find(&hash(2)).is_none());
+	}
+
+	#[test]
+	fn name_does_not_depend_on_insertion_order() {
+		let a = PackIndex::new(vec![
+			PackIndexEntry {
+				hash: hash(1),
+				object_type: ObjectType::Blob,
+				offset: 4,
+				length: 1,
+			},
+			PackIndexEntry {
+				hash: hash(2),
+				object_type: ObjectType::Blob,
+				offset: 5,
+				length: 1,
+			},
+		]);
+		let b = PackIndex::new(vec![
+			PackIndexEntry {
+				hash: hash(2),
+				object_type: ObjectType::Blob,
+				offset: 4,
+				length: 1,
+			},
+			PackIndexEntry {
+				hash: hash(1),
+				object_type: ObjectType::Blob,
+				offset: 5,
+				length: 1,
+			},
+		]);
+
+		assert_eq!(a.name(), b.name());
+	}
+}
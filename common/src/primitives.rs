@@ -1,4 +1,4 @@
-use crate::{BLOB_KEY, INDEX_KEY, TREE_KEY};
+use crate::{BLOB_KEY, CHUNK_LIST_KEY, ENCRYPTED_KEY, INDEX_KEY, TREE_KEY};
 use std::fmt::Display;
 
 #[allow(clippy::zero_prefixed_literal)]
@@ -48,6 +48,11 @@ pub enum ObjectType {
 	Blob,
 	Tree,
 	Index,
+	/// A large file split into chunks, each stored as its own blob
+	ChunkList,
+	/// An object encrypted by the client, stored under the hash of its
+	/// plaintext. Its type and contents are only known to holders of the key.
+	Encrypted,
 }
 
 impl ObjectType {
@@ -57,6 +62,8 @@ impl ObjectType {
 			BLOB_KEY => Some(Self::Blob),
 			TREE_KEY => Some(Self::Tree),
 			INDEX_KEY => Some(Self::Index),
+			CHUNK_LIST_KEY => Some(Self::ChunkList),
+			ENCRYPTED_KEY => Some(Self::Encrypted),
 			_ => None,
 		}
 	}
@@ -66,6 +73,8 @@ impl ObjectType {
 			Self::Blob => BLOB_KEY,
 			Self::Index => INDEX_KEY,
 			Self::Tree => TREE_KEY,
+			Self::ChunkList => CHUNK_LIST_KEY,
+			Self::Encrypted => ENCRYPTED_KEY,
 		}
 	}
 }
@@ -0,0 +1,194 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use ssh_key::{AuthorizedKeys, HashAlg, LineEnding, SshSig};
+
+pub use ssh_key::{PrivateKey, PublicKey};
+
+use crate::object_body::Index;
+
+/// Index metadata key holding the signature of the rest of the index
+pub const SIGNATURE_KEY: &str = "signature";
+
+/// SSHSIG namespace index signatures are made in, so a signature over an
+/// index can't be passed off as one made for another purpose
+pub const SIGNATURE_NAMESPACE: &str = "arx-index";
+
+const PEM_BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const PEM_END: &str = "-----END SSH SIGNATURE-----";
+const PEM_LINE_WIDTH: usize = 70;
+
+/// The bytes of an index covered by its signature: the tree, timestamp and
+/// metadata sorted by key, without the signature itself. Independent of the
+/// order the metadata was stored in.
+pub fn signed_data(index: &Index) -> Vec<u8> {
+	let metadata: BTreeMap<_, _> = index
+		.metadata
+		.iter()
+		.filter(|(key, _)| key.as_str() != SIGNATURE_KEY)
+		.collect();
+
+	let mut data = format!(
+		"tree: {}\ntimestamp: {}\n",
+		index.tree,
+		index.timestamp.to_rfc3339()
+	);
+	for (key, value) in metadata {
+		data.push_str(&format!("{key}: {value}\n"));
+	}
+	data.push('\n');
+
+	data.into_bytes()
+}
+
+/// Sign `index` with `key`, replacing any existing signature. The signature is
+/// an SSHSIG, as produced by `ssh-keygen -Y sign -n arx-index`, stored as a
+/// single line of base64 in the index metadata.
+pub fn sign_index(index: &mut Index, key: &PrivateKey) -> Result<()> {
+	if key.is_encrypted() {
+		return Err(anyhow!(
+			"Signing key is encrypted, decrypted keys are required"
+		));
+	}
+
+	let signature = key.sign(SIGNATURE_NAMESPACE, HashAlg::Sha512, &signed_data(index))?;
+
+	let pem = signature.to_pem(LineEnding::LF)?;
+	let encoded: String = pem
+		.lines()
+		.filter(|line| !line.starts_with("-----"))
+		.collect();
+
+	index.metadata.insert(SIGNATURE_KEY.to_string(), encoded);
+
+	Ok(())
+}
+
+/// Check `index` is signed by one of `trusted_keys`, returning the key that
+/// signed it
+pub fn verify_index<'a>(index: &Index, trusted_keys: &'a [PublicKey]) -> Result<&'a PublicKey> {
+	let encoded = index
+		.metadata
+		.get(SIGNATURE_KEY)
+		.ok_or_else(|| anyhow!("Index is not signed"))?;
+
+	let signature = decode_signature(encoded)?;
+
+	let key = trusted_keys
+		.iter()
+		.find(|key| key.key_data() == signature.public_key())
+		.ok_or_else(|| anyhow!("Index is not signed by a trusted key"))?;
+
+	key.verify(SIGNATURE_NAMESPACE, &signed_data(index), &signature)
+		.map_err(|err| anyhow!("Index signature is invalid: {err}"))?;
+
+	Ok(key)
+}
+
+/// Parse trusted keys in the `authorized_keys` format: one OpenSSH public key
+/// per line, with `#` comments
+pub fn parse_trusted_keys(data: &str) -> Result<Vec<PublicKey>> {
+	AuthorizedKeys::new(data)
+		.map(|entry| Ok(entry?.public_key().clone()))
+		.collect()
+}
+
+/// Read an unencrypted OpenSSH private key, such as one made with
+/// `ssh-keygen -t ed25519 -N ''`
+pub fn read_signing_key(path: &Path) -> Result<PrivateKey> {
+	PrivateKey::read_openssh_file(path)
+		.map_err(|err| anyhow!("Unable to read signing key {}: {err}", path.display()))
+}
+
+/// Fingerprint and comment of a key, for telling users who signed an index
+pub fn describe_key(key: &PublicKey) -> String {
+	let fingerprint = key.fingerprint(HashAlg::Sha256);
+
+	match key.comment() {
+		"" => fingerprint.to_string(),
+		comment => format!("{fingerprint} ({comment})"),
+	}
+}
+
+pub fn read_trusted_keys(path: &Path) -> Result<Vec<PublicKey>> {
+	let data = std::fs::read_to_string(path)
+		.with_context(|| format!("Unable to read trusted keys from {}", path.display()))?;
+
+	parse_trusted_keys(&data)
+}
+
+fn decode_signature(encoded: &str) -> Result<SshSig> {
+	let mut pem = format!("{PEM_BEGIN}\n");
+	for line in encoded.as_bytes().chunks(PEM_LINE_WIDTH) {
+		pem.push_str(std::str::from_utf8(line)?);
+		pem.push('\n');
+	}
+	pem.push_str(PEM_END);
+	pem.push('\n');
+
+	SshSig::from_pem(pem).map_err(|err| anyhow!("Index signature is malformed: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chrono::Utc;
+	use ssh_key::private::Ed25519Keypair;
+
+	use super::*;
+	use crate::{object_body::Object, Hash};
+
+	fn key(seed: u8) -> PrivateKey {
+		Ed25519Keypair::from_seed(&[seed; 32]).into()
+	}
+
+	fn index() -> Index {
+		Index {
+			tree: Hash::from([1u8; 32]),
+			timestamp: Utc::now(),
+			metadata: HashMap::from([
+				("version".to_string(), "1.2.3".to_string()),
+				("git".to_string(), "abc".to_string()),
+			]),
+		}
+	}
+
+	#[test]
+	fn signed_indexes_verify_after_a_round_trip() {
+		let key = key(1);
+		let mut index = index();
+		sign_index(&mut index, &key).unwrap();
+
+		let index = Index::from_data(&index.to_data());
+		let trusted = vec![key.public_key().clone()];
+
+		assert_eq!(verify_index(&index, &trusted).unwrap(), key.public_key());
+	}
+
+	#[test]
+	fn untrusted_or_tampered_signatures_are_rejected() {
+		let key = key(1);
+		let mut index = index();
+
+		let trusted = vec![key.public_key().clone()];
+		assert!(verify_index(&index, &trusted).is_err());
+
+		sign_index(&mut index, &key).unwrap();
+		assert!(verify_index(&index, &[self::key(2).public_key().clone()]).is_err());
+
+		index
+			.metadata
+			.insert("version".to_string(), "6.6.6".to_string());
+		assert!(verify_index(&index, &trusted).is_err());
+	}
+
+	#[test]
+	fn trusted_keys_skip_comments() {
+		let public = key(1).public_key().to_openssh().unwrap();
+		let keys = parse_trusted_keys(&format!("# release keys\n{public} release@ci\n")).unwrap();
+
+		assert_eq!(keys.len(), 1);
+		assert_eq!(keys[0].key_data(), key(1).public_key().key_data());
+	}
+}
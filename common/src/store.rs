@@ -1,9 +1,29 @@
-use crate::{Hash, Header};
-use anyhow::Result;
-use futures::io::copy;
-use futures::AsyncReadExt;
-use futures::{AsyncBufRead, AsyncRead, AsyncWriteExt};
-use opendal::{Builder, FuturesAsyncReader, Operator};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::delta::{self, DELTA_MAGIC, DELTA_PREFIX_LENGTH, MAX_DELTA_DEPTH};
+use crate::object_body::{Index, Tree, TreeEntry};
+use crate::pack::{
+	pack_index_path, pack_path, pack_roots_path, roots_from_data, roots_to_data, PackIndex,
+	PackIndexEntry, PackRoot, PackWriter, PACK_DIR,
+};
+use crate::{Hash, Header, Mode, ObjectType, ZSTD_MAGIC};
+use anyhow::{anyhow, Result};
+use async_compression::futures::{bufread::ZstdDecoder, write::ZstdEncoder};
+use async_compression::Level;
+use futures::future::BoxFuture;
+use futures::io::{copy, BufReader};
+use futures::{
+	AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite,
+	AsyncWriteExt, TryStreamExt,
+};
+use opendal::{Builder, ErrorKind, FuturesAsyncReader, Operator};
+
+/// Body of an object read from the store, decompressed if it was stored compressed
+pub type ObjectReader = Box<dyn AsyncBufRead + Send + Unpin>;
 
 pub struct StoreObject<T>
 where
@@ -91,47 +111,369 @@ where
 	}
 }
 
+/// A pack known to the store, along with the index used to locate objects in it.
+struct Pack {
+	name: Hash,
+	path: String,
+	index: PackIndex,
+}
+
+#[derive(Default)]
+struct PackSet {
+	/// When the pack list was last read from the backend, if it has been
+	loaded_at: Option<Instant>,
+	packs: Vec<Arc<Pack>>,
+}
+
+/// When a lookup reads the pack list from the backend again, once it has
+/// been read at all
+#[derive(Clone, Copy)]
+enum Reload {
+	Never,
+	/// If it's older than the store's reload interval
+	IfStale,
+	Always,
+}
+
+/// Where to find an object that has been consolidated into a pack.
+struct PackedLocation {
+	path: String,
+	object_type: ObjectType,
+	offset: u64,
+	length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepackSummary {
+	pub name: Hash,
+	pub objects: usize,
+	/// How many of the packed objects were stored as deltas
+	pub deltas: usize,
+	pub bytes: u64,
+}
+
+/// Blobs smaller than this aren't worth delta encoding
+const MIN_DELTA_SIZE: u64 = 1024;
+/// Blobs larger than this are never delta encoded, as both the blob and its
+/// base have to be held in memory to compute the delta
+const MAX_DELTA_SIZE: u64 = 256 * 1024 * 1024;
+/// Where uploads are written until they've been checked, so an object's own
+/// key only ever holds bytes that were verified to be that object
+const STAGING_DIR: &str = "staging/";
+
+/// How long a miss in [`Store::exists`] waits before listing pack/ again for
+/// packs written by other processes
+const PACK_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An object written to a temporary key by [`Store::stage_object`]. It isn't
+/// readable under its hash until [`Store::publish`] moves it there.
+pub struct StagedObject {
+	hash: Hash,
+	path: String,
+}
+
 #[derive(Clone)]
 pub struct Store {
 	operator: Operator,
+	packs: Arc<RwLock<PackSet>>,
+	compression: Option<i32>,
+	pack_reload_interval: Duration,
 }
 
 impl Store {
 	pub fn new(operator: Operator) -> Self {
-		Self { operator }
+		Self {
+			operator,
+			packs: Default::default(),
+			compression: None,
+			pack_reload_interval: PACK_RELOAD_INTERVAL,
+		}
+	}
+
+	/// Compress newly written objects with zstd at the given level. Objects
+	/// are always readable regardless of whether they were written compressed.
+	pub fn with_compression(mut self, level: Option<i32>) -> Self {
+		self.compression = level;
+		self
+	}
+
+	/// Look for packs written by other processes at most once per `interval`
+	/// when [`Store::exists`] doesn't find an object, rather than listing
+	/// pack/ on every miss. Reads always look again before failing.
+	pub fn with_pack_reload_interval(mut self, interval: Duration) -> Self {
+		self.pack_reload_interval = interval;
+		self
 	}
 
 	pub fn from_builder(builder: impl Builder) -> Result<Self> {
 		Ok(Self::new(Operator::new(builder)?.finish()))
 	}
 
+	/// The operator the store reads and writes through, for keeping other data
+	/// alongside the objects.
+	pub fn operator(&self) -> &Operator {
+		&self.operator
+	}
+
+	/// Whether `hash` is stored. Uploads probe for objects that mostly don't
+	/// exist yet, so a miss only looks for new packs once per reload interval.
 	pub async fn exists(&self, hash: &Hash) -> Result<bool> {
-		Ok(self.operator.exists(hash.as_str()).await?)
+		if self.find_packed(hash, Reload::Never).await?.is_some() {
+			return Ok(true);
+		}
+
+		if self.operator.exists(hash.as_str()).await? {
+			return Ok(true);
+		}
+
+		// Another process may have packed the object since we last looked
+		Ok(self.find_packed(hash, Reload::IfStale).await?.is_some())
 	}
 
-	pub async fn get_object(&self, hash: &Hash) -> Result<StoreObject<FuturesAsyncReader>> {
-		let mut reader = self
-			.operator
+	pub async fn get_object(&self, hash: &Hash) -> Result<StoreObject<ObjectReader>> {
+		let (_, reader) = self.open_stored(hash).await?;
+
+		let mut reader = self.decode(reader).await?;
+		let header = Header::read_from_async_buf(&mut reader).await?;
+
+		Ok(StoreObject::new_with_header(header, reader))
+	}
+
+	/// Open an object with its body starting `start` bytes in, for serving
+	/// ranges. Plain objects are read from that offset past their header
+	/// directly, while compressed and delta objects have to be decoded up to
+	/// it.
+	pub async fn get_object_from(
+		&self,
+		hash: &Hash,
+		start: u64,
+	) -> Result<StoreObject<ObjectReader>> {
+		let (_, mut reader) = self.open_stored(hash).await?;
+
+		let prefix = reader.fill_buf().await?;
+		if prefix.starts_with(&ZSTD_MAGIC) || prefix.starts_with(&DELTA_MAGIC) {
+			let mut reader = self.decode(reader).await?;
+			let header = Header::read_from_async_buf(&mut reader).await?;
+			copy(&mut (&mut reader).take(start), &mut futures::io::sink()).await?;
+
+			return Ok(StoreObject::new_with_header(header, reader));
+		}
+
+		let header = Header::read_from_async_buf(&mut reader).await?;
+		reader.seek(SeekFrom::Current(start as i64)).await?;
+
+		Ok(StoreObject::new_with_header(header, Box::new(reader)))
+	}
+
+	/// Header of an object, without decoding its body. A delta isn't applied:
+	/// its type comes from the pack index and its size from the length of the
+	/// object the delta produces.
+	pub async fn get_header(&self, hash: &Hash) -> Result<Header> {
+		let (object_type, mut reader) = self.open_stored(hash).await?;
+
+		if !reader.fill_buf().await?.starts_with(&DELTA_MAGIC) {
+			let mut reader = self.decode(reader).await?;
+			return Header::read_from_async_buf(&mut reader).await;
+		}
+
+		let object_type =
+			object_type.ok_or_else(|| anyhow!("Loose object {hash} is stored as a delta"))?;
+		let mut prefix = vec![0; DELTA_PREFIX_LENGTH + 16];
+		reader.read_exact(&mut prefix).await?;
+
+		delta_header(
+			object_type,
+			delta::target_length(&prefix[DELTA_PREFIX_LENGTH..])?,
+		)
+	}
+
+	/// Open the stored bytes of an object, wherever it's kept, along with its
+	/// type if it's packed. An object that isn't found where it was expected
+	/// may have just been packed by another process, so the pack list is read
+	/// again before giving up on it.
+	async fn open_stored(&self, hash: &Hash) -> Result<(Option<ObjectType>, FuturesAsyncReader)> {
+		if let Some(location) = self.find_packed(hash, Reload::Never).await? {
+			return Ok((
+				Some(location.object_type),
+				self.open_packed(location).await?,
+			));
+		}
+
+		match self.open_loose(hash).await {
+			Err(err) if err.kind() == ErrorKind::NotFound => {}
+			reader => return Ok((None, reader?)),
+		}
+
+		let location = self
+			.find_packed(hash, Reload::Always)
+			.await?
+			.ok_or_else(|| anyhow!("Object {hash} does not exist in the store"))?;
+
+		Ok((
+			Some(location.object_type),
+			self.open_packed(location).await?,
+		))
+	}
+
+	async fn open_loose(&self, hash: &Hash) -> opendal::Result<FuturesAsyncReader> {
+		self.operator
 			.reader(hash.as_str())
 			.await?
 			.into_futures_async_read(..)
-			.await?;
+			.await
+	}
 
-		let header = Header::read_from_async(&mut reader).await?;
+	async fn open_packed(&self, location: PackedLocation) -> Result<FuturesAsyncReader> {
+		let PackedLocation {
+			path,
+			offset,
+			length,
+			..
+		} = location;
 
-		Ok(StoreObject::new_with_header(header, reader))
+		Ok(self
+			.operator
+			.reader(&path)
+			.await?
+			.into_futures_async_read(offset..offset + length)
+			.await?)
 	}
 
-	pub async fn put_object<T>(&self, hash: &Hash, mut object: StoreObject<T>) -> Result<()>
+	/// If `hash` is stored as a delta in a pack, return its base along with the
+	/// stored delta entry. Used to pass deltas through to archives as-is.
+	pub async fn get_delta(&self, hash: &Hash) -> Result<Option<(Hash, Vec<u8>)>> {
+		let Some(location) = self.find_packed(hash, Reload::Never).await? else {
+			return Ok(None);
+		};
+
+		let prefix_length = (DELTA_PREFIX_LENGTH as u64).min(location.length);
+		let prefix = self
+			.operator
+			.read_with(&location.path)
+			.range(location.offset..location.offset + prefix_length)
+			.await?
+			.to_vec();
+
+		if !prefix.starts_with(&DELTA_MAGIC) {
+			return Ok(None);
+		}
+
+		let entry = self
+			.operator
+			.read_with(&location.path)
+			.range(location.offset..location.offset + location.length)
+			.await?
+			.to_vec();
+
+		Ok(delta::from_entry(&entry).map(|(base, _)| (base, entry.clone())))
+	}
+
+	/// Read an object in full, header included, exactly as it was hashed
+	fn read_full<'a>(&'a self, hash: &'a Hash) -> BoxFuture<'a, Result<Vec<u8>>> {
+		Box::pin(async move {
+			let mut object = self.get_object(hash).await?;
+
+			let mut data = object.header.to_string().into_bytes();
+			object.read_to_end(&mut data).await?;
+
+			Ok(data)
+		})
+	}
+
+	pub async fn put_object<T>(&self, hash: &Hash, object: StoreObject<T>) -> Result<()>
 	where
 		T: AsyncBufRead + AsyncRead + Unpin,
 	{
-		let mut writer = self
-			.operator
-			.writer(hash.as_str())
-			.await?
-			.into_futures_async_write();
+		self.write_to(hash.as_str(), object).await
+	}
+
+	/// Write an object to a key of its own, to be checked before it's
+	/// published under its hash or discarded
+	pub async fn stage_object<T>(&self, hash: &Hash, object: StoreObject<T>) -> Result<StagedObject>
+	where
+		T: AsyncBufRead + AsyncRead + Unpin,
+	{
+		static STAGED: AtomicU64 = AtomicU64::new(0);
+
+		let nanos = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_nanos();
+		let path = format!(
+			"{STAGING_DIR}{hash}.{nanos}.{}.{}",
+			std::process::id(),
+			STAGED.fetch_add(1, Ordering::Relaxed)
+		);
+
+		if let Err(err) = self.write_to(&path, object).await {
+			let _ = self.operator.delete(&path).await;
+			return Err(err);
+		}
+
+		Ok(StagedObject {
+			hash: hash.clone(),
+			path,
+		})
+	}
 
+	/// Move a staged object to its hash. If the store already has the object
+	/// the staged copy is dropped instead, so a stored object is never
+	/// replaced. Returns whether the staged copy was published.
+	pub async fn publish(&self, staged: StagedObject) -> Result<bool> {
+		if self.exists(&staged.hash).await? {
+			self.discard(staged).await?;
+			return Ok(false);
+		}
+
+		// Another upload of the object may still be published between the
+		// check and the move, but both copies have been verified
+		let capability = self.operator.info().full_capability();
+		if capability.rename {
+			self.operator
+				.rename(&staged.path, staged.hash.as_str())
+				.await?;
+			return Ok(true);
+		}
+
+		if capability.copy {
+			self.operator
+				.copy(&staged.path, staged.hash.as_str())
+				.await?;
+		} else {
+			let data = self.operator.read(&staged.path).await?;
+			self.operator.write(staged.hash.as_str(), data).await?;
+		}
+		self.operator.delete(&staged.path).await?;
+
+		Ok(true)
+	}
+
+	/// Remove a staged object that failed its checks
+	pub async fn discard(&self, staged: StagedObject) -> Result<()> {
+		self.operator.delete(&staged.path).await?;
+		Ok(())
+	}
+
+	async fn write_to<T>(&self, path: &str, mut object: StoreObject<T>) -> Result<()>
+	where
+		T: AsyncBufRead + AsyncRead + Unpin,
+	{
+		let writer = self.operator.writer(path).await?.into_futures_async_write();
+
+		match self.compression {
+			Some(level) => {
+				let encoder = ZstdEncoder::with_quality(writer, Level::Precise(level));
+				Self::write_object(encoder, &mut object).await
+			}
+			None => Self::write_object(writer, &mut object).await,
+		}
+	}
+
+	async fn write_object<W, T>(mut writer: W, object: &mut StoreObject<T>) -> Result<()>
+	where
+		W: AsyncWrite + Unpin,
+		T: AsyncBufRead + AsyncRead + Unpin,
+	{
 		object.header.write_to_async(&mut writer).await?;
 		copy(&mut object.body, &mut writer).await?;
 
@@ -139,4 +481,821 @@ impl Store {
 
 		Ok(())
 	}
+
+	/// Wrap a reader over stored bytes in a decompressor when they start with
+	/// the zstd frame marker, or rebuild the object when it was packed as a
+	/// delta. Older uncompressed objects are passed through.
+	async fn decode(&self, mut reader: FuturesAsyncReader) -> Result<ObjectReader> {
+		let prefix = reader.fill_buf().await?;
+
+		if prefix.starts_with(&ZSTD_MAGIC) {
+			return Ok(Box::new(BufReader::new(ZstdDecoder::new(reader))));
+		}
+
+		if !prefix.starts_with(&DELTA_MAGIC) {
+			return Ok(Box::new(reader));
+		}
+
+		let mut entry = Vec::new();
+		reader.read_to_end(&mut entry).await?;
+
+		let (base, delta) =
+			delta::from_entry(&entry).ok_or_else(|| anyhow!("Invalid delta entry in pack"))?;
+		let base = self.read_full(&base).await?;
+
+		Ok(Box::new(futures::io::Cursor::new(delta::apply(
+			&base, delta,
+		)?)))
+	}
+
+	/// Every object in the store. Packed objects come with their type from
+	/// the pack index, while loose objects would have to be opened to learn
+	/// it. Objects in more than one pack are listed once.
+	pub async fn list_objects(&self) -> Result<Vec<(Hash, Option<ObjectType>)>> {
+		self.reload_packs().await?;
+
+		let mut objects: HashMap<Hash, Option<ObjectType>> = self
+			.loose_objects()
+			.await?
+			.into_iter()
+			.map(|hash| (hash, None))
+			.collect();
+
+		let packs = self.packs.read().expect("pack lock to not be poisoned");
+		for pack in &packs.packs {
+			for entry in pack.index.entries() {
+				objects.insert(entry.hash.clone(), Some(entry.object_type));
+			}
+		}
+
+		Ok(objects.into_iter().collect())
+	}
+
+	/// Hashes of the objects stored on their own rather than in a pack
+	async fn loose_objects(&self) -> Result<Vec<Hash>> {
+		let mut loose = Vec::new();
+		let mut lister = self.operator.lister("").await?;
+		while let Some(entry) = lister.try_next().await? {
+			if !entry.metadata().is_file() {
+				continue;
+			}
+
+			if let Ok(hash) = Hash::try_from(entry.name()) {
+				loose.push(hash);
+			}
+		}
+
+		Ok(loose)
+	}
+
+	/// Consolidate every loose object in the store into a single new pack.
+	///
+	/// Blobs that are a new version of a file already seen at the same path in
+	/// an earlier index are stored as a delta against that version when doing
+	/// so saves at least half their size.
+	///
+	/// The pack is written in full before its index, and loose objects are only
+	/// removed once the index exists, so readers never observe an object as
+	/// missing while a repack is in progress. Returns `None` when there were no
+	/// loose objects to pack.
+	pub async fn repack(&self) -> Result<Option<RepackSummary>> {
+		let loose = self.loose_objects().await?;
+
+		if loose.is_empty() {
+			return Ok(None);
+		}
+
+		let mut headers = HashMap::new();
+		for hash in &loose {
+			headers.insert(hash.clone(), self.get_header(hash).await?);
+		}
+
+		let name = PackIndex::new(
+			loose
+				.iter()
+				.map(|hash| PackIndexEntry {
+					hash: hash.clone(),
+					object_type: headers[hash].object_type,
+					offset: 0,
+					length: 0,
+				})
+				.collect(),
+		)
+		.name();
+
+		let loose_indexes = loose
+			.iter()
+			.filter(|hash| headers[*hash].object_type == ObjectType::Index);
+		let roots = self.index_roots(loose_indexes).await?;
+
+		let candidate_order = self.delta_candidates(&headers, &roots).await?;
+		let candidates: HashMap<Hash, Hash> = candidate_order.iter().cloned().collect();
+
+		// Write delta candidates last, oldest first, so each one's base has
+		// already been settled by the time it is considered.
+		let mut order: Vec<&Hash> = loose
+			.iter()
+			.filter(|hash| !candidates.contains_key(*hash))
+			.collect();
+		order.extend(candidate_order.iter().map(|(target, _)| target));
+
+		let writer = self
+			.operator
+			.writer(&pack_path(&name))
+			.await?
+			.into_futures_async_write();
+		let mut pack = PackWriter::new(writer).await?;
+
+		let mut bytes = 0;
+		let mut bases: HashMap<Hash, Hash> = HashMap::new();
+		for hash in order {
+			let stored = self.operator.read(hash.as_str()).await?.to_vec();
+
+			let delta = match candidates.get(hash) {
+				Some(base) => self.try_delta(hash, base, &stored, &bases).await?,
+				None => None,
+			};
+
+			let data = match delta {
+				Some(entry) => {
+					bases.insert(hash.clone(), candidates[hash].clone());
+					entry
+				}
+				None => stored,
+			};
+
+			bytes += data.len() as u64;
+			pack.add(hash.clone(), headers[hash].object_type, &data)
+				.await?;
+		}
+
+		let (_, index) = pack.finish().await?;
+
+		self.operator
+			.write(&pack_roots_path(&name), roots_to_data(&roots))
+			.await?;
+		self.operator
+			.write(&pack_index_path(&name), index.to_data())
+			.await?;
+
+		self.reload_packs().await?;
+
+		for hash in &loose {
+			self.operator.delete(hash.as_str()).await?;
+		}
+
+		Ok(Some(RepackSummary {
+			name,
+			objects: index.len(),
+			deltas: bases.len(),
+			bytes,
+		}))
+	}
+
+	/// Pairs of (target, base) worth trying to delta encode, in the order they
+	/// should be tried. Each loose index is compared with the index made
+	/// before it, loose or packed, and a loose blob is paired with the blob
+	/// at the same path there. Only trees that are loose are walked, as a
+	/// packed tree's files were paired up when it was packed.
+	async fn delta_candidates(
+		&self,
+		loose: &HashMap<Hash, Header>,
+		loose_roots: &[PackRoot],
+	) -> Result<Vec<(Hash, Hash)>> {
+		if loose_roots.is_empty() {
+			return Ok(Vec::new());
+		}
+
+		let mut roots: Vec<(&PackRoot, bool)> = Vec::new();
+		let packed_roots = self.packed_roots().await?;
+		roots.extend(packed_roots.iter().map(|root| (root, false)));
+		roots.extend(loose_roots.iter().map(|root| (root, true)));
+		roots.sort_by_key(|(root, _)| root.timestamp);
+
+		let mut candidates = Vec::new();
+		let mut targets = HashSet::new();
+		let mut previous: Option<&Hash> = None;
+		for (root, is_loose) in roots {
+			if let (true, Some(previous)) = (is_loose, previous) {
+				self.changed_blobs(previous, &root.tree, loose, &mut targets, &mut candidates)
+					.await?;
+			}
+
+			previous = Some(&root.tree);
+		}
+
+		Ok(candidates)
+	}
+
+	/// Walk the loose trees under `new` alongside the trees at the same paths
+	/// under `old`, pairing each loose blob with the blob it replaced
+	async fn changed_blobs(
+		&self,
+		old: &Hash,
+		new: &Hash,
+		loose: &HashMap<Hash, Header>,
+		targets: &mut HashSet<Hash>,
+		candidates: &mut Vec<(Hash, Hash)>,
+	) -> Result<()> {
+		let mut stack = vec![(old.clone(), new.clone())];
+
+		while let Some((old, new)) = stack.pop() {
+			let is_loose_tree = loose
+				.get(&new)
+				.is_some_and(|header| header.object_type == ObjectType::Tree);
+			if old == new || !is_loose_tree {
+				continue;
+			}
+
+			let old_entries: HashMap<String, TreeEntry> = self
+				.read_tree(&old)
+				.await?
+				.contents
+				.into_iter()
+				.map(|entry| (entry.path.clone(), entry))
+				.collect();
+
+			for entry in self.read_tree(&new).await?.contents {
+				let Some(old_entry) = old_entries.get(&entry.path) else {
+					continue;
+				};
+
+				match (&entry.mode, &old_entry.mode) {
+					(Mode::Tree, Mode::Tree) => stack.push((old_entry.hash.clone(), entry.hash)),
+					(Mode::Tree, _) | (_, Mode::Tree) => {}
+					_ => {
+						let is_loose_blob = loose
+							.get(&entry.hash)
+							.is_some_and(|header| header.object_type == ObjectType::Blob);
+
+						if is_loose_blob
+							&& entry.hash != old_entry.hash
+							&& targets.insert(entry.hash.clone())
+						{
+							candidates.push((entry.hash, old_entry.hash.clone()));
+						}
+					}
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn read_tree(&self, hash: &Hash) -> Result<Tree> {
+		let mut object = self.get_object(hash).await?;
+		let mut data = Vec::new();
+		object.read_to_end(&mut data).await?;
+
+		Tree::parse(&data)
+	}
+
+	/// Roots of the indexes in `hashes`. A malformed index has no paths to
+	/// pair blobs up by, so it's left out.
+	async fn index_roots(&self, hashes: impl Iterator<Item = &Hash>) -> Result<Vec<PackRoot>> {
+		let mut roots = Vec::new();
+		for hash in hashes {
+			let mut object = self.get_object(hash).await?;
+			let mut data = Vec::new();
+			object.read_to_end(&mut data).await?;
+
+			if let Ok(index) = Index::parse(&data) {
+				roots.push(PackRoot {
+					timestamp: index.timestamp,
+					tree: index.tree,
+				});
+			}
+		}
+
+		Ok(roots)
+	}
+
+	/// Roots of every packed index, from the roots written alongside each
+	/// pack. Packs written before roots were are read once and given them.
+	async fn packed_roots(&self) -> Result<Vec<PackRoot>> {
+		let packs = self
+			.packs
+			.read()
+			.expect("pack lock to not be poisoned")
+			.packs
+			.clone();
+
+		let mut roots = Vec::new();
+		for pack in packs {
+			let path = pack_roots_path(&pack.name);
+			match self.operator.read(&path).await {
+				Ok(data) => roots.extend(roots_from_data(&mut data.to_vec().as_slice())?),
+				Err(err) if err.kind() == ErrorKind::NotFound => {
+					let indexes = pack
+						.index
+						.entries()
+						.iter()
+						.filter(|entry| entry.object_type == ObjectType::Index)
+						.map(|entry| &entry.hash);
+					let pack_roots = self.index_roots(indexes).await?;
+
+					self.operator
+						.write(&path, roots_to_data(&pack_roots))
+						.await?;
+					roots.extend(pack_roots);
+				}
+				Err(err) => return Err(err.into()),
+			}
+		}
+
+		Ok(roots)
+	}
+
+	/// Delta encode `target` against `base`, returning the entry to store if
+	/// it is worthwhile and keeps the chain of bases short and acyclic.
+	async fn try_delta(
+		&self,
+		target: &Hash,
+		base: &Hash,
+		stored: &[u8],
+		bases: &HashMap<Hash, Hash>,
+	) -> Result<Option<Vec<u8>>> {
+		if !self.exists(base).await? {
+			return Ok(None);
+		}
+
+		let mut depth = 1;
+		let mut current = base.clone();
+		loop {
+			if &current == target || depth > MAX_DELTA_DEPTH {
+				return Ok(None);
+			}
+
+			let next = match bases.get(&current) {
+				Some(next) => Some(next.clone()),
+				None => self.get_delta(&current).await?.map(|(next, _)| next),
+			};
+
+			let Some(next) = next else {
+				break;
+			};
+
+			current = next;
+			depth += 1;
+		}
+
+		let target_full = self.read_full(target).await?;
+		let target_size = target_full.len() as u64;
+		if !(MIN_DELTA_SIZE..=MAX_DELTA_SIZE).contains(&target_size) {
+			return Ok(None);
+		}
+
+		let base_size = self.get_header(base).await?.size;
+		if base_size > target_size * 2 || target_size > base_size * 2 {
+			return Ok(None);
+		}
+
+		let base_full = self.read_full(base).await?;
+		let entry = delta::to_entry(base, &delta::encode(&base_full, &target_full));
+
+		if entry.len() < stored.len() / 2 {
+			Ok(Some(entry))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Find `hash` in the loaded packs, reading the pack list first if it
+	/// never has been, or as `reload` asks.
+	async fn find_packed(&self, hash: &Hash, reload: Reload) -> Result<Option<PackedLocation>> {
+		let loaded_at = self
+			.packs
+			.read()
+			.expect("pack lock to not be poisoned")
+			.loaded_at;
+		let stale = match (loaded_at, reload) {
+			(None, _) | (_, Reload::Always) => true,
+			(Some(loaded_at), Reload::IfStale) => loaded_at.elapsed() >= self.pack_reload_interval,
+			(Some(_), Reload::Never) => false,
+		};
+
+		if stale {
+			self.reload_packs().await?;
+		}
+
+		let packs = self.packs.read().expect("pack lock to not be poisoned");
+
+		Ok(packs.packs.iter().find_map(|pack| {
+			pack.index.find(hash).map(|entry| PackedLocation {
+				path: pack.path.clone(),
+				object_type: entry.object_type,
+				offset: entry.offset,
+				length: entry.length,
+			})
+		}))
+	}
+
+	/// Synchronise the in-memory pack list with the packs present in the
+	/// backend, only reading indexes for packs we haven't seen before.
+	async fn reload_packs(&self) -> Result<()> {
+		let mut names = Vec::new();
+		let mut lister = self.operator.lister(PACK_DIR).await?;
+		while let Some(entry) = lister.try_next().await? {
+			let Some(name) = entry.name().strip_suffix(".idx") else {
+				continue;
+			};
+
+			if let Ok(name) = Hash::try_from(name) {
+				names.push(name);
+			}
+		}
+
+		let known: Vec<String> = {
+			let packs = self.packs.read().expect("pack lock to not be poisoned");
+			packs.packs.iter().map(|pack| pack.path.clone()).collect()
+		};
+
+		let mut added = Vec::new();
+		for name in &names {
+			let path = pack_path(name);
+			if known.contains(&path) {
+				continue;
+			}
+
+			let data = self.operator.read(&pack_index_path(name)).await?.to_vec();
+			added.push(Arc::new(Pack {
+				name: name.clone(),
+				path,
+				index: PackIndex::from_data(&mut data.as_slice())?,
+			}));
+		}
+
+		let mut packs = self.packs.write().expect("pack lock to not be poisoned");
+		packs
+			.packs
+			.retain(|pack| names.iter().any(|name| pack_path(name) == pack.path));
+		packs.packs.extend(added);
+		packs.loaded_at = Some(Instant::now());
+
+		Ok(())
+	}
+}
+
+/// Header of an object whose header and body together are `length` bytes.
+/// Only one size fits, as a longer body never has a shorter size in its
+/// header.
+fn delta_header(object_type: ObjectType, length: u64) -> Result<Header> {
+	(1..=20)
+		.filter_map(|digits| length.checked_sub(object_type.to_str().len() as u64 + 2 + digits))
+		.map(|size| Header::new(object_type, size))
+		.find(|header| header.to_string().len() as u64 + header.size == length)
+		.ok_or_else(|| anyhow!("No {object_type:?} header fits an object of {length} bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{object_body::Object, ObjectType};
+
+	fn memory_store() -> Store {
+		Store::from_builder(opendal::services::Memory::default()).unwrap()
+	}
+
+	fn compressed_store() -> Store {
+		memory_store().with_compression(Some(3))
+	}
+
+	async fn put_blob(store: &Store, byte: u8, body: &'static [u8]) -> Hash {
+		let hash = Hash::from([byte; 32]);
+		let header = Header::new(ObjectType::Blob, body.len() as u64);
+		store
+			.put_object(
+				&hash,
+				StoreObject::new_with_header(header, futures::io::BufReader::new(body)),
+			)
+			.await
+			.unwrap();
+		hash
+	}
+
+	async fn read_body(store: &Store, hash: &Hash) -> (Header, Vec<u8>) {
+		let mut object = store.get_object(hash).await.unwrap();
+		let mut data = Vec::new();
+		object.read_to_end(&mut data).await.unwrap();
+		(object.header, data)
+	}
+
+	#[tokio::test]
+	async fn loose_objects_read_back_without_their_header() {
+		let store = memory_store();
+		let hash = put_blob(&store, 1, b"hello").await;
+
+		let (header, body) = read_body(&store, &hash).await;
+
+		assert_eq!(header, Header::new(ObjectType::Blob, 5));
+		assert_eq!(body, b"hello");
+	}
+
+	#[tokio::test]
+	async fn staged_objects_never_replace_stored_ones() {
+		let store = memory_store();
+		let hash = Hash::from([1u8; 32]);
+		let stage = |body: &'static [u8]| {
+			let header = Header::new(ObjectType::Blob, body.len() as u64);
+			store.stage_object(
+				&hash,
+				StoreObject::new_with_header(header, futures::io::BufReader::new(body)),
+			)
+		};
+
+		let rejected = stage(b"wrong").await.unwrap();
+		let accepted = stage(b"hello").await.unwrap();
+		let late = stage(b"later").await.unwrap();
+		assert!(!store.exists(&hash).await.unwrap());
+
+		store.discard(rejected).await.unwrap();
+		assert!(store.publish(accepted).await.unwrap());
+		assert!(!store.publish(late).await.unwrap());
+
+		assert_eq!(read_body(&store, &hash).await.1, b"hello");
+		assert!(store
+			.operator
+			.list(STAGING_DIR)
+			.await
+			.unwrap()
+			.iter()
+			.all(|entry| entry.path() == STAGING_DIR));
+	}
+
+	#[tokio::test]
+	async fn repacked_objects_remain_readable() {
+		let store = memory_store();
+		let first = put_blob(&store, 1, b"hello").await;
+		let second = put_blob(&store, 2, b"world!").await;
+
+		let summary = store.repack().await.unwrap().expect("objects to be packed");
+		assert_eq!(summary.objects, 2);
+
+		assert!(!store.operator.exists(first.as_str()).await.unwrap());
+		assert!(store.exists(&first).await.unwrap());
+		assert!(store.exists(&second).await.unwrap());
+		assert!(!store.exists(&Hash::from([3u8; 32])).await.unwrap());
+
+		assert_eq!(read_body(&store, &first).await.1, b"hello");
+		assert_eq!(read_body(&store, &second).await.1, b"world!");
+	}
+
+	#[tokio::test]
+	async fn packs_written_by_another_store_are_discovered() {
+		let store = memory_store();
+		let other = Store::new(store.operator.clone());
+		let hash = put_blob(&store, 1, b"hello").await;
+
+		// Load the (empty) pack list before the other instance repacks
+		assert!(store.exists(&Hash::from([9u8; 32])).await.is_ok_and(|x| !x));
+		other.repack().await.unwrap();
+
+		// The pack list was only just read, but a read looks again rather than
+		// fail on an object that was there a moment ago
+		assert_eq!(read_body(&store, &hash).await.1, b"hello");
+		assert_eq!(
+			store.get_header(&hash).await.unwrap(),
+			Header::new(ObjectType::Blob, 5)
+		);
+
+		let eager = Store::new(store.operator.clone()).with_pack_reload_interval(Duration::ZERO);
+		let hash = put_blob(&eager, 2, b"world").await;
+		assert!(eager.exists(&Hash::from([9u8; 32])).await.is_ok_and(|x| !x));
+		other.repack().await.unwrap();
+
+		assert!(eager.exists(&hash).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn compressed_objects_are_smaller_and_read_back_transparently() {
+		let store = compressed_store();
+		let body: &'static [u8] = &[b'a'; 4096];
+		let hash = put_blob(&store, 1, body).await;
+
+		let stored = store.operator.read(hash.as_str()).await.unwrap().to_vec();
+		assert!(stored.starts_with(&ZSTD_MAGIC));
+		assert!(stored.len() < body.len());
+
+		let (header, data) = read_body(&store, &hash).await;
+		assert_eq!(header, Header::new(ObjectType::Blob, 4096));
+		assert_eq!(data, body);
+	}
+
+	#[tokio::test]
+	async fn uncompressed_objects_remain_readable_after_enabling_compression() {
+		let store = memory_store();
+		let hash = put_blob(&store, 1, b"hello").await;
+
+		let store = Store::new(store.operator.clone()).with_compression(Some(3));
+
+		assert_eq!(read_body(&store, &hash).await.1, b"hello");
+	}
+
+	#[tokio::test]
+	async fn compressed_objects_survive_a_repack() {
+		let store = compressed_store();
+		let first = put_blob(&store, 1, b"hello").await;
+		let second = put_blob(&store, 2, b"world!").await;
+
+		store.repack().await.unwrap();
+
+		assert_eq!(read_body(&store, &first).await.1, b"hello");
+		assert_eq!(read_body(&store, &second).await.1, b"world!");
+	}
+
+	#[tokio::test]
+	async fn objects_can_be_read_from_an_offset_into_their_body() {
+		async fn read_from(store: &Store, hash: &Hash, start: u64) -> Vec<u8> {
+			let mut object = store.get_object_from(hash, start).await.unwrap();
+			assert_eq!(object.header, Header::new(ObjectType::Blob, 11));
+
+			let mut data = Vec::new();
+			object.read_to_end(&mut data).await.unwrap();
+			data
+		}
+
+		for store in [memory_store(), compressed_store()] {
+			let loose = put_blob(&store, 1, b"hello world").await;
+			assert_eq!(read_from(&store, &loose, 6).await, b"world");
+			assert_eq!(read_from(&store, &loose, 11).await, b"");
+
+			let packed = put_blob(&store, 2, b"hello there").await;
+			store.repack().await.unwrap();
+			assert_eq!(read_from(&store, &packed, 0).await, b"hello there");
+			assert_eq!(read_from(&store, &packed, 6).await, b"there");
+			assert_eq!(read_from(&store, &loose, 6).await, b"world");
+			assert_eq!(
+				store.get_header(&loose).await.unwrap(),
+				Header::new(ObjectType::Blob, 11)
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn objects_are_listed_whether_loose_or_packed() {
+		let store = memory_store();
+		let packed = put_blob(&store, 1, b"hello").await;
+		store.repack().await.unwrap();
+		let loose = put_blob(&store, 2, b"world").await;
+
+		let mut objects = store.list_objects().await.unwrap();
+		objects.sort_by_key(|(hash, _)| hash.hash);
+
+		assert_eq!(
+			objects,
+			vec![(packed, Some(ObjectType::Blob)), (loose, None)]
+		);
+	}
+
+	#[tokio::test]
+	async fn repack_without_loose_objects_is_a_no_op() {
+		let store = memory_store();
+		assert!(store.repack().await.unwrap().is_none());
+	}
+
+	async fn put_data(store: &Store, byte: u8, object_type: ObjectType, body: Vec<u8>) -> Hash {
+		let hash = Hash::from([byte; 32]);
+		let header = Header::new(object_type, body.len() as u64);
+		store
+			.put_object(
+				&hash,
+				StoreObject::new_with_header(
+					header,
+					futures::io::BufReader::new(futures::io::Cursor::new(body)),
+				),
+			)
+			.await
+			.unwrap();
+		hash
+	}
+
+	/// Store a blob under `file` in a tree referenced by a new index, as a
+	/// commit would. Hashes are derived from `byte` rather than the content.
+	async fn put_version(store: &Store, byte: u8, seconds: i64, body: Vec<u8>) -> Hash {
+		let blob = put_data(store, byte, ObjectType::Blob, body).await;
+		let tree = Tree {
+			contents: vec![crate::object_body::TreeEntry {
+				mode: Mode::Normal,
+				path: "file".to_string(),
+				hash: blob.clone(),
+			}],
+		};
+		let tree = put_data(store, byte + 1, ObjectType::Tree, tree.to_data()).await;
+		let index = Index {
+			tree,
+			timestamp: chrono::DateTime::from_timestamp(seconds, 0).unwrap(),
+			metadata: HashMap::new(),
+		};
+		put_data(store, byte + 2, ObjectType::Index, index.to_data()).await;
+		blob
+	}
+
+	fn pseudo_random(length: usize) -> Vec<u8> {
+		let mut state: u64 = 1;
+		(0..length)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				(state >> 56) as u8
+			})
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn new_versions_of_a_file_are_packed_as_deltas() {
+		let store = memory_store();
+		let original = pseudo_random(8192);
+		let mut edited = original.clone();
+		edited[100..110].copy_from_slice(b"0123456789");
+
+		let first = put_version(&store, 10, 1000, original.clone()).await;
+		let second = put_version(&store, 20, 2000, edited.clone()).await;
+
+		let summary = store.repack().await.unwrap().expect("objects to be packed");
+		assert_eq!(summary.objects, 6);
+		assert_eq!(summary.deltas, 1);
+
+		let (base, entry) = store.get_delta(&second).await.unwrap().expect("a delta");
+		assert_eq!(base, first);
+		assert!(entry.len() < 512);
+		assert!(store.get_delta(&first).await.unwrap().is_none());
+
+		assert_eq!(read_body(&store, &first).await.1, original);
+		let (header, body) = read_body(&store, &second).await;
+		assert_eq!(header, Header::new(ObjectType::Blob, 8192));
+		assert_eq!(body, edited);
+		assert_eq!(store.get_header(&second).await.unwrap(), header);
+	}
+
+	#[test]
+	fn delta_headers_are_recovered_from_their_length() {
+		for size in [0, 9, 10, 99, 100, 8192, u32::MAX as u64] {
+			let header = Header::new(ObjectType::ChunkList, size);
+			let length = header.to_string().len() as u64 + size;
+			assert_eq!(delta_header(ObjectType::ChunkList, length).unwrap(), header);
+		}
+
+		// "blob 0\0" is the shortest blob
+		assert!(delta_header(ObjectType::Blob, 6).is_err());
+	}
+
+	#[tokio::test]
+	async fn deltas_can_use_bases_from_earlier_packs() {
+		let store = compressed_store();
+		let original = pseudo_random(8192);
+		let mut edited = original.clone();
+		edited.extend_from_slice(b"appended");
+
+		let first = put_version(&store, 10, 1000, original).await;
+		store.repack().await.unwrap();
+
+		let second = put_version(&store, 20, 2000, edited.clone()).await;
+		let summary = store.repack().await.unwrap().expect("objects to be packed");
+		assert_eq!(summary.deltas, 1);
+
+		assert_eq!(store.get_delta(&second).await.unwrap().unwrap().0, first);
+		assert_eq!(read_body(&store, &second).await.1, edited);
+	}
+
+	#[tokio::test]
+	async fn packs_without_roots_are_given_them() {
+		let store = memory_store();
+		let original = pseudo_random(8192);
+		let mut edited = original.clone();
+		edited.extend_from_slice(b"appended");
+
+		let first = put_version(&store, 10, 1000, original).await;
+		let name = store.repack().await.unwrap().unwrap().name;
+		store
+			.operator
+			.delete(&pack_roots_path(&name))
+			.await
+			.unwrap();
+
+		let second = put_version(&store, 20, 2000, edited).await;
+		assert_eq!(store.repack().await.unwrap().unwrap().deltas, 1);
+
+		assert_eq!(store.get_delta(&second).await.unwrap().unwrap().0, first);
+		assert!(store
+			.operator
+			.exists(&pack_roots_path(&name))
+			.await
+			.unwrap());
+	}
+
+	#[tokio::test]
+	async fn small_or_dissimilar_blobs_are_packed_whole() {
+		let store = memory_store();
+		put_version(&store, 10, 1000, b"small".to_vec()).await;
+		let small = put_version(&store, 20, 2000, b"smaller".to_vec()).await;
+		put_version(&store, 30, 3000, pseudo_random(4096)).await;
+		let unrelated = put_version(&store, 40, 4000, vec![7u8; 4096]).await;
+
+		let summary = store.repack().await.unwrap().unwrap();
+
+		assert_eq!(summary.deltas, 0);
+		assert!(store.get_delta(&small).await.unwrap().is_none());
+		assert_eq!(read_body(&store, &unrelated).await.1, vec![7u8; 4096]);
+	}
 }
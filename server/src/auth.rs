@@ -0,0 +1,564 @@
+use std::{
+	collections::HashMap,
+	fmt::{self, Display},
+	str::FromStr,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+	extract::{Request, State},
+	http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::AuthConfig;
+use crate::namespace::{is_valid_name, namespace_from_path};
+
+/// Version prefix of HMAC signed tokens
+const SIGNED_TOKEN_PREFIX: &str = "arx1";
+
+/// Most of a rejected request's body that is read before responding
+pub const REJECTED_BODY_LIMIT: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+	Read,
+	Write,
+	/// Server wide administration, such as reporting usage of every namespace
+	Admin,
+}
+
+impl Scope {
+	/// Scope needed to make a request with `method` to `path`
+	pub fn for_request(method: &Method, path: &str) -> Self {
+		if path.starts_with("/admin/") {
+			return Scope::Admin;
+		}
+
+		match *method {
+			Method::GET | Method::HEAD | Method::OPTIONS => Scope::Read,
+			_ => Scope::Write,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Scope::Read => "read",
+			Scope::Write => "write",
+			Scope::Admin => "admin",
+		}
+	}
+
+	#[allow(clippy::should_implement_trait)]
+	pub fn from_str(value: &str) -> Option<Self> {
+		match value {
+			"read" => Some(Scope::Read),
+			"write" => Some(Scope::Write),
+			"admin" => Some(Scope::Admin),
+			_ => None,
+		}
+	}
+}
+
+/// A scope granted to a token, either in every namespace or in a single one.
+/// Written as `read` or `read:<namespace>`. `admin` is server wide, so it
+/// can't be limited to a namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Grant {
+	pub scope: Scope,
+	pub namespace: Option<String>,
+}
+
+impl Grant {
+	pub fn allows(&self, scope: Scope, namespace: &str) -> bool {
+		match (scope, self.namespace.as_deref()) {
+			(Scope::Admin, Some(_)) => false,
+			(_, granted) => self.scope == scope && granted.is_none_or(|x| x == namespace),
+		}
+	}
+}
+
+impl FromStr for Grant {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let (scope, namespace) = match value.split_once(':') {
+			Some((scope, namespace)) => (scope, Some(namespace)),
+			None => (value, None),
+		};
+
+		let scope = Scope::from_str(scope)
+			.ok_or_else(|| format!("invalid scope \"{scope}\", expected read, write or admin"))?;
+
+		if let Some(namespace) = namespace {
+			if scope == Scope::Admin {
+				return Err(format!(
+					"admin is server wide and can't be limited to namespace \"{namespace}\""
+				));
+			}
+			if !is_valid_name(namespace) {
+				return Err(format!("invalid namespace \"{namespace}\""));
+			}
+		}
+
+		Ok(Grant {
+			scope,
+			namespace: namespace.map(str::to_string),
+		})
+	}
+}
+
+impl Display for Grant {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.namespace {
+			Some(namespace) => write!(f, "{}:{namespace}", self.scope.as_str()),
+			None => write!(f, "{}", self.scope.as_str()),
+		}
+	}
+}
+
+impl TryFrom<String> for Grant {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+impl From<Grant> for String {
+	fn from(grant: Grant) -> Self {
+		grant.to_string()
+	}
+}
+
+/// The authenticated caller of a request, added to the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+	pub subject: String,
+	pub grants: Vec<Grant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+	Missing,
+	Invalid,
+	Forbidden(Scope, String),
+}
+
+impl IntoResponse for AuthError {
+	fn into_response(self) -> Response {
+		let (status, message) = match self {
+			AuthError::Missing => (StatusCode::UNAUTHORIZED, "Missing credentials".to_string()),
+			AuthError::Invalid => (
+				StatusCode::UNAUTHORIZED,
+				"Invalid or expired credentials".to_string(),
+			),
+			AuthError::Forbidden(scope, namespace) => (
+				StatusCode::FORBIDDEN,
+				format!(
+					"Credentials lack the {} scope in namespace {namespace}",
+					scope.as_str()
+				),
+			),
+		};
+
+		let mut response = (status, message).into_response();
+		if status == StatusCode::UNAUTHORIZED {
+			let headers = response.headers_mut();
+			headers.append(
+				header::WWW_AUTHENTICATE,
+				HeaderValue::from_static("Bearer realm=\"arx\""),
+			);
+			// Lets browsers prompt for a token when using the web UI
+			headers.append(
+				header::WWW_AUTHENTICATE,
+				HeaderValue::from_static("Basic realm=\"arx\""),
+			);
+		}
+		response
+	}
+}
+
+/// Verifies the bearer tokens presented by clients.
+///
+/// Tokens are either static, listed in the config along with their scopes, or
+/// signed with the configured HMAC secret (see [`issue_token`]) so they can be
+/// handed out without editing the config. Authentication is only enforced once
+/// at least one of the two is configured.
+pub struct Auth {
+	anonymous_read: bool,
+	/// Static tokens keyed by their sha256 digest, so looking one up doesn't
+	/// leak how much of a guessed token matched through timing
+	tokens: HashMap<[u8; 32], Identity>,
+	hmac_secret: Option<Vec<u8>>,
+}
+
+impl Auth {
+	pub fn new(config: &AuthConfig) -> Self {
+		Self {
+			anonymous_read: config.anonymous_read,
+			tokens: config
+				.tokens
+				.iter()
+				.map(|token| {
+					(
+						digest(&token.token),
+						Identity {
+							subject: token.name.clone(),
+							grants: token.scopes.clone(),
+						},
+					)
+				})
+				.collect(),
+			hmac_secret: config.hmac_secret.as_ref().map(|x| x.as_bytes().to_vec()),
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		!self.tokens.is_empty() || self.hmac_secret.is_some()
+	}
+
+	/// Check the request's credentials grant `scope` in `namespace`. Returns
+	/// the caller's identity, or `None` for anonymous requests that are
+	/// allowed through.
+	pub fn authorize(
+		&self,
+		headers: &HeaderMap,
+		scope: Scope,
+		namespace: &str,
+	) -> Result<Option<Identity>, AuthError> {
+		if !self.is_enabled() {
+			return Ok(None);
+		}
+
+		let Some(token) = request_token(headers) else {
+			if scope == Scope::Read && self.anonymous_read {
+				return Ok(None);
+			}
+			return Err(AuthError::Missing);
+		};
+
+		let identity = self.identify(&token).ok_or(AuthError::Invalid)?;
+
+		if !identity
+			.grants
+			.iter()
+			.any(|grant| grant.allows(scope, namespace))
+		{
+			return Err(AuthError::Forbidden(scope, namespace.to_string()));
+		}
+
+		Ok(Some(identity))
+	}
+
+	fn identify(&self, token: &str) -> Option<Identity> {
+		if let Some(identity) = self.tokens.get(&digest(token)) {
+			return Some(identity.clone());
+		}
+
+		verify_token(self.hmac_secret.as_deref()?, token, SystemTime::now())
+	}
+}
+
+pub async fn require_auth(
+	State(auth): State<Arc<Auth>>,
+	mut request: Request,
+	next: Next,
+) -> Result<Response, AuthError> {
+	let scope = Scope::for_request(request.method(), request.uri().path());
+	let namespace = namespace_from_path(request.uri().path()).to_string();
+
+	match auth.authorize(request.headers(), scope, &namespace) {
+		Ok(Some(identity)) => {
+			request.extensions_mut().insert(identity);
+		}
+		Ok(None) => {}
+		Err(err) => {
+			// Clients send the whole upload before reading the response, so
+			// read small bodies to let them see the rejection rather than a
+			// broken pipe.
+			let _ = axum::body::to_bytes(request.into_body(), REJECTED_BODY_LIMIT).await;
+			return Err(err);
+		}
+	}
+
+	Ok(next.run(request).await)
+}
+
+/// Create a token for `subject` granting `scopes` until `ttl` from now, signed
+/// with `secret`. Tokens have the form
+/// `arx1.<subject>.<grant>+<grant>.<expiry>.<hex hmac-sha256>`.
+pub fn issue_token(
+	secret: &[u8],
+	subject: &str,
+	scopes: &[Grant],
+	ttl: Duration,
+) -> anyhow::Result<String> {
+	if subject.is_empty() || subject.contains('.') {
+		anyhow::bail!("token subject must be non-empty and cannot contain '.'");
+	}
+
+	if scopes.is_empty() {
+		anyhow::bail!("token must grant at least one scope");
+	}
+
+	let expiry = (SystemTime::now() + ttl)
+		.duration_since(UNIX_EPOCH)?
+		.as_secs();
+	let scopes = scopes
+		.iter()
+		.map(Grant::to_string)
+		.collect::<Vec<_>>()
+		.join("+");
+
+	let payload = format!("{SIGNED_TOKEN_PREFIX}.{subject}.{scopes}.{expiry}");
+	let signature = hex::encode(sign(secret, &payload).finalize().into_bytes());
+
+	Ok(format!("{payload}.{signature}"))
+}
+
+fn verify_token(secret: &[u8], token: &str, now: SystemTime) -> Option<Identity> {
+	let (payload, signature) = token.rsplit_once('.')?;
+
+	sign(secret, payload)
+		.verify_slice(&hex::decode(signature).ok()?)
+		.ok()?;
+
+	let mut parts = payload.split('.');
+	let (Some(SIGNED_TOKEN_PREFIX), Some(subject), Some(scopes), Some(expiry), None) = (
+		parts.next(),
+		parts.next(),
+		parts.next(),
+		parts.next(),
+		parts.next(),
+	) else {
+		return None;
+	};
+
+	let expiry = UNIX_EPOCH + Duration::from_secs(expiry.parse().ok()?);
+	if now >= expiry {
+		return None;
+	}
+
+	Some(Identity {
+		subject: subject.to_string(),
+		grants: scopes
+			.split('+')
+			.map(|grant| grant.parse().ok())
+			.collect::<Option<_>>()?,
+	})
+}
+
+fn sign(secret: &[u8], payload: &str) -> Hmac<Sha256> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC to accept keys of any size");
+	mac.update(payload.as_bytes());
+	mac
+}
+
+/// The token a request presents, either as a bearer token or as the password
+/// of basic auth, which is all browsers can send. The basic auth user name is
+/// ignored.
+fn request_token(headers: &HeaderMap) -> Option<String> {
+	let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+
+	if let Some(token) = value.strip_prefix("Bearer ") {
+		return Some(token.trim().to_string());
+	}
+
+	let credentials = BASE64_STANDARD
+		.decode(value.strip_prefix("Basic ")?.trim())
+		.ok()?;
+	let (_, token) = std::str::from_utf8(&credentials).ok()?.split_once(':')?;
+
+	Some(token.to_string())
+}
+
+fn digest(token: &str) -> [u8; 32] {
+	Sha256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::TokenConfig;
+
+	fn config(anonymous_read: bool) -> AuthConfig {
+		AuthConfig {
+			anonymous_read,
+			tokens: vec![TokenConfig {
+				name: "ci".into(),
+				token: "static-token".into(),
+				scopes: vec!["read".parse().unwrap(), "write:team".parse().unwrap()],
+			}],
+			hmac_secret: Some("secret".into()),
+		}
+	}
+
+	fn headers(token: &str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			header::AUTHORIZATION,
+			HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+		);
+		headers
+	}
+
+	#[test]
+	fn no_credentials_configured_allows_everything() {
+		let auth = Auth::new(&AuthConfig::default());
+
+		assert_eq!(
+			auth.authorize(&HeaderMap::new(), Scope::Write, "team"),
+			Ok(None)
+		);
+	}
+
+	#[test]
+	fn anonymous_reads_are_configurable() {
+		let open = Auth::new(&config(true));
+		let closed = Auth::new(&config(false));
+
+		assert_eq!(
+			open.authorize(&HeaderMap::new(), Scope::Read, "team"),
+			Ok(None)
+		);
+		assert_eq!(
+			open.authorize(&HeaderMap::new(), Scope::Write, "team"),
+			Err(AuthError::Missing)
+		);
+		assert_eq!(
+			closed.authorize(&HeaderMap::new(), Scope::Read, "team"),
+			Err(AuthError::Missing)
+		);
+	}
+
+	#[test]
+	fn static_tokens_grant_their_scopes() {
+		let auth = Auth::new(&config(false));
+
+		let identity = auth
+			.authorize(&headers("static-token"), Scope::Write, "team")
+			.unwrap()
+			.unwrap();
+		assert_eq!(identity.subject, "ci");
+
+		assert_eq!(
+			auth.authorize(&headers("wrong-token"), Scope::Read, "team"),
+			Err(AuthError::Invalid)
+		);
+	}
+
+	#[test]
+	fn browsers_can_send_tokens_as_basic_auth_passwords() {
+		let auth = Auth::new(&config(false));
+
+		let mut headers = HeaderMap::new();
+		let credentials = BASE64_STANDARD.encode("anyone:static-token");
+		headers.insert(
+			header::AUTHORIZATION,
+			HeaderValue::from_str(&format!("Basic {credentials}")).unwrap(),
+		);
+
+		let identity = auth
+			.authorize(&headers, Scope::Read, "default")
+			.unwrap()
+			.unwrap();
+		assert_eq!(identity.subject, "ci");
+	}
+
+	#[test]
+	fn signed_tokens_are_limited_to_their_scopes() {
+		let auth = Auth::new(&config(false));
+		let token = issue_token(
+			b"secret",
+			"reader",
+			&["read".parse().unwrap()],
+			Duration::from_secs(60),
+		)
+		.unwrap();
+
+		assert!(auth
+			.authorize(&headers(&token), Scope::Read, "team")
+			.is_ok());
+		assert_eq!(
+			auth.authorize(&headers(&token), Scope::Write, "team"),
+			Err(AuthError::Forbidden(Scope::Write, "team".into()))
+		);
+	}
+
+	#[test]
+	fn tampered_or_expired_signed_tokens_are_rejected() {
+		let token = issue_token(
+			b"secret",
+			"reader",
+			&["read".parse().unwrap()],
+			Duration::from_secs(60),
+		)
+		.unwrap();
+
+		let escalated = token.replacen(".read.", ".read+write.", 1);
+		assert!(verify_token(b"secret", &escalated, SystemTime::now()).is_none());
+		assert!(verify_token(b"other", &token, SystemTime::now()).is_none());
+
+		let later = SystemTime::now() + Duration::from_secs(120);
+		assert!(verify_token(b"secret", &token, later).is_none());
+		assert!(verify_token(b"secret", &token, SystemTime::now()).is_some());
+	}
+
+	#[test]
+	fn grants_can_be_limited_to_a_namespace() {
+		let auth = Auth::new(&config(false));
+
+		assert!(auth
+			.authorize(&headers("static-token"), Scope::Read, "other")
+			.is_ok());
+		assert_eq!(
+			auth.authorize(&headers("static-token"), Scope::Write, "other"),
+			Err(AuthError::Forbidden(Scope::Write, "other".into()))
+		);
+
+		let grant: Grant = "write:team".parse().unwrap();
+		assert_eq!(grant.to_string(), "write:team");
+		assert!("delete".parse::<Grant>().is_err());
+		assert!("read:Not Valid".parse::<Grant>().is_err());
+	}
+
+	#[test]
+	fn admin_grants_cannot_be_limited_to_a_namespace() {
+		assert!("admin:team".parse::<Grant>().is_err());
+		assert!("admin"
+			.parse::<Grant>()
+			.unwrap()
+			.allows(Scope::Admin, "team"));
+
+		let grant = Grant {
+			scope: Scope::Admin,
+			namespace: Some("team".into()),
+		};
+		assert!(!grant.allows(Scope::Admin, "team"));
+		assert!(!grant.allows(Scope::Admin, "default"));
+	}
+
+	#[test]
+	fn admin_routes_need_the_admin_scope() {
+		assert_eq!(
+			Scope::for_request(&Method::GET, "/admin/usage"),
+			Scope::Admin
+		);
+		assert_eq!(
+			Scope::for_request(&Method::GET, "/ns/team/usage"),
+			Scope::Read
+		);
+		assert_eq!(
+			Scope::for_request(&Method::PUT, "/object/abc"),
+			Scope::Write
+		);
+	}
+}
@@ -0,0 +1,467 @@
+use axum::{
+	body::Body,
+	debug_handler,
+	extract::{Path as AxumPath, State},
+	http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+	response::IntoResponse,
+	routing::get,
+	Json, Router,
+};
+use common::{
+	object_body::{ChunkList, Index, Tree},
+	store::Store,
+	Hash, Header, Mode, ObjectType,
+};
+use futures::{stream, AsyncBufReadExt, AsyncReadExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
+
+use crate::{
+	caching, check_visible,
+	compression::{self, Precompressed},
+	find_object, internal_error, malformed,
+	namespace::{Namespace, Namespaces},
+	ServerState,
+};
+
+/// Routes for looking inside an index without pulling it
+pub fn routes() -> Router<ServerState> {
+	Router::new()
+		.route("/index/{index_id}/tree", get(get_root_tree))
+		.route("/index/{index_id}/tree/", get(get_root_tree))
+		.route("/index/{index_id}/tree/{*path}", get(get_tree))
+		.route("/index/{index_id}/file/{*path}", get(get_file))
+}
+
+#[derive(Deserialize)]
+struct IndexRoot {
+	index_id: Hash,
+}
+
+#[derive(Deserialize)]
+struct IndexPath {
+	index_id: Hash,
+	path: String,
+}
+
+#[derive(Serialize)]
+pub struct TreeListing {
+	pub name: String,
+	pub mode: &'static str,
+	/// Size of the file, or `None` for directories
+	pub size: Option<u64>,
+	pub hash: Hash,
+}
+
+#[debug_handler]
+async fn get_root_tree(
+	AxumPath(IndexRoot { index_id }): AxumPath<IndexRoot>,
+	namespace: Namespace,
+	state: State<ServerState>,
+	request_headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+	list_tree(index_id, String::new(), namespace, state, request_headers).await
+}
+
+#[debug_handler]
+async fn get_tree(
+	AxumPath(IndexPath { index_id, path }): AxumPath<IndexPath>,
+	namespace: Namespace,
+	state: State<ServerState>,
+	request_headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+	list_tree(index_id, path, namespace, state, request_headers).await
+}
+
+/// The entries of the directory at `path` in an index, sorted by name
+async fn list_tree(
+	index_hash: Hash,
+	path: String,
+	namespace: Namespace,
+	State(ServerState {
+		store,
+		namespaces,
+		inventory,
+		..
+	}): State<ServerState>,
+	request_headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+	let index = find_object(&store, &inventory, &namespaces, &namespace, &index_hash).await?;
+	let (mode, hash) = resolve(
+		&store,
+		&namespaces,
+		&namespace,
+		&index_hash,
+		index.header,
+		&path,
+	)
+	.await?;
+
+	if !matches!(mode, Mode::Tree) {
+		return Err((
+			StatusCode::BAD_REQUEST,
+			format!("{path} is a file, fetch it from /index/{index_hash}/file/{path}"),
+		));
+	}
+
+	let mut headers = HeaderMap::new();
+	let etag = caching::object_etag(&hash);
+	caching::set_immutable(&mut headers, &etag);
+
+	if caching::is_not_modified(&request_headers, &etag) {
+		return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+	}
+
+	let entries = tree_entries(&store, &namespaces, &namespace, &hash).await?;
+
+	Ok((headers, Json(entries)).into_response())
+}
+
+/// The entries of a tree sorted by name, with the size of each file. Every
+/// file has to be visible to `namespace`, like the tree itself.
+pub async fn tree_entries(
+	store: &Store,
+	namespaces: &Namespaces,
+	namespace: &Namespace,
+	hash: &Hash,
+) -> Result<Vec<TreeListing>, (StatusCode, String)> {
+	check_visible(namespaces, namespace, hash).await?;
+	let (header, data) = read_object(store, hash).await?;
+	if header.object_type != ObjectType::Tree {
+		return Err(unbrowsable(hash, header.object_type));
+	}
+	let mut entries = Vec::new();
+
+	for entry in Tree::parse(&data)
+		.map_err(|err| malformed(hash, err))?
+		.contents
+	{
+		let size = match entry.mode {
+			Mode::Tree => None,
+			_ => {
+				check_visible(namespaces, namespace, &entry.hash).await?;
+				Some(file_size(store, &entry.hash).await?)
+			}
+		};
+
+		entries.push(TreeListing {
+			name: entry.path,
+			mode: entry.mode.as_str(),
+			size,
+			hash: entry.hash,
+		});
+	}
+
+	entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+	Ok(entries)
+}
+
+/// The contents of the file at `path` in an index, with a Content-Type guessed
+/// from its name. Files stored as chunk lists are streamed chunk by chunk.
+#[debug_handler]
+async fn get_file(
+	AxumPath(IndexPath {
+		index_id: index_hash,
+		path,
+	}): AxumPath<IndexPath>,
+	namespace: Namespace,
+	State(ServerState {
+		store,
+		namespaces,
+		inventory,
+		..
+	}): State<ServerState>,
+	request_headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+	let index = find_object(&store, &inventory, &namespaces, &namespace, &index_hash).await?;
+	let (mode, hash) = resolve(
+		&store,
+		&namespaces,
+		&namespace,
+		&index_hash,
+		index.header,
+		&path,
+	)
+	.await?;
+
+	if matches!(mode, Mode::Tree) {
+		return Err((
+			StatusCode::BAD_REQUEST,
+			format!("{path} is a directory, list it from /index/{index_hash}/tree/{path}"),
+		));
+	}
+
+	let mut headers = HeaderMap::new();
+	let etag = caching::object_etag(&hash);
+	caching::set_immutable(&mut headers, &etag);
+
+	let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+	headers.insert(
+		header::CONTENT_TYPE,
+		HeaderValue::from_str(content_type.as_ref()).unwrap(),
+	);
+	// Artifacts are untrusted, so keep browsers from running anything they
+	// contain with the server's origin
+	headers.insert(
+		header::X_CONTENT_TYPE_OPTIONS,
+		HeaderValue::from_static("nosniff"),
+	);
+	headers.insert(
+		header::CONTENT_SECURITY_POLICY,
+		HeaderValue::from_static("sandbox"),
+	);
+
+	if caching::is_not_modified(&request_headers, &etag) {
+		return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+	}
+
+	let mut object = store.get_object(&hash).await.map_err(internal_error)?;
+	let mut precompressed = false;
+
+	let body = match object.header.object_type {
+		ObjectType::Blob => {
+			headers.insert(header::CONTENT_LENGTH, object.header.size.into());
+			let prefix = object.fill_buf().await.map_err(internal_error)?;
+			precompressed = compression::is_precompressed(ObjectType::Blob, prefix);
+			Body::from_stream(ReaderStream::new(object.compat()))
+		}
+		ObjectType::ChunkList => {
+			let (_, data) = read_object(&store, &hash).await?;
+			let chunks = ChunkList::parse(&data).map_err(|err| malformed(&hash, err))?;
+			headers.insert(header::CONTENT_LENGTH, chunks.size().into());
+
+			// Checked before streaming starts, as a response can't fail
+			// cleanly once its body has begun
+			for chunk in &chunks.chunks {
+				check_visible(&namespaces, &namespace, &chunk.hash).await?;
+			}
+
+			let chunks = stream::iter(chunks.chunks)
+				.then(move |chunk| {
+					let store = store.clone();
+					async move {
+						let object = store
+							.get_object(&chunk.hash)
+							.await
+							.map_err(std::io::Error::other)?;
+						Ok::<_, std::io::Error>(ReaderStream::new(object.compat()))
+					}
+				})
+				.try_flatten();
+			Body::from_stream(chunks)
+		}
+		object_type => return Err(unbrowsable(&hash, object_type)),
+	};
+
+	let mut response = Response::new(body);
+	*response.headers_mut() = headers;
+	if precompressed {
+		response.extensions_mut().insert(Precompressed);
+	}
+
+	Ok(response)
+}
+
+/// Read the index stored as `hash`, which has the header `header`
+pub async fn read_index(
+	store: &Store,
+	hash: &Hash,
+	header: Header,
+) -> Result<Index, (StatusCode, String)> {
+	if header.object_type != ObjectType::Index {
+		return Err(unbrowsable(hash, header.object_type));
+	}
+
+	let (_, data) = read_object(store, hash).await?;
+
+	Index::parse(&data).map_err(|err| malformed(hash, err))
+}
+
+/// Walk `path` down from the tree of an index, returning the mode and hash of
+/// the entry it names. An empty path names the index's root tree. Each object
+/// on the way, and the one named, has to be visible to `namespace`.
+pub async fn resolve(
+	store: &Store,
+	namespaces: &Namespaces,
+	namespace: &Namespace,
+	index_hash: &Hash,
+	index_header: Header,
+	path: &str,
+) -> Result<(Mode, Hash), (StatusCode, String)> {
+	let index = read_index(store, index_hash, index_header).await?;
+	let mut current = (Mode::Tree, index.tree);
+
+	for name in path.split('/').filter(|name| !name.is_empty()) {
+		let (mode, hash) = &current;
+		if !matches!(mode, Mode::Tree) {
+			return Err(missing(path));
+		}
+
+		check_visible(namespaces, namespace, hash).await?;
+		let (header, data) = read_object(store, hash).await?;
+		if header.object_type != ObjectType::Tree {
+			return Err(unbrowsable(hash, header.object_type));
+		}
+
+		let entry = Tree::parse(&data)
+			.map_err(|err| malformed(hash, err))?
+			.contents
+			.into_iter()
+			.find(|entry| entry.path == name)
+			.ok_or_else(|| missing(path))?;
+
+		current = (entry.mode, entry.hash);
+	}
+
+	check_visible(namespaces, namespace, &current.1).await?;
+
+	Ok(current)
+}
+
+/// Size of the file an entry refers to, whether it's a blob or a chunk list
+async fn file_size(store: &Store, hash: &Hash) -> Result<u64, (StatusCode, String)> {
+	let object = store.get_object(hash).await.map_err(internal_error)?;
+
+	match object.header.object_type {
+		ObjectType::ChunkList => {
+			let (_, data) = read_object(store, hash).await?;
+			let chunks = ChunkList::parse(&data).map_err(|err| malformed(hash, err))?;
+			Ok(chunks.size())
+		}
+		_ => Ok(object.header.size),
+	}
+}
+
+async fn read_object(
+	store: &Store,
+	hash: &Hash,
+) -> Result<(Header, Vec<u8>), (StatusCode, String)> {
+	let mut object = store.get_object(hash).await.map_err(internal_error)?;
+
+	let mut data = Vec::new();
+	object
+		.read_to_end(&mut data)
+		.await
+		.map_err(internal_error)?;
+
+	Ok((object.header, data))
+}
+
+fn missing(path: &str) -> (StatusCode, String) {
+	(
+		StatusCode::NOT_FOUND,
+		format!("No file or directory {path}"),
+	)
+}
+
+/// Encrypted objects (and anything unexpected) can't be looked inside
+fn unbrowsable(hash: &Hash, object_type: ObjectType) -> (StatusCode, String) {
+	match object_type {
+		ObjectType::Encrypted => (
+			StatusCode::UNPROCESSABLE_ENTITY,
+			format!("Object {hash} is encrypted and can only be read by pulling it"),
+		),
+		object_type => (
+			StatusCode::BAD_REQUEST,
+			format!(
+				"Object {hash} is a {}, not part of a tree",
+				object_type.to_str()
+			),
+		),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chrono::Utc;
+	use common::{
+		object_body::{Object, TreeEntry},
+		store::StoreObject,
+	};
+
+	use crate::namespace::DEFAULT_NAMESPACE;
+
+	use super::*;
+
+	async fn put(store: &Store, byte: u8, object_type: ObjectType, data: Vec<u8>) -> Hash {
+		let hash = Hash::from([byte; 32]);
+		let header = Header::new(object_type, data.len() as u64);
+		store
+			.put_object(
+				&hash,
+				StoreObject::new_with_header(header, futures::io::Cursor::new(data)),
+			)
+			.await
+			.unwrap();
+		hash
+	}
+
+	fn tree(entries: Vec<(Mode, &str, Hash)>) -> Vec<u8> {
+		let contents = entries
+			.into_iter()
+			.map(|(mode, path, hash)| TreeEntry {
+				mode,
+				path: path.to_string(),
+				hash,
+			})
+			.collect();
+		Tree { contents }.to_data()
+	}
+
+	#[tokio::test]
+	async fn paths_are_resolved_through_nested_trees() {
+		let store = Store::from_builder(opendal::services::Memory::default()).unwrap();
+
+		let file = put(&store, 1, ObjectType::Blob, b"hello".to_vec()).await;
+		let sub = tree(vec![(Mode::Normal, "report.txt", file.clone())]);
+		let sub = put(&store, 2, ObjectType::Tree, sub).await;
+		let root = tree(vec![(Mode::Tree, "logs", sub.clone())]);
+		let root = put(&store, 3, ObjectType::Tree, root).await;
+
+		let index = Index {
+			tree: root.clone(),
+			timestamp: Utc::now(),
+			metadata: HashMap::new(),
+		};
+		let index = put(&store, 4, ObjectType::Index, index.to_data()).await;
+		let header = store.get_object(&index).await.unwrap().header;
+
+		let namespaces = Namespaces::new(store.operator().clone());
+		let resolved_in = |namespace: &'static str, path: &'static str| {
+			let store = store.clone();
+			let namespaces = namespaces.clone();
+			let index = index.clone();
+			async move {
+				let namespace = Namespace(namespace.to_string());
+				resolve(&store, &namespaces, &namespace, &index, header, path).await
+			}
+		};
+		let resolved = |path| resolved_in(DEFAULT_NAMESPACE, path);
+
+		assert_eq!(resolved("").await.unwrap().1, root);
+		assert_eq!(resolved("logs/").await.unwrap().1, sub);
+		assert_eq!(resolved("logs/report.txt").await.unwrap().1, file);
+		assert_eq!(
+			resolved("logs/missing").await.unwrap_err().0,
+			StatusCode::NOT_FOUND
+		);
+		assert_eq!(
+			resolved("logs/report.txt/deeper").await.unwrap_err().0,
+			StatusCode::NOT_FOUND
+		);
+
+		// A namespace only reaches the objects it references itself
+		for hash in [&root, &sub] {
+			let header = store.get_object(hash).await.unwrap().header;
+			namespaces.add_object("team", hash, &header).await.unwrap();
+		}
+		assert_eq!(resolved_in("team", "logs/").await.unwrap().1, sub);
+		assert_eq!(
+			resolved_in("team", "logs/report.txt").await.unwrap_err().0,
+			StatusCode::NOT_FOUND
+		);
+	}
+}
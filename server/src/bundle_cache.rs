@@ -0,0 +1,492 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	ops::Range,
+	sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use axum::{
+	body::{Body, Bytes},
+	http::StatusCode,
+};
+use common::{archive::CompressionAlgorithm, Hash};
+use futures::{
+	future::{BoxFuture, Shared},
+	FutureExt, TryStreamExt,
+};
+use opendal::{ErrorKind, Operator};
+
+use crate::{caching, telemetry};
+
+const BUNDLES_DIR: &str = "bundles/";
+
+/// What a bundle is built from. An index's bundle never changes for a given
+/// compression, so this is all a cached bundle is looked up by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleKey {
+	pub index: Hash,
+	pub compression: CompressionAlgorithm,
+	/// The numeric level, so `default` and the level it stands for share
+	/// an entry
+	pub level: i32,
+}
+
+impl BundleKey {
+	fn name(&self) -> String {
+		format!("{}.{}.{}", self.index, self.compression, self.level)
+	}
+}
+
+/// A built bundle, either just built and still in memory or read from the
+/// cache
+#[derive(Debug, Clone)]
+pub struct Bundle {
+	pub etag: String,
+	pub length: u64,
+	source: Source,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+	Memory(Bytes),
+	Stored(String),
+}
+
+type BundleResult = Result<Bundle, (StatusCode, String)>;
+type Builds = Arc<Mutex<HashMap<String, Shared<BoxFuture<'static, BundleResult>>>>>;
+
+/// Bundles already built, kept in the store under `bundles/` so repeat
+/// requests for one are served without walking its tree again.
+///
+/// Each is stored as `{index}.{compression}.{level}.{digest}`, so its key
+/// and `ETag` can be recovered from a listing at startup. The least recently
+/// used bundles are removed once together they take more than the cache's
+/// size. Concurrent requests for a bundle that isn't cached share one build.
+#[derive(Clone)]
+pub struct BundleCache {
+	operator: Operator,
+	max_size: u64,
+	entries: Arc<Mutex<Entries>>,
+	builds: Builds,
+}
+
+/// Removes a build from [`BundleCache::builds`] when its task ends, whether
+/// it succeeded, failed or panicked, so the next request starts afresh
+struct BuildGuard {
+	builds: Builds,
+	name: String,
+}
+
+impl Drop for BuildGuard {
+	fn drop(&mut self) {
+		if let Ok(mut builds) = self.builds.lock() {
+			builds.remove(&self.name);
+		}
+	}
+}
+
+#[derive(Default)]
+struct Entries {
+	by_name: HashMap<String, Entry>,
+	size: u64,
+	/// Incremented on every use, to order entries by when they were last used
+	clock: u64,
+}
+
+struct Entry {
+	digest: String,
+	length: u64,
+	last_used: u64,
+}
+
+impl BundleCache {
+	/// A cache of at most `max_size` bytes of bundles. Nothing is cached when
+	/// it's 0.
+	pub fn new(operator: Operator, max_size: u64) -> Self {
+		Self {
+			operator,
+			max_size,
+			entries: Default::default(),
+			builds: Default::default(),
+		}
+	}
+
+	/// Pick up the bundles cached before the server started, oldest first.
+	/// Returns how many there were.
+	pub async fn load(&self) -> Result<usize> {
+		let mut lister = self.operator.lister(BUNDLES_DIR).await?;
+
+		let mut found = Vec::new();
+		while let Some(entry) = lister.try_next().await? {
+			let Some((name, digest)) = entry.name().rsplit_once('.') else {
+				continue;
+			};
+
+			let metadata = self.operator.stat(entry.path()).await?;
+			if !metadata.is_file() {
+				continue;
+			}
+
+			found.push((
+				metadata.last_modified(),
+				name.to_string(),
+				Entry {
+					digest: digest.to_string(),
+					length: metadata.content_length(),
+					last_used: 0,
+				},
+			));
+		}
+
+		found.sort_by_key(|(modified, _, _)| *modified);
+		let count = found.len();
+
+		{
+			let mut entries = self.lock();
+			for (_, name, mut entry) in found {
+				entries.clock += 1;
+				entry.last_used = entries.clock;
+				entries.size += entry.length;
+				entries.by_name.insert(name, entry);
+			}
+		}
+		self.evict().await;
+
+		Ok(count)
+	}
+
+	/// The bundle for `key`, from the cache or by awaiting `build`, which
+	/// returns the bundle's bytes. Requests arriving while a bundle is being
+	/// built wait for that build rather than starting another. The build runs
+	/// as its own task, so it finishes even if every request for it is
+	/// dropped.
+	pub async fn get_or_build(
+		&self,
+		key: &BundleKey,
+		build: impl Future<Output = Result<Vec<u8>, (StatusCode, String)>> + Send + 'static,
+	) -> BundleResult {
+		if let Some(bundle) = self.get(key).await {
+			telemetry::record_bundle_cache(true);
+			return Ok(bundle);
+		}
+		telemetry::record_bundle_cache(false);
+
+		let name = key.name();
+		let shared = self
+			.builds
+			.lock()
+			.expect("bundle builds lock to not be poisoned")
+			.entry(name.clone())
+			.or_insert_with(|| {
+				let cache = self.clone();
+				let key = key.clone();
+				let guard = BuildGuard {
+					builds: self.builds.clone(),
+					name: name.clone(),
+				};
+				let task = tokio::spawn(async move {
+					let _guard = guard;
+					Ok(cache.insert(&key, build.await?).await)
+				});
+
+				async move {
+					task.await.unwrap_or_else(|err| {
+						tracing::error!("Bundle build for {name} failed: {err}");
+						Err((
+							StatusCode::INTERNAL_SERVER_ERROR,
+							format!("Bundle build failed: {err}"),
+						))
+					})
+				}
+				.boxed()
+				.shared()
+			})
+			.clone();
+
+		shared.await
+	}
+
+	/// The body of `range` of a bundle
+	pub async fn body(&self, bundle: &Bundle, range: Range<u64>) -> Result<Body> {
+		match &bundle.source {
+			Source::Memory(bytes) => Ok(Body::from(
+				bytes.slice(range.start as usize..range.end as usize),
+			)),
+			Source::Stored(path) => {
+				let stream = self
+					.operator
+					.reader(path)
+					.await?
+					.into_bytes_stream(range)
+					.await?;
+				Ok(Body::from_stream(stream))
+			}
+		}
+	}
+
+	/// The cached bundle for `key`, if it's still in the store
+	async fn get(&self, key: &BundleKey) -> Option<Bundle> {
+		let name = key.name();
+		let (digest, length) = {
+			let mut entries = self.lock();
+			entries.clock += 1;
+			let clock = entries.clock;
+			let entry = entries.by_name.get_mut(&name)?;
+			entry.last_used = clock;
+			(entry.digest.clone(), entry.length)
+		};
+
+		// Another server sharing the store may have evicted it
+		let path = format!("{BUNDLES_DIR}{name}.{digest}");
+		match self.operator.stat(&path).await {
+			Ok(_) => {}
+			Err(err) => {
+				if err.kind() != ErrorKind::NotFound {
+					tracing::warn!("Unable to check cached bundle {name}: {err}");
+				}
+				self.forget(&name);
+				return None;
+			}
+		}
+
+		Some(Bundle {
+			etag: caching::bundle_etag(&key.index, &digest),
+			length,
+			source: Source::Stored(path),
+		})
+	}
+
+	/// Cache a newly built bundle if it fits, returning it to serve from memory
+	async fn insert(&self, key: &BundleKey, body: Vec<u8>) -> Bundle {
+		let digest = caching::bundle_digest(&body);
+		let length = body.len() as u64;
+		let body = Bytes::from(body);
+
+		let bundle = Bundle {
+			etag: caching::bundle_etag(&key.index, &digest),
+			length,
+			source: Source::Memory(body.clone()),
+		};
+
+		if length > self.max_size {
+			return bundle;
+		}
+
+		let name = key.name();
+		let path = format!("{BUNDLES_DIR}{name}.{digest}");
+		if let Err(err) = self.operator.write(&path, body).await {
+			tracing::warn!("Unable to cache bundle {name}: {err}");
+			return bundle;
+		}
+
+		let replaced = {
+			let mut entries = self.lock();
+			entries.clock += 1;
+			let last_used = entries.clock;
+			entries.size += length;
+			let replaced = entries.by_name.insert(
+				name.clone(),
+				Entry {
+					digest: digest.clone(),
+					length,
+					last_used,
+				},
+			);
+			if let Some(replaced) = &replaced {
+				entries.size -= replaced.length;
+			}
+			replaced
+		};
+
+		// A rebuild after the store was repacked can differ from the bundle it
+		// replaces
+		if let Some(replaced) = replaced.filter(|replaced| replaced.digest != digest) {
+			let _ = self
+				.operator
+				.delete(&format!("{BUNDLES_DIR}{name}.{}", replaced.digest))
+				.await;
+		}
+		self.evict().await;
+
+		bundle
+	}
+
+	/// Remove the least recently used bundles until the rest fit
+	async fn evict(&self) {
+		loop {
+			let (name, digest) = {
+				let mut entries = self.lock();
+				if entries.size <= self.max_size {
+					return;
+				}
+
+				let Some(name) = entries
+					.by_name
+					.iter()
+					.min_by_key(|(_, entry)| entry.last_used)
+					.map(|(name, _)| name.clone())
+				else {
+					return;
+				};
+
+				let entry = entries.by_name.remove(&name).unwrap();
+				entries.size -= entry.length;
+				(name, entry.digest)
+			};
+
+			let path = format!("{BUNDLES_DIR}{name}.{digest}");
+			if let Err(err) = self.operator.delete(&path).await {
+				tracing::warn!("Unable to evict cached bundle {name}: {err}");
+			}
+		}
+	}
+
+	fn forget(&self, name: &str) {
+		let mut entries = self.lock();
+		if let Some(entry) = entries.by_name.remove(name) {
+			entries.size -= entry.length;
+		}
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+		self.entries
+			.lock()
+			.expect("bundle cache lock to not be poisoned")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::atomic::{AtomicUsize, Ordering},
+		time::Duration,
+	};
+
+	use super::*;
+
+	fn key(byte: u8) -> BundleKey {
+		BundleKey {
+			index: Hash::from([byte; 32]),
+			compression: CompressionAlgorithm::Zstd,
+			level: 3,
+		}
+	}
+
+	async fn bytes(cache: &BundleCache, bundle: &Bundle) -> Vec<u8> {
+		let body = cache.body(bundle, 0..bundle.length).await.unwrap();
+		axum::body::to_bytes(body, usize::MAX)
+			.await
+			.unwrap()
+			.to_vec()
+	}
+
+	#[tokio::test]
+	async fn concurrent_requests_share_one_build() {
+		let operator = Operator::new(opendal::services::Memory::default())
+			.unwrap()
+			.finish();
+		let cache = BundleCache::new(operator, 1024);
+		let builds = Arc::new(AtomicUsize::new(0));
+
+		let build = || {
+			let builds = builds.clone();
+			async move {
+				builds.fetch_add(1, Ordering::SeqCst);
+				tokio::time::sleep(Duration::from_millis(50)).await;
+				Ok(b"bundle".to_vec())
+			}
+		};
+
+		let key = key(1);
+		let (first, second) = tokio::join!(
+			cache.get_or_build(&key, build()),
+			cache.get_or_build(&key, build())
+		);
+		let (first, second) = (first.unwrap(), second.unwrap());
+		assert_eq!(builds.load(Ordering::SeqCst), 1);
+		assert_eq!(first.etag, second.etag);
+
+		// Later requests are served from the store
+		let cached = cache.get_or_build(&key, build()).await.unwrap();
+		assert_eq!(builds.load(Ordering::SeqCst), 1);
+		assert!(matches!(cached.source, Source::Stored(_)));
+		assert_eq!(cached.etag, first.etag);
+		assert_eq!(bytes(&cache, &cached).await, b"bundle");
+	}
+
+	#[tokio::test]
+	async fn failed_or_panicked_builds_are_not_kept() {
+		let operator = Operator::new(opendal::services::Memory::default())
+			.unwrap()
+			.finish();
+		let cache = BundleCache::new(operator, 1024);
+		let key = key(1);
+
+		let failed = cache
+			.get_or_build(&key, async {
+				Err((StatusCode::BAD_REQUEST, "malformed".to_string()))
+			})
+			.await;
+		assert_eq!(failed.unwrap_err().0, StatusCode::BAD_REQUEST);
+
+		let panicked = cache
+			.get_or_build(&key, async { panic!("build panicked") })
+			.await;
+		assert_eq!(panicked.unwrap_err().0, StatusCode::INTERNAL_SERVER_ERROR);
+
+		// A build nobody waits for still finishes, and is then cached
+		let abandoned = cache.get_or_build(&key, async {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+			Ok(b"bundle".to_vec())
+		});
+		assert!(tokio::time::timeout(Duration::from_millis(10), abandoned)
+			.await
+			.is_err());
+		tokio::time::sleep(Duration::from_millis(100)).await;
+
+		assert!(cache.builds.lock().unwrap().is_empty());
+		let bundle = cache
+			.get_or_build(&key, async { Err((StatusCode::GONE, String::new())) })
+			.await
+			.unwrap();
+		assert_eq!(bytes(&cache, &bundle).await, b"bundle");
+	}
+
+	#[tokio::test]
+	async fn least_recently_used_bundles_are_evicted() {
+		let operator = Operator::new(opendal::services::Memory::default())
+			.unwrap()
+			.finish();
+		let cache = BundleCache::new(operator.clone(), 20);
+		let build = |data: &'static [u8]| async move { Ok(data.to_vec()) };
+
+		cache
+			.get_or_build(&key(1), build(b"0123456789"))
+			.await
+			.unwrap();
+		cache
+			.get_or_build(&key(2), build(b"abcdefghij"))
+			.await
+			.unwrap();
+		// Using the first bundle makes the second the one to go
+		cache.get_or_build(&key(1), build(b"")).await.unwrap();
+		cache
+			.get_or_build(&key(3), build(b"ABCDEFGHIJ"))
+			.await
+			.unwrap();
+
+		let restarted = BundleCache::new(operator, 20);
+		assert_eq!(restarted.load().await.unwrap(), 2);
+		assert!(restarted.get(&key(1)).await.is_some());
+		assert!(restarted.get(&key(2)).await.is_none());
+
+		// Bundles bigger than the whole cache are served without being kept
+		let large = cache
+			.get_or_build(&key(4), build(b"too large to be cached"))
+			.await
+			.unwrap();
+		assert_eq!(bytes(&cache, &large).await, b"too large to be cached");
+		assert!(cache.get(&key(4)).await.is_none());
+	}
+}
@@ -0,0 +1,75 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use common::Hash;
+use sha2::{Digest, Sha256};
+
+/// Content addressed responses never change, so they can be cached for as
+/// long as caches allow
+const IMMUTABLE: &str = "max-age=31536000, immutable";
+
+/// Strong validator for the bytes of an object, which are fixed by its hash
+pub fn object_etag(hash: &Hash) -> String {
+	format!("\"{hash}\"")
+}
+
+/// Strong validator for a bundle of an index. Bundles of the same index always
+/// hold the same objects, but not necessarily in the same bytes, as that
+/// depends on the server's compression settings and how its store is packed,
+/// so a digest of the bytes from [`bundle_digest`] is part of the tag.
+pub fn bundle_etag(hash: &Hash, digest: &str) -> String {
+	format!("\"{hash}-{digest}\"")
+}
+
+pub fn bundle_digest(body: &[u8]) -> String {
+	hex::encode(&Sha256::digest(body)[..8])
+}
+
+/// Mark a response as content addressed by `etag`
+pub fn set_immutable(headers: &mut HeaderMap, etag: &str) {
+	headers.insert(header::ETAG, HeaderValue::from_str(etag).unwrap());
+	headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE));
+}
+
+/// Whether the request's `If-None-Match` matches `etag`, meaning the client's
+/// cached copy is current. Uses the weak comparison required for
+/// `If-None-Match`.
+pub fn is_not_modified(request: &HeaderMap, etag: &str) -> bool {
+	let etag = etag.trim_start_matches("W/");
+
+	request
+		.get_all(header::IF_NONE_MATCH)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(str::trim)
+		.any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn if_none_match_compares_weakly() {
+		let hash = Hash::from([1u8; 32]);
+		let etag = object_etag(&hash);
+
+		let mut request = HeaderMap::new();
+		assert!(!is_not_modified(&request, &etag));
+
+		request.insert(
+			header::IF_NONE_MATCH,
+			HeaderValue::from_str(&format!("\"other\", W/{etag}")).unwrap(),
+		);
+		assert!(is_not_modified(&request, &etag));
+		assert!(!is_not_modified(
+			&request,
+			&bundle_etag(&hash, &bundle_digest(b"bundle"))
+		));
+
+		request.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+		assert!(!is_not_modified(&request, &etag));
+
+		request.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+		assert!(is_not_modified(&request, &etag));
+	}
+}
@@ -0,0 +1,474 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::{Arc, RwLock},
+	time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use common::{object_body::Index, store::Store, Hash, ObjectType};
+use futures::{AsyncReadExt, TryStreamExt};
+use opendal::{ErrorKind, Operator};
+use serde::{Deserialize, Serialize};
+
+use crate::namespace::{Namespaces, DEFAULT_NAMESPACE};
+
+const CATALOG_DIR: &str = "catalog/indexes/";
+/// Written once every index that was in the store before the catalog existed
+/// has been added to it
+const BACKFILLED_MARKER: &str = "catalog/backfilled";
+
+/// Query parameter prefix for matching index metadata
+const METADATA_PREFIX: &str = "meta.";
+
+/// What an index says about itself, without its tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexSummary {
+	pub hash: Hash,
+	pub timestamp: DateTime<Utc>,
+	pub metadata: HashMap<String, String>,
+}
+
+/// Secondary index of every index in the store, so they can be searched by
+/// metadata without reading each one.
+///
+/// Each index is summarised as JSON at `catalog/indexes/{hash}` when it is
+/// uploaded, and the summaries are held in memory along with the namespaces
+/// each index was uploaded to. Servers sharing a store pick up each other's
+/// summaries and namespace markers on [`Catalog::refresh`].
+#[derive(Clone)]
+pub struct Catalog {
+	store: Store,
+	operator: Operator,
+	namespaces: Namespaces,
+	entries: Arc<RwLock<HashMap<Hash, IndexSummary>>>,
+	/// Namespaces each index is listed in. Membership is never removed.
+	members: Arc<RwLock<HashMap<Hash, HashSet<String>>>>,
+}
+
+impl Catalog {
+	pub fn new(store: Store, namespaces: Namespaces) -> Self {
+		Self {
+			operator: store.operator().clone(),
+			store,
+			namespaces,
+			entries: Default::default(),
+			members: Default::default(),
+		}
+	}
+
+	/// Record an index uploaded to `namespace`, whose marker has already been
+	/// written
+	pub async fn add(&self, namespace: &str, hash: &Hash) -> Result<()> {
+		self.summary(hash).await?;
+		self.add_member(namespace, hash);
+
+		Ok(())
+	}
+
+	/// Summaries of the indexes listed in `namespace`, in no particular order
+	pub fn list(&self, namespace: &str) -> Vec<IndexSummary> {
+		let entries = self.read();
+		self.members
+			.read()
+			.expect("catalog lock to not be poisoned")
+			.iter()
+			.filter(|(_, namespaces)| namespaces.contains(namespace))
+			.filter_map(|(hash, _)| entries.get(hash).cloned())
+			.collect()
+	}
+
+	/// Summary of the index `hash`, reading the index and recording it in the
+	/// catalog if it isn't already
+	pub async fn summary(&self, hash: &Hash) -> Result<IndexSummary> {
+		if let Some(summary) = self.get(hash) {
+			return Ok(summary);
+		}
+
+		let mut object = self.store.get_object(hash).await?;
+		if object.header.object_type != ObjectType::Index {
+			return Err(anyhow!("Object {hash} is not an index"));
+		}
+
+		let mut data = Vec::new();
+		object.read_to_end(&mut data).await?;
+		let index = Index::parse(&data)?;
+
+		let summary = IndexSummary {
+			hash: hash.clone(),
+			timestamp: index.timestamp,
+			metadata: index.metadata,
+		};
+
+		self.operator
+			.write(
+				&format!("{CATALOG_DIR}{hash}"),
+				serde_json::to_vec(&summary)?,
+			)
+			.await?;
+		self.entries
+			.write()
+			.expect("catalog lock to not be poisoned")
+			.insert(hash.clone(), summary.clone());
+
+		Ok(summary)
+	}
+
+	pub fn get(&self, hash: &Hash) -> Option<IndexSummary> {
+		self.read().get(hash).cloned()
+	}
+
+	/// Load summaries and namespace markers written since the last refresh,
+	/// by this server or another one sharing the store
+	pub async fn refresh(&self) -> Result<()> {
+		self.load_summaries().await?;
+		// Markers are written before summaries, so every summary loaded above
+		// has its marker in place by now
+		self.load_members().await?;
+		self.adopt_unlisted().await
+	}
+
+	async fn load_summaries(&self) -> Result<()> {
+		let mut lister = self
+			.operator
+			.lister_with(CATALOG_DIR)
+			.recursive(true)
+			.await?;
+
+		let mut missing = Vec::new();
+		while let Some(entry) = lister.try_next().await? {
+			let Ok(hash) = Hash::try_from(entry.name()) else {
+				continue;
+			};
+
+			if !self.read().contains_key(&hash) {
+				missing.push(entry.path().to_string());
+			}
+		}
+
+		for path in missing {
+			let data = match self.operator.read(&path).await {
+				Ok(data) => data.to_vec(),
+				Err(err) if err.kind() == ErrorKind::NotFound => continue,
+				Err(err) => return Err(err.into()),
+			};
+			let summary: IndexSummary = serde_json::from_slice(&data)?;
+
+			self.entries
+				.write()
+				.expect("catalog lock to not be poisoned")
+				.insert(summary.hash.clone(), summary);
+		}
+
+		Ok(())
+	}
+
+	async fn load_members(&self) -> Result<()> {
+		for namespace in self.namespaces.names().await? {
+			for hash in self.namespaces.indexes(&namespace).await? {
+				self.add_member(&namespace, &hash);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// List indexes that aren't in any namespace in the default one. They were
+	/// uploaded before namespaces existed, when they were all in the default
+	/// namespace.
+	async fn adopt_unlisted(&self) -> Result<()> {
+		let unlisted: Vec<Hash> = {
+			let members = self
+				.members
+				.read()
+				.expect("catalog lock to not be poisoned");
+			self.read()
+				.keys()
+				.filter(|hash| !members.contains_key(*hash))
+				.cloned()
+				.collect()
+		};
+
+		for hash in &unlisted {
+			self.namespaces.add_index(DEFAULT_NAMESPACE, hash).await?;
+			self.add_member(DEFAULT_NAMESPACE, hash);
+		}
+
+		if !unlisted.is_empty() {
+			tracing::info!(
+				"Listed {} indexes from before namespaces in the default namespace",
+				unlisted.len()
+			);
+		}
+
+		Ok(())
+	}
+
+	fn add_member(&self, namespace: &str, hash: &Hash) {
+		self.members
+			.write()
+			.expect("catalog lock to not be poisoned")
+			.entry(hash.clone())
+			.or_default()
+			.insert(namespace.to_string());
+	}
+
+	/// Add every index already in the store to the catalog. Only done once per
+	/// store, as loose objects have to be opened to find the indexes among
+	/// them. Returns how many indexes were added.
+	pub async fn backfill(&self) -> Result<usize> {
+		if self.operator.exists(BACKFILLED_MARKER).await? {
+			return Ok(0);
+		}
+
+		self.refresh().await?;
+
+		let mut added = 0;
+		for (hash, object_type) in self.store.list_objects().await? {
+			if self.read().contains_key(&hash) {
+				continue;
+			}
+
+			let object_type = match object_type {
+				Some(object_type) => object_type,
+				None => self.store.get_header(&hash).await?.object_type,
+			};
+
+			if object_type != ObjectType::Index {
+				continue;
+			}
+
+			// One bad index mustn't leave the rest of the store unsearchable
+			match self.summary(&hash).await {
+				Ok(_) => added += 1,
+				Err(err) => tracing::warn!("Unable to add index {hash} to the catalog: {err:#}"),
+			}
+		}
+
+		self.operator
+			.write(BACKFILLED_MARKER, Vec::<u8>::new())
+			.await?;
+
+		Ok(added)
+	}
+
+	/// Backfill, then refresh every `interval`, logging rather than stopping
+	/// on errors
+	pub fn spawn_refresh(self, interval: Duration) {
+		tokio::spawn(async move {
+			match self.backfill().await {
+				Ok(0) => {}
+				Ok(added) => tracing::info!("Added {added} existing indexes to the catalog"),
+				Err(err) => {
+					tracing::error!("Unable to add existing indexes to the catalog: {err:#}")
+				}
+			}
+
+			let mut ticks = tokio::time::interval(interval);
+			loop {
+				ticks.tick().await;
+
+				if let Err(err) = self.refresh().await {
+					tracing::error!("Unable to refresh the index catalog: {err:#}");
+				}
+			}
+		});
+	}
+
+	fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<Hash, IndexSummary>> {
+		self.entries
+			.read()
+			.expect("catalog lock to not be poisoned")
+	}
+}
+
+/// Filters for searching indexes, from the query string of `GET /indexes`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IndexQuery {
+	/// Metadata the index must have, from `meta.<key>=<value>` parameters
+	pub metadata: Vec<(String, String)>,
+	/// Only indexes made at or after this time
+	pub since: Option<DateTime<Utc>>,
+	/// Keep only this many of the most recent matches
+	pub limit: Option<usize>,
+}
+
+impl IndexQuery {
+	pub fn from_params(params: &[(String, String)]) -> Result<Self, String> {
+		let mut query = IndexQuery::default();
+
+		for (key, value) in params {
+			if let Some(key) = key.strip_prefix(METADATA_PREFIX) {
+				query.metadata.push((key.to_string(), value.clone()));
+				continue;
+			}
+
+			match key.as_str() {
+				"since" => {
+					let since = DateTime::parse_from_rfc3339(value)
+						.map_err(|_| format!("since must be an RFC 3339 timestamp, not {value}"))?;
+					query.since = Some(since.into());
+				}
+				"limit" => {
+					let limit = value
+						.parse()
+						.map_err(|_| format!("limit must be a number, not {value}"))?;
+					query.limit = Some(limit);
+				}
+				key => return Err(format!("Unknown query parameter {key}")),
+			}
+		}
+
+		Ok(query)
+	}
+
+	pub fn matches(&self, summary: &IndexSummary) -> bool {
+		self.since.is_none_or(|since| summary.timestamp >= since)
+			&& self
+				.metadata
+				.iter()
+				.all(|(key, value)| summary.metadata.get(key) == Some(value))
+	}
+
+	/// The matching indexes, oldest first
+	pub fn apply(&self, summaries: Vec<IndexSummary>) -> Vec<IndexSummary> {
+		let mut matches: Vec<_> = summaries
+			.into_iter()
+			.filter(|summary| self.matches(summary))
+			.collect();
+		matches.sort_by(|a, b| (a.timestamp, a.hash.as_str()).cmp(&(b.timestamp, b.hash.as_str())));
+
+		if let Some(limit) = self.limit {
+			matches.drain(..matches.len().saturating_sub(limit));
+		}
+
+		matches
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+	use common::{object_body::Object, store::StoreObject, Header};
+
+	use super::*;
+
+	fn summary(byte: u8, seconds: i64, branch: &str) -> IndexSummary {
+		IndexSummary {
+			hash: Hash::from([byte; 32]),
+			timestamp: Utc.timestamp_opt(seconds, 0).unwrap(),
+			metadata: HashMap::from([("branch".to_string(), branch.to_string())]),
+		}
+	}
+
+	fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+		params
+			.iter()
+			.map(|(key, value)| (key.to_string(), value.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn queries_filter_by_metadata_and_keep_the_latest() {
+		let summaries = vec![
+			summary(1, 300, "main"),
+			summary(2, 100, "main"),
+			summary(3, 200, "feature"),
+			summary(4, 50, "main"),
+		];
+
+		let query = IndexQuery::from_params(&params(&[
+			("meta.branch", "main"),
+			("since", "1970-01-01T00:01:00Z"),
+			("limit", "1"),
+		]))
+		.unwrap();
+
+		assert_eq!(
+			query.apply(summaries.clone()),
+			vec![summary(1, 300, "main")]
+		);
+
+		let all = IndexQuery::default().apply(summaries);
+		let hashes: Vec<u8> = all.iter().map(|summary| summary.hash.hash[0]).collect();
+		assert_eq!(hashes, vec![4, 2, 3, 1]);
+	}
+
+	#[test]
+	fn unknown_or_malformed_parameters_are_rejected() {
+		assert!(IndexQuery::from_params(&params(&[("branch", "main")])).is_err());
+		assert!(IndexQuery::from_params(&params(&[("since", "yesterday")])).is_err());
+		assert!(IndexQuery::from_params(&params(&[("limit", "-1")])).is_err());
+	}
+
+	#[tokio::test]
+	async fn existing_indexes_are_backfilled_once() {
+		let store = Store::from_builder(opendal::services::Memory::default()).unwrap();
+
+		let index = Index {
+			tree: Hash::from([9u8; 32]),
+			timestamp: Utc.timestamp_opt(100, 0).unwrap(),
+			metadata: HashMap::from([("branch".to_string(), "main".to_string())]),
+		}
+		.to_data();
+		let hash = Hash::from([1u8; 32]);
+		for (hash, index) in [
+			(&hash, index),
+			(&Hash::from([2u8; 32]), b"garbage".to_vec()),
+		] {
+			let header = Header::new(ObjectType::Index, index.len() as u64);
+			store
+				.put_object(
+					hash,
+					StoreObject::new_with_header(header, futures::io::Cursor::new(index)),
+				)
+				.await
+				.unwrap();
+		}
+
+		// The malformed index is skipped rather than stopping the backfill
+		let namespaces = Namespaces::new(store.operator().clone());
+		let catalog = Catalog::new(store.clone(), namespaces.clone());
+		assert_eq!(catalog.backfill().await.unwrap(), 1);
+		assert_eq!(catalog.backfill().await.unwrap(), 0);
+
+		// Indexes from before namespaces are listed in the default one
+		catalog.refresh().await.unwrap();
+		assert_eq!(
+			catalog.list(DEFAULT_NAMESPACE),
+			vec![summary(1, 100, "main")]
+		);
+		assert!(catalog.list("team").is_empty());
+
+		let team = Index {
+			tree: Hash::from([9u8; 32]),
+			timestamp: Utc.timestamp_opt(200, 0).unwrap(),
+			metadata: HashMap::from([("branch".to_string(), "team".to_string())]),
+		}
+		.to_data();
+		let team_hash = Hash::from([3u8; 32]);
+		let header = Header::new(ObjectType::Index, team.len() as u64);
+		store
+			.put_object(
+				&team_hash,
+				StoreObject::new_with_header(header, futures::io::Cursor::new(team)),
+			)
+			.await
+			.unwrap();
+		namespaces
+			.add_object("team", &team_hash, &header)
+			.await
+			.unwrap();
+		catalog.add("team", &team_hash).await.unwrap();
+		assert_eq!(catalog.list("team"), vec![summary(3, 200, "team")]);
+		assert_eq!(catalog.list(DEFAULT_NAMESPACE).len(), 1);
+
+		// A second server sharing the store sees the summaries and where
+		// they're listed
+		let other = Catalog::new(store.clone(), Namespaces::new(store.operator().clone()));
+		other.refresh().await.unwrap();
+		assert_eq!(other.get(&hash), Some(summary(1, 100, "main")));
+		assert_eq!(other.list("team"), vec![summary(3, 200, "team")]);
+		assert_eq!(other.list(DEFAULT_NAMESPACE), vec![summary(1, 100, "main")]);
+	}
+}
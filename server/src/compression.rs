@@ -0,0 +1,98 @@
+use axum::http::Response;
+use common::ObjectType;
+use tower_http::compression::{
+	predicate::{DefaultPredicate, Predicate},
+	CompressionLayer,
+};
+
+/// Marks a response whose body is already compressed, so the HTTP layer
+/// sends it as it is rather than spending CPU compressing it again
+#[derive(Debug, Clone, Copy)]
+pub struct Precompressed;
+
+/// Magic numbers of formats that don't get any smaller when compressed again
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+	// zstd
+	&[0x28, 0xB5, 0x2F, 0xFD],
+	// gzip
+	&[0x1F, 0x8B],
+	// xz
+	&[0xFD, b'7', b'z', b'X', b'Z', 0x00],
+	// bzip2
+	b"BZh",
+	// lz4
+	&[0x04, 0x22, 0x4D, 0x18],
+	// zip, and the many formats built on it
+	&[b'P', b'K', 0x03, 0x04],
+	// 7z
+	&[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C],
+	// png
+	&[0x89, b'P', b'N', b'G'],
+	// jpeg
+	&[0xFF, 0xD8, 0xFF],
+	// gif
+	b"GIF8",
+];
+
+/// Compression of responses for clients that accept it, except for responses
+/// marked [`Precompressed`] and those [`DefaultPredicate`] skips, such as
+/// small bodies and images
+pub fn layer() -> CompressionLayer<ContentAware> {
+	CompressionLayer::new()
+		.br(true)
+		.deflate(true)
+		.gzip(true)
+		.zstd(true)
+		.compress_when(ContentAware::default())
+}
+
+#[derive(Clone, Default)]
+pub struct ContentAware(DefaultPredicate);
+
+impl Predicate for ContentAware {
+	fn should_compress<B>(&self, response: &Response<B>) -> bool
+	where
+		B: axum::body::HttpBody,
+	{
+		response.extensions().get::<Precompressed>().is_none() && self.0.should_compress(response)
+	}
+}
+
+/// Whether the body of an object, starting with `prefix`, is already
+/// compressed. Encrypted objects never compress, and blobs are recognised by
+/// the magic number of their format.
+pub fn is_precompressed(object_type: ObjectType, prefix: &[u8]) -> bool {
+	match object_type {
+		ObjectType::Encrypted => true,
+		ObjectType::Blob => COMPRESSED_MAGIC
+			.iter()
+			.any(|magic| prefix.starts_with(magic)),
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::body::Body;
+
+	use super::*;
+
+	#[test]
+	fn compressed_formats_are_recognised() {
+		let zstd = [0x28, 0xB5, 0x2F, 0xFD, 0x00, 0x58];
+		assert!(is_precompressed(ObjectType::Blob, &zstd));
+		assert!(is_precompressed(ObjectType::Blob, b"PK\x03\x04archive"));
+		assert!(!is_precompressed(ObjectType::Blob, b"plain text"));
+		assert!(!is_precompressed(ObjectType::Tree, &zstd));
+		assert!(is_precompressed(ObjectType::Encrypted, b"anything"));
+	}
+
+	#[test]
+	fn marked_responses_are_not_compressed() {
+		let mut response = Response::new(Body::from(vec![b'a'; 4096]));
+		assert!(ContentAware::default().should_compress(&response));
+
+		response.extensions_mut().insert(Precompressed);
+		assert!(!ContentAware::default().should_compress(&response));
+	}
+}
@@ -1,4 +1,8 @@
-use std::{collections::BTreeMap, net::SocketAddr, path::Path};
+use std::{
+	collections::BTreeMap,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+};
 
 use common::{
 	archive::{CompressionAlgorithm, CompressionLevel},
@@ -11,7 +15,7 @@ use figment::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::Cli;
+use crate::{auth::Grant, quota::Quota, Cli};
 
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct Config {
@@ -22,25 +26,316 @@ pub struct Config {
 	pub logging: LoggingConfig,
 	#[serde(default)]
 	pub archive: ArchiveConfig,
+	#[serde(default)]
+	pub objects: ObjectsConfig,
+	#[serde(default)]
+	pub inventory: InventoryConfig,
+	#[serde(default)]
+	pub catalog: CatalogConfig,
+	#[serde(default)]
+	pub auth: AuthConfig,
+	#[serde(default)]
+	pub quotas: QuotasConfig,
+	#[serde(default)]
+	pub signatures: SignaturesConfig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
 	#[serde(default = "default_bind")]
 	pub bind: SocketAddr,
+	/// Serve HTTPS rather than plain HTTP
+	pub tls: Option<TlsConfig>,
+	/// Seconds in-flight requests are given to finish on shutdown before
+	/// their connections are closed
+	#[serde(default = "default_shutdown_timeout")]
+	pub shutdown_timeout: u64,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+fn default_shutdown_timeout() -> u64 {
+	30
+}
+
+/// Certificates for serving HTTPS. The files are read again when the server
+/// receives SIGHUP, so renewed certificates are picked up without a restart.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TlsConfig {
+	/// PEM certificate chain, starting with the server's certificate
+	pub cert: PathBuf,
+	/// PEM private key of the server's certificate
+	pub key: PathBuf,
+	/// PEM bundle of the CAs client certificates are issued by. When set,
+	/// every client must present a certificate issued by one of them.
+	pub client_ca: Option<PathBuf>,
+}
+
+/// How bundles of an index's objects are built and cached
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ArchiveConfig {
 	#[serde(default)]
 	pub compression_format: CompressionAlgorithm,
+	#[serde(default)]
 	pub compression_level: CompressionLevel,
+	/// Bytes of built bundles kept in the store to serve repeat requests
+	/// from. 0 disables the cache.
+	#[serde(default = "default_bundle_cache_size")]
+	pub cache_size: u64,
+	/// Levels clients may ask for with `?compression=&level=`, by format.
+	/// Formats not listed can't be asked for.
+	#[serde(default = "default_allowed_levels")]
+	pub allowed_levels: BTreeMap<String, LevelRange>,
+}
+
+/// Inclusive range of compression levels
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct LevelRange {
+	pub min: i32,
+	pub max: i32,
+}
+
+impl Default for ArchiveConfig {
+	fn default() -> Self {
+		Self {
+			compression_format: CompressionAlgorithm::default(),
+			compression_level: CompressionLevel::default(),
+			cache_size: default_bundle_cache_size(),
+			allowed_levels: default_allowed_levels(),
+		}
+	}
+}
+
+impl ArchiveConfig {
+	/// The format and numeric level to build a bundle with, given the
+	/// `compression` and `level` a client asked for. Without either, the
+	/// configured ones are used. Anything asked for has to be allowed by
+	/// `allowed_levels`.
+	pub fn bundle_compression(
+		&self,
+		compression: Option<&str>,
+		level: Option<&str>,
+	) -> Result<(CompressionAlgorithm, i32), String> {
+		if compression.is_none() && level.is_none() {
+			let level = self
+				.compression_level
+				.get_compression_level(self.compression_format)
+				.map_err(|err| err.to_string())?;
+			return Ok((self.compression_format, level));
+		}
+
+		let compression = match compression {
+			Some(compression) => compression
+				.parse::<CompressionAlgorithm>()
+				.map_err(|_| format!("Unknown compression {compression}"))?,
+			None => self.compression_format,
+		};
+		let level = match level {
+			Some(level) => level
+				.parse::<CompressionLevel>()
+				.map_err(|_| format!("Unknown compression level {level}"))?,
+			None => CompressionLevel::Default,
+		};
+		let level = level
+			.get_compression_level(compression)
+			.map_err(|err| err.to_string())?;
+
+		let Some(allowed) = self.allowed_levels.get(&compression.to_string()) else {
+			return Err(format!(
+				"Bundles can't be requested with {compression} compression"
+			));
+		};
+		if !(allowed.min..=allowed.max).contains(&level) {
+			return Err(format!(
+				"Compression level {level} for {compression} is outside of the allowed {} to {}",
+				allowed.min, allowed.max
+			));
+		}
+
+		Ok((compression, level))
+	}
+}
+
+fn default_bundle_cache_size() -> u64 {
+	1024 * 1024 * 1024
+}
+
+/// Every format at up to its `best` level, except zstd's slowest levels
+fn default_allowed_levels() -> BTreeMap<String, LevelRange> {
+	BTreeMap::from([
+		("none".to_string(), LevelRange { min: 0, max: 0 }),
+		("zstd".to_string(), LevelRange { min: 1, max: 15 }),
+		("deflate".to_string(), LevelRange { min: 0, max: 9 }),
+		("lzma2".to_string(), LevelRange { min: 0, max: 9 }),
+	])
+}
+
+/// How individual objects are written to the store. Objects stay readable
+/// whichever format they were written in.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ObjectsConfig {
+	#[serde(default = "default_object_compression")]
+	pub compression_format: CompressionAlgorithm,
+	#[serde(default)]
+	pub compression_level: CompressionLevel,
+}
+
+impl Default for ObjectsConfig {
+	fn default() -> Self {
+		Self {
+			compression_format: default_object_compression(),
+			compression_level: CompressionLevel::default(),
+		}
+	}
+}
+
+impl ObjectsConfig {
+	/// The zstd level to compress new objects with, if compression is enabled
+	pub fn zstd_level(&self) -> anyhow::Result<Option<i32>> {
+		match self.compression_format {
+			CompressionAlgorithm::None => Ok(None),
+			CompressionAlgorithm::Zstd => Ok(Some(
+				self.compression_level
+					.get_compression_level(CompressionAlgorithm::Zstd)?,
+			)),
+			other => anyhow::bail!(
+				"unsupported object compression format {other}: only none and zstd are supported"
+			),
+		}
+	}
+}
+
+fn default_object_compression() -> CompressionAlgorithm {
+	CompressionAlgorithm::None
+}
+
+/// The in-memory record of which objects the store holds
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InventoryConfig {
+	/// Seconds between scans of the store for objects written by other
+	/// servers sharing it
+	#[serde(default = "default_resync_interval")]
+	pub resync_interval: u64,
+}
+
+impl Default for InventoryConfig {
+	fn default() -> Self {
+		Self {
+			resync_interval: default_resync_interval(),
+		}
+	}
+}
+
+fn default_resync_interval() -> u64 {
+	600
+}
+
+/// The in-memory summaries of the indexes in the store
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CatalogConfig {
+	/// Seconds between loads of the index summaries and namespace markers
+	/// written by other servers sharing the store
+	#[serde(default = "default_refresh_interval")]
+	pub refresh_interval: u64,
+}
+
+impl Default for CatalogConfig {
+	fn default() -> Self {
+		Self {
+			refresh_interval: default_refresh_interval(),
+		}
+	}
+}
+
+fn default_refresh_interval() -> u64 {
+	60
+}
+
+/// Who may read from and write to the server. Requests are only authenticated
+/// once at least one token or an HMAC secret is configured.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuthConfig {
+	/// Allow reads without credentials, for public caches
+	#[serde(default = "default_anonymous_read")]
+	pub anonymous_read: bool,
+	#[serde(default)]
+	pub tokens: Vec<TokenConfig>,
+	/// Secret used to sign and verify tokens issued with `--issue-token`
+	pub hmac_secret: Option<String>,
+}
+
+impl Default for AuthConfig {
+	fn default() -> Self {
+		Self {
+			anonymous_read: default_anonymous_read(),
+			tokens: Vec::new(),
+			hmac_secret: None,
+		}
+	}
+}
+
+fn default_anonymous_read() -> bool {
+	true
+}
+
+/// Storage quotas, in logical bytes as given by each object's size
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct QuotasConfig {
+	/// Applies to namespaces without an entry in `namespaces`
+	#[serde(default)]
+	pub default: Quota,
+	#[serde(default)]
+	pub namespaces: BTreeMap<String, Quota>,
+	/// Keyed by static token name or signed token subject
+	#[serde(default)]
+	pub tokens: BTreeMap<String, Quota>,
+}
+
+impl QuotasConfig {
+	pub fn namespace(&self, namespace: &str) -> Quota {
+		self.namespaces
+			.get(namespace)
+			.copied()
+			.unwrap_or(self.default)
+	}
+
+	pub fn token(&self, subject: &str) -> Quota {
+		self.tokens.get(subject).copied().unwrap_or_default()
+	}
+}
+
+/// Refs that may only point at signed indexes
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct SignaturesConfig {
+	/// Refs starting with any of these prefixes, in any namespace, can only be
+	/// set to indexes signed by one of the trusted keys
+	#[serde(default)]
+	pub protected_refs: Vec<String>,
+	/// File of trusted OpenSSH public keys in the `authorized_keys` format
+	pub trusted_keys: Option<PathBuf>,
+}
+
+impl SignaturesConfig {
+	pub fn is_protected(&self, name: &str) -> bool {
+		self.protected_refs
+			.iter()
+			.any(|prefix| name.starts_with(prefix.as_str()))
+	}
+}
+
+/// A static bearer token
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TokenConfig {
+	/// Identifies the token's holder in logs
+	pub name: String,
+	pub token: String,
+	pub scopes: Vec<Grant>,
 }
 
 impl Default for ServerConfig {
 	fn default() -> Self {
 		Self {
 			bind: default_bind(),
+			tls: None,
+			shutdown_timeout: default_shutdown_timeout(),
 		}
 	}
 }
@@ -224,6 +519,105 @@ mod tests {
 		assert!(cfg.logging.level.is_none());
 		assert!(cfg.logging.format.is_none());
 		assert!(cfg.store.is_none());
+		assert_eq!(cfg.objects.zstd_level().unwrap(), None);
+		assert!(cfg.auth.anonymous_read);
+		assert!(cfg.auth.tokens.is_empty());
+		assert!(cfg.server.tls.is_none());
+		assert_eq!(cfg.inventory.resync_interval, 600);
+		assert_eq!(cfg.catalog.refresh_interval, 60);
+		assert_eq!(cfg.archive.cache_size, 1024 * 1024 * 1024);
+	}
+
+	#[test]
+	fn bundle_compression_is_limited_to_allowed_levels() {
+		let cfg: Config = toml::from_str(
+			"[archive]\ncompression_format = \"Zstd\"\n\n[archive.allowed_levels]\nzstd = { min = 1, max = 9 }\nlzma2 = { min = 0, max = 6 }",
+		)
+		.unwrap();
+		let archive = cfg.archive;
+
+		assert_eq!(
+			archive.bundle_compression(None, None),
+			Ok((CompressionAlgorithm::Zstd, 3))
+		);
+		assert_eq!(
+			archive.bundle_compression(Some("lzma2"), Some("fast")),
+			Ok((CompressionAlgorithm::LZMA2, 1))
+		);
+		assert_eq!(
+			archive.bundle_compression(None, Some("7")),
+			Ok((CompressionAlgorithm::Zstd, 7))
+		);
+		// zstd's best is 15 and lzma2's is 9
+		assert!(archive.bundle_compression(None, Some("best")).is_err());
+		assert!(archive
+			.bundle_compression(Some("lzma2"), Some("best"))
+			.is_err());
+		assert!(archive.bundle_compression(Some("deflate"), None).is_err());
+		assert!(archive.bundle_compression(Some("brotli"), None).is_err());
+		assert!(archive.bundle_compression(None, Some("fastest")).is_err());
+	}
+
+	#[test]
+	fn tls_client_ca_is_optional() {
+		let cfg: Config =
+			toml::from_str("[server.tls]\ncert = \"server.pem\"\nkey = \"server.key\"").unwrap();
+
+		let tls = cfg.server.tls.unwrap();
+		assert_eq!(tls.cert, PathBuf::from("server.pem"));
+		assert!(tls.client_ca.is_none());
+	}
+
+	#[test]
+	fn auth_tokens_are_parsed_with_scopes() {
+		let cfg: Config = toml::from_str(
+			"[auth]\nanonymous_read = false\n\n[[auth.tokens]]\nname = \"ci\"\ntoken = \"abc\"\nscopes = [\"read\", \"write\"]",
+		)
+		.unwrap();
+
+		assert!(!cfg.auth.anonymous_read);
+		assert_eq!(cfg.auth.tokens[0].name, "ci");
+		assert_eq!(
+			cfg.auth.tokens[0].scopes,
+			vec!["read".parse().unwrap(), "write".parse().unwrap()]
+		);
+	}
+
+	#[test]
+	fn namespaces_fall_back_to_the_default_quota() {
+		let cfg: Config = toml::from_str(
+			"[quotas.default]\nhard_limit = 100\n\n[quotas.namespaces.team]\nsoft_limit = 10\n\n[quotas.tokens.ci]\nhard_limit = 5",
+		)
+		.unwrap();
+
+		assert_eq!(cfg.quotas.namespace("other").hard_limit, Some(100));
+		assert_eq!(cfg.quotas.namespace("team").hard_limit, None);
+		assert_eq!(cfg.quotas.namespace("team").soft_limit, Some(10));
+		assert_eq!(cfg.quotas.token("ci").hard_limit, Some(5));
+		assert_eq!(cfg.quotas.token("other"), Quota::default());
+	}
+
+	#[test]
+	fn protected_refs_match_by_prefix() {
+		let cfg: Config = toml::from_str(
+			"[signatures]\nprotected_refs = [\"releases/\"]\ntrusted_keys = \"keys\"",
+		)
+		.unwrap();
+
+		assert!(cfg.signatures.is_protected("releases/v1"));
+		assert!(!cfg.signatures.is_protected("nightly/v1"));
+	}
+
+	#[test]
+	fn object_compression_only_accepts_zstd() {
+		let cfg: Config = toml::from_str(
+			"[objects]\ncompression_format = \"Zstd\"\ncompression_level = \"Best\"",
+		)
+		.unwrap();
+		assert_eq!(cfg.objects.zstd_level().unwrap(), Some(15));
+
+		let cfg: Config = toml::from_str("[objects]\ncompression_format = \"LZMA2\"").unwrap();
+		assert!(cfg.objects.zstd_level().is_err());
 	}
 
 	#[test]
@@ -235,6 +629,10 @@ mod tests {
 			json: None,
 			config: None,
 			store: Some(PathBuf::from("/tmp/test-store")),
+			repack: false,
+			issue_token: None,
+			scope: Vec::new(),
+			token_ttl: 0,
 		};
 
 		let config: Config = Figment::new()
@@ -0,0 +1,123 @@
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use axum_server::Handle;
+use opendal::Operator;
+
+/// Written and removed again to check the store accepts writes
+const PROBE_PATH: &str = "health/readyz";
+
+#[derive(Clone)]
+struct HealthState {
+	operator: Operator,
+	shutting_down: Arc<AtomicBool>,
+}
+
+/// `/healthz` and `/readyz`, which orchestrators probe without credentials
+pub fn routes(operator: Operator, shutting_down: Arc<AtomicBool>) -> Router {
+	Router::new()
+		.route("/healthz", get(|| async { "ok" }))
+		.route("/readyz", get(readyz))
+		.with_state(HealthState {
+			operator,
+			shutting_down,
+		})
+}
+
+/// Ready while the store can be written to, until the server starts shutting
+/// down so load balancers stop sending it new requests
+async fn readyz(State(state): State<HealthState>) -> (StatusCode, String) {
+	if state.shutting_down.load(Ordering::Relaxed) {
+		return (StatusCode::SERVICE_UNAVAILABLE, "shutting down".into());
+	}
+
+	let probe = async {
+		state.operator.write(PROBE_PATH, b"ready".to_vec()).await?;
+		state.operator.delete(PROBE_PATH).await
+	};
+
+	match probe.await {
+		Ok(()) => (StatusCode::OK, "ready".into()),
+		Err(err) => {
+			tracing::warn!("Store is not writable: {err}");
+			(
+				StatusCode::SERVICE_UNAVAILABLE,
+				format!("store is not writable: {err}"),
+			)
+		}
+	}
+}
+
+/// Once the process receives SIGTERM or Ctrl-C, stop accepting connections
+/// and give in-flight requests up to `timeout` to finish before closing the
+/// rest
+pub async fn shutdown_on_signal(
+	handle: Handle<std::net::SocketAddr>,
+	shutting_down: Arc<AtomicBool>,
+	timeout: Duration,
+) {
+	let interrupt = async {
+		let _ = tokio::signal::ctrl_c().await;
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		use tokio::signal::unix::{signal, SignalKind};
+
+		match signal(SignalKind::terminate()) {
+			Ok(mut terminate) => {
+				terminate.recv().await;
+			}
+			Err(err) => {
+				tracing::warn!("Unable to listen for SIGTERM: {err}");
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		_ = interrupt => {}
+		_ = terminate => {}
+	}
+
+	shutting_down.store(true, Ordering::Relaxed);
+	tracing::info!(
+		"Shutting down, waiting up to {}s for {} connections to finish",
+		timeout.as_secs(),
+		handle.connection_count()
+	);
+	handle.graceful_shutdown(Some(timeout));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn not_ready_once_shutting_down() {
+		let state = HealthState {
+			operator: Operator::new(opendal::services::Memory::default())
+				.unwrap()
+				.finish(),
+			shutting_down: Arc::new(AtomicBool::new(false)),
+		};
+
+		assert_eq!(readyz(State(state.clone())).await.0, StatusCode::OK);
+		assert!(!state.operator.exists(PROBE_PATH).await.unwrap());
+
+		state.shutting_down.store(true, Ordering::Relaxed);
+		assert_eq!(
+			readyz(State(state)).await.0,
+			StatusCode::SERVICE_UNAVAILABLE
+		);
+	}
+}
@@ -0,0 +1,284 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, RwLock,
+	},
+	time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use common::{store::Store, Hash, Header, ObjectType};
+use futures::{stream, StreamExt, TryStreamExt};
+use opendal::ErrorKind;
+
+const SNAPSHOT_PATH: &str = "inventory/snapshot";
+/// How many unknown objects a resync reads the headers of at once
+const RESYNC_CONCURRENCY: usize = 32;
+/// Hash, type key and little endian size of each object in a snapshot
+const SNAPSHOT_ENTRY_LENGTH: usize = 32 + 4 + 8;
+
+/// Headers of the objects in the store, held in memory so existence and
+/// header queries don't each cost a round trip to the backend.
+///
+/// The inventory is loaded from a snapshot at startup and brought up to date
+/// by [`Inventory::resync`], which only opens objects it doesn't know yet.
+/// Objects are never removed from the store, so a known object is always
+/// there. An unknown one may still have been written by another server
+/// sharing the store since the last resync.
+#[derive(Clone)]
+pub struct Inventory {
+	store: Store,
+	objects: Arc<RwLock<HashMap<[u8; 32], Header>>>,
+	/// Whether a resync has completed, so every object written before it is
+	/// known
+	synced: Arc<AtomicBool>,
+}
+
+impl Inventory {
+	pub fn new(store: Store) -> Self {
+		Self {
+			store,
+			objects: Default::default(),
+			synced: Default::default(),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.read().len()
+	}
+
+	pub fn get(&self, hash: &Hash) -> Option<Header> {
+		self.read().get(&hash.hash).copied()
+	}
+
+	/// Record an object written to the store
+	pub fn insert(&self, hash: &Hash, header: Header) {
+		self.objects
+			.write()
+			.expect("inventory lock to not be poisoned")
+			.insert(hash.hash, header);
+	}
+
+	/// Whether the store has `hash`. Unknown objects are looked up in the
+	/// store, as they may have been written by another server.
+	pub async fn exists(&self, hash: &Hash) -> Result<bool> {
+		if self.get(hash).is_some() {
+			return Ok(true);
+		}
+
+		self.store.exists(hash).await
+	}
+
+	/// Whether an upload of `hash` can be skipped. Once synced, unknown objects
+	/// are taken to be missing without asking the store: at worst the body of
+	/// an object another server wrote since the last resync is uploaded
+	/// again. The upload is staged and checked like any other, and dropped
+	/// rather than published as [`Store::publish`] never replaces a stored
+	/// object.
+	pub async fn contains(&self, hash: &Hash) -> Result<bool> {
+		if self.get(hash).is_some() {
+			return Ok(true);
+		}
+
+		match self.synced.load(Ordering::Relaxed) {
+			true => Ok(false),
+			false => self.store.exists(hash).await,
+		}
+	}
+
+	/// Header of `hash`, or `None` if the store doesn't have it. Unknown
+	/// objects are opened and recorded.
+	pub async fn header(&self, hash: &Hash) -> Result<Option<Header>> {
+		if let Some(header) = self.get(hash) {
+			return Ok(Some(header));
+		}
+
+		if !self.store.exists(hash).await? {
+			return Ok(None);
+		}
+
+		let header = self.store.get_header(hash).await?;
+		self.insert(hash, header);
+
+		Ok(Some(header))
+	}
+
+	/// Load the snapshot saved by the last resync, so the inventory is useful
+	/// before the next one finishes. Returns how many objects it held.
+	pub async fn load_snapshot(&self) -> Result<usize> {
+		let data = match self.store.operator().read(SNAPSHOT_PATH).await {
+			Ok(data) => data.to_vec(),
+			Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+			Err(err) => return Err(err.into()),
+		};
+
+		let entries = decode_snapshot(&data)?;
+		let count = entries.len();
+
+		self.objects
+			.write()
+			.expect("inventory lock to not be poisoned")
+			.extend(entries);
+
+		Ok(count)
+	}
+
+	/// Add every object in the store the inventory doesn't know yet, then save
+	/// a snapshot if any were added. Only the headers of unknown objects are
+	/// read, a few at a time. Returns how many were added.
+	pub async fn resync(&self) -> Result<usize> {
+		let unknown: Vec<Hash> = self
+			.store
+			.list_objects()
+			.await?
+			.into_iter()
+			.map(|(hash, _)| hash)
+			.filter(|hash| self.get(hash).is_none())
+			.collect();
+		let added = unknown.len();
+
+		stream::iter(unknown)
+			.map(|hash| async move {
+				let header = self.store.get_header(&hash).await?;
+				self.insert(&hash, header);
+				anyhow::Ok(())
+			})
+			.buffer_unordered(RESYNC_CONCURRENCY)
+			.try_collect::<()>()
+			.await?;
+
+		if added > 0 || !self.synced.load(Ordering::Relaxed) {
+			let snapshot = encode_snapshot(&self.read());
+			self.store.operator().write(SNAPSHOT_PATH, snapshot).await?;
+		}
+
+		self.synced.store(true, Ordering::Relaxed);
+
+		Ok(added)
+	}
+
+	/// Resync now and then every `interval`, logging rather than stopping on
+	/// errors
+	pub fn spawn_resync(self, interval: Duration) {
+		tokio::spawn(async move {
+			let mut ticks = tokio::time::interval(interval);
+			loop {
+				ticks.tick().await;
+
+				match self.resync().await {
+					Ok(0) => {}
+					Ok(added) => tracing::info!(
+						"Added {added} objects to the inventory, which now holds {}",
+						self.len()
+					),
+					Err(err) => tracing::error!("Unable to resync the object inventory: {err:#}"),
+				}
+			}
+		});
+	}
+
+	fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<[u8; 32], Header>> {
+		self.objects
+			.read()
+			.expect("inventory lock to not be poisoned")
+	}
+}
+
+fn encode_snapshot(objects: &HashMap<[u8; 32], Header>) -> Vec<u8> {
+	let mut data = Vec::with_capacity(objects.len() * SNAPSHOT_ENTRY_LENGTH);
+
+	for (hash, header) in objects {
+		data.extend_from_slice(hash);
+		data.extend_from_slice(header.object_type.to_str().as_bytes());
+		data.extend_from_slice(&header.size.to_le_bytes());
+	}
+
+	data
+}
+
+fn decode_snapshot(data: &[u8]) -> Result<Vec<([u8; 32], Header)>> {
+	if !data.len().is_multiple_of(SNAPSHOT_ENTRY_LENGTH) {
+		return Err(anyhow!("Inventory snapshot is truncated"));
+	}
+
+	data.chunks_exact(SNAPSHOT_ENTRY_LENGTH)
+		.map(|entry| {
+			let (hash, rest) = entry.split_at(32);
+			let (object_type, size) = rest.split_at(4);
+
+			let object_type = std::str::from_utf8(object_type)
+				.ok()
+				.and_then(ObjectType::from_str)
+				.ok_or_else(|| anyhow!("Inventory snapshot has an unknown object type"))?;
+			let size = u64::from_le_bytes(size.try_into().unwrap());
+
+			Ok((hash.try_into().unwrap(), Header::new(object_type, size)))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use common::store::StoreObject;
+
+	use super::*;
+
+	async fn put(store: &Store, byte: u8, data: &[u8]) -> Hash {
+		let hash = Hash::from([byte; 32]);
+		let header = Header::new(ObjectType::Blob, data.len() as u64);
+		store
+			.put_object(
+				&hash,
+				StoreObject::new_with_header(header, futures::io::Cursor::new(data.to_vec())),
+			)
+			.await
+			.unwrap();
+		hash
+	}
+
+	#[tokio::test]
+	async fn objects_are_found_after_a_resync_or_from_its_snapshot() {
+		let store = Store::from_builder(opendal::services::Memory::default()).unwrap();
+		let first = put(&store, 1, b"hello").await;
+		let second = put(&store, 2, b"hello world").await;
+
+		let inventory = Inventory::new(store.clone());
+		assert_eq!(inventory.resync().await.unwrap(), 2);
+		assert_eq!(inventory.resync().await.unwrap(), 0);
+		assert_eq!(
+			inventory.get(&second),
+			Some(Header::new(ObjectType::Blob, 11))
+		);
+
+		// Another server starting up knows the objects before resyncing
+		let other = Inventory::new(store.clone());
+		assert_eq!(other.load_snapshot().await.unwrap(), 2);
+		assert_eq!(other.get(&first), Some(Header::new(ObjectType::Blob, 5)));
+
+		// Objects written elsewhere since are still found by asking the store
+		let third = put(&store, 3, b"!").await;
+		assert!(!inventory.contains(&third).await.unwrap());
+		assert!(inventory.exists(&third).await.unwrap());
+		assert_eq!(
+			inventory.header(&third).await.unwrap(),
+			Some(Header::new(ObjectType::Blob, 1))
+		);
+		assert_eq!(
+			inventory.header(&Hash::from([4u8; 32])).await.unwrap(),
+			None
+		);
+	}
+
+	#[test]
+	fn truncated_snapshots_are_rejected() {
+		let objects = HashMap::from([([7u8; 32], Header::new(ObjectType::Tree, 42))]);
+		let data = encode_snapshot(&objects);
+
+		assert_eq!(
+			decode_snapshot(&data).unwrap(),
+			vec![([7u8; 32], Header::new(ObjectType::Tree, 42))]
+		);
+		assert!(decode_snapshot(&data[..data.len() - 1]).is_err());
+	}
+}
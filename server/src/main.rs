@@ -1,101 +1,192 @@
 use axum::{
 	body::Body,
 	debug_handler,
-	extract::{DefaultBodyLimit, Path as AxumPath, Request, State},
-	http::{HeaderMap, HeaderValue, Response, StatusCode},
-	routing::{get, put},
-	Router,
+	extract::{DefaultBodyLimit, Path as AxumPath, Query, Request, State},
+	http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+	middleware,
+	routing::get,
+	Extension, Json, Router,
 };
+use axum_server::{tls_rustls::RustlsConfig, Handle};
 use clap::Parser;
 use common::{
-	archive::{Archive, ArchiveBody, ArchiveHeaderEntry, StoreEntryData, HEADER},
-	object_body::{Index, Object},
+	archive::{
+		Archive, ArchiveBody, ArchiveEntryData, ArchiveHeaderEntry, CompressionAlgorithm,
+		CompressionLevel, RawEntryData, StoreEntryData, HEADER,
+	},
+	object_body::{Index, MalformedObject},
 	read_object_into_headers,
-	store::{Store, StoreObject},
+	signature::{read_trusted_keys, verify_index, PublicKey},
+	store::{ObjectReader, Store, StoreObject},
 	Hash, Header, ObjectType,
 };
-use futures::{AsyncReadExt, TryStreamExt};
-use std::{collections::HashMap, fs::create_dir, path::PathBuf};
-use tower_http::{compression::CompressionLayer, trace::TraceLayer};
+use futures::{AsyncBufReadExt, AsyncReadExt};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, HashMap},
+	fs::create_dir,
+	path::PathBuf,
+	sync::{atomic::AtomicBool, Arc},
+	time::{Duration, Instant},
+};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
+use tower_http::trace::TraceLayer;
 
+use crate::auth::{issue_token, require_auth, Auth, Grant, Identity, REJECTED_BODY_LIMIT};
+use crate::bundle_cache::{BundleCache, BundleKey};
+use crate::catalog::{Catalog, IndexQuery};
+use crate::compression::Precompressed;
 use crate::config::{Config, StoreConfig};
+use crate::inventory::Inventory;
 use crate::logging::configure_tracing;
-
+use crate::namespace::{is_valid_ref, Namespace, Namespaces, Usage};
+use crate::quota::{check_upload, Quota};
+use crate::upload::UploadCheck;
+
+mod auth;
+mod browse;
+mod bundle_cache;
+mod caching;
+mod catalog;
+mod compression;
 mod config;
+mod health;
+mod inventory;
 mod logging;
+mod namespace;
+mod quota;
+mod range;
+mod telemetry;
+mod tls;
+mod ui;
+mod upload;
 
-// lazy_static! {
-//     static ref INDEXES: RwLock<HashSet<Hash>> = Default::default();
-//     static ref TREES: RwLock<HashSet<Hash>> = Default::default();
-//     static ref BLOBS: RwLock<HashSet<Hash>> = Default::default();
-// }
-
-// async fn read_cache(store: &Store) {
-//     let mut indexes = INDEXES.try_write().unwrap();
-//     let mut trees = TREES.try_write().unwrap();
-//     let mut blobs = BLOBS.try_write().unwrap();
+#[derive(Clone)]
+struct ServerState {
+	store: Store,
+	namespaces: Namespaces,
+	config: Config,
+	/// Keys indexes must be signed with to be set as a protected ref
+	trusted_keys: Arc<Vec<PublicKey>>,
+	catalog: Catalog,
+	inventory: Inventory,
+	bundles: BundleCache,
+}
 
-//     store.
+#[derive(Deserialize)]
+struct ObjectPath {
+	object_id: Hash,
+}
 
-//     let mut total_size: u128 = 0;
+#[derive(Deserialize)]
+struct BundlePath {
+	index_id: Hash,
+}
 
-//     for entry in fs::read_dir(path).unwrap().filter_map(|x| x.ok()) {
-//         let Ok(metadata) = entry.metadata() else {
-//             continue;
-//         };
+/// Compression a client asks for a bundle to be built with, as a format name
+/// and a level such as `best` or `9`
+#[derive(Deserialize)]
+struct BundleQuery {
+	compression: Option<String>,
+	level: Option<String>,
+}
 
-//         if metadata.is_file() {
-//             continue;
-//         }
+#[derive(Deserialize)]
+struct RefPath {
+	name: String,
+}
 
-//         let prefix = entry.file_name();
+fn internal_error(err: impl ToString) -> (StatusCode, String) {
+	telemetry::record_store_error();
+	(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
 
-//         for entry in fs::read_dir(entry.path()).unwrap().filter_map(|x| x.ok()) {
-//             let Ok(metadata) = entry.metadata() else {
-//                 continue;
-//             };
+/// Objects outside the default namespace are only visible to namespaces that
+/// uploaded them. The default namespace sees the whole store, as it did before
+/// namespaces existed.
+async fn check_visible(
+	namespaces: &Namespaces,
+	namespace: &Namespace,
+	hash: &Hash,
+) -> Result<(), (StatusCode, String)> {
+	if namespace.is_default()
+		|| namespaces
+			.contains(&namespace.0, hash)
+			.await
+			.map_err(internal_error)?
+	{
+		return Ok(());
+	}
 
-//             if !metadata.is_file() {
-//                 continue;
-//             }
+	Err(not_found(hash))
+}
 
-//            let Ok(file) = File::open(entry.path()).await else {
-//                continue;
-//            };
-//            let mut reader = BufReader::new(file).compat();
+fn not_found(hash: &Hash) -> (StatusCode, String) {
+	(StatusCode::NOT_FOUND, format!("No object {hash}"))
+}
 
-//             let Ok(file) = File::open(entry.path()).await else {
-//                 continue;
-//             };
-//             let mut reader = BufReader::new(file);
+fn malformed(hash: &Hash, err: anyhow::Error) -> (StatusCode, String) {
+	(
+		StatusCode::BAD_REQUEST,
+		format!("Object {hash} is malformed: {err:#}"),
+	)
+}
 
-//             let Ok(Header { object_type, size }) = Header::read_from_async(&mut reader).await else {
-//                 panic!("Corrupt file {:?}", entry.path());
-//             };
+/// An error from walking a tree, which is the client's fault when an object
+/// in it is malformed
+fn walk_error(err: anyhow::Error) -> (StatusCode, String) {
+	match err.downcast_ref::<MalformedObject>() {
+		Some(_) => (StatusCode::BAD_REQUEST, err.to_string()),
+		None => internal_error(err),
+	}
+}
 
-//             total_size += size as u128;
+/// Open an object visible to `namespace`, or 404 if there isn't one
+async fn find_object(
+	store: &Store,
+	inventory: &Inventory,
+	namespaces: &Namespaces,
+	namespace: &Namespace,
+	hash: &Hash,
+) -> Result<StoreObject<ObjectReader>, (StatusCode, String)> {
+	check_visible(namespaces, namespace, hash).await?;
+
+	if !inventory.exists(hash).await.map_err(internal_error)? {
+		return Err(not_found(hash));
+	}
 
-//             match object_type {
-//                 common::ObjectType::Blob => &mut blobs,
-//                 common::ObjectType::Tree => &mut trees,
-//                 common::ObjectType::Index => &mut indexes,
-//             }
-//             .insert(hash);
-//         }
-//     }
+	store.get_object(hash).await.map_err(internal_error)
+}
 
-//     println!("Loaded {} blobs", blobs.len());
-//     println!("Loaded {} trees", trees.len());
-//     println!("Loaded {} indexes", indexes.len());
-//     println!("Total Size: {} bytes", total_size);
+/// Key the object requested as `hash` is stored under for `namespace`: the
+/// hash itself, or where the namespace only has an encrypted copy, the key
+/// that copy is kept under
+async fn object_key(
+	inventory: &Inventory,
+	namespaces: &Namespaces,
+	namespace: &Namespace,
+	hash: &Hash,
+) -> Result<Hash, (StatusCode, String)> {
+	for key in [hash.clone(), namespace.encrypted_key(hash)] {
+		if check_visible(namespaces, namespace, &key).await.is_ok()
+			&& inventory.exists(&key).await.map_err(internal_error)?
+		{
+			return Ok(key);
+		}
+	}
 
-//     indexes.iter().for_each(|i| println!("Index {i}"));
-// }
+	Err(not_found(hash))
+}
 
-#[derive(Clone)]
-struct ServerState {
-	store: Store,
-	config: Config,
+/// Headers describing an object, shared by `GET` and `HEAD`
+fn object_headers(hash: &Hash, Header { object_type, size }: Header) -> HeaderMap {
+	let mut headers = HeaderMap::new();
+	headers.insert("Object-Type", object_type.to_str().parse().unwrap());
+	headers.insert("Object-Size", size.to_string().parse().unwrap());
+	headers.insert(header::CONTENT_LENGTH, size.into());
+	headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+	caching::set_immutable(&mut headers, &caching::object_etag(hash));
+	headers
 }
 
 #[allow(dead_code)]
@@ -184,17 +275,182 @@ impl From<std::io::Error> for ErrorResult {
 
 #[debug_handler]
 async fn put_object(
-	AxumPath(object_hash): AxumPath<Hash>,
-	State(ServerState { store, .. }): State<ServerState>,
+	AxumPath(ObjectPath {
+		object_id: object_hash,
+	}): AxumPath<ObjectPath>,
+	namespace: Namespace,
+	identity: Option<Extension<Identity>>,
+	State(ServerState {
+		store,
+		namespaces,
+		config,
+		catalog,
+		inventory,
+		..
+	}): State<ServerState>,
 	headers: HeaderMap,
 	request: Request<Body>,
-) -> Result<StatusCode, (StatusCode, String)> {
-	match store.exists(&object_hash).await {
-		Ok(false) => Ok(()),
-		Ok(true) => Err((StatusCode::OK, "Object already exists".into())),
-		Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-	}?;
+) -> Result<(StatusCode, HeaderMap), (StatusCode, String)> {
+	// Encrypted objects can't be checked against their hash, so they're
+	// stored under a key of their own rather than the hash they're uploaded as
+	let key = match read_object_headers(&headers) {
+		Ok(header) if header.object_type == ObjectType::Encrypted => {
+			namespace.encrypted_key(&object_hash)
+		}
+		_ => object_hash.clone(),
+	};
+
+	let exists = inventory.contains(&key).await.map_err(internal_error)?;
+	let stored = match exists {
+		true => Some(
+			inventory
+				.header(&key)
+				.await
+				.map_err(internal_error)?
+				.ok_or_else(|| not_found(&object_hash))?,
+		),
+		false => None,
+	};
+
+	// An object already in the shared pool that this namespace can see is
+	// recorded again without its body, using the size it was stored with. One
+	// it can't see has to be uploaded in full, so knowing its hash isn't
+	// enough to make it readable here.
+	let reachable = namespace.is_default()
+		|| namespaces
+			.contains(&namespace.0, &key)
+			.await
+			.map_err(internal_error)?;
+	let header = match stored {
+		Some(stored) if reachable => stored,
+		_ => read_object_headers(&headers)?,
+	};
+
+	let subject = identity
+		.as_ref()
+		.map(|Extension(identity)| identity.subject.as_str());
+
+	let warnings = match check_upload(
+		&namespaces,
+		&config.quotas,
+		&namespace.0,
+		subject,
+		&key,
+		header.size,
+	)
+	.await
+	{
+		Ok(warnings) => warnings,
+		Err(err) => {
+			// Let the client see the rejection rather than a broken pipe
+			let _ = axum::body::to_bytes(request.into_body(), REJECTED_BODY_LIMIT).await;
+			return Err(err);
+		}
+	};
 
+	let mut response_headers = HeaderMap::new();
+	response_headers.insert(header::ETAG, caching::object_etag(&key).parse().unwrap());
+	for warning in warnings {
+		tracing::warn!("{warning}");
+		if let Ok(value) = HeaderValue::from_str(&warning) {
+			response_headers.append("Quota-Warning", value);
+		}
+	}
+
+	let check = UploadCheck::new(header);
+	// The quota was checked against the declared size, so a body of any other
+	// length mustn't be kept or counted, and neither may one that isn't the
+	// object it was uploaded as
+	let verify = |check: &UploadCheck| match header.object_type {
+		ObjectType::Encrypted => check.finish(),
+		_ => check.verify(&object_hash),
+	};
+
+	let status = if stored.is_some() {
+		if !reachable {
+			let _ = futures::io::copy(
+				&mut check.reader(request.into_body()),
+				&mut futures::io::sink(),
+			)
+			.await;
+			verify(&check)?;
+		}
+
+		StatusCode::OK
+	} else {
+		// The body is written to a key of its own and only moved to the
+		// object's key once it has been checked, so a bad upload never
+		// replaces or removes a good copy of the object
+		let mut body = check.reader(request.into_body());
+		let staged = match header.object_type {
+			// Indexes are small, and have to parse to be catalogued, so
+			// they're read in full and a malformed one is never stored
+			ObjectType::Index => {
+				let mut data = Vec::new();
+				let _ = body.read_to_end(&mut data).await;
+				verify(&check)?;
+				Index::parse(&data).map_err(|err| malformed(&object_hash, err))?;
+
+				let store_object =
+					StoreObject::new_with_header(header, futures::io::Cursor::new(data));
+				store.stage_object(&key, store_object).await
+			}
+			_ => {
+				let store_object = StoreObject::new_with_header(header, body);
+				store.stage_object(&key, store_object).await
+			}
+		};
+
+		if let Err(err) = verify(&check) {
+			if let Ok(staged) = staged {
+				if let Err(err) = store.discard(staged).await {
+					tracing::warn!("Unable to remove rejected upload of {object_hash}: {err}");
+				}
+			}
+			return Err(err);
+		}
+		let staged = staged.map_err(internal_error)?;
+
+		// A concurrent upload of the same object may have been published
+		// first, in which case this copy is dropped
+		match store.publish(staged).await.map_err(internal_error)? {
+			true => StatusCode::CREATED,
+			false => StatusCode::OK,
+		}
+	};
+	inventory.insert(&key, header);
+
+	telemetry::record_upload(
+		header.object_type,
+		header.size,
+		status != StatusCode::CREATED,
+	);
+
+	namespaces
+		.add_object(&namespace.0, &key, &header)
+		.await
+		.map_err(internal_error)?;
+
+	if let Some(subject) = subject {
+		namespaces
+			.add_upload(subject, &key, &header)
+			.await
+			.map_err(internal_error)?;
+	}
+
+	if header.object_type == ObjectType::Index {
+		catalog
+			.add(&namespace.0, &object_hash)
+			.await
+			.map_err(internal_error)?;
+	}
+
+	Ok((status, response_headers))
+}
+
+/// The header of an uploaded object, from the `Object-Type` and `Object-Size`
+/// request headers
+fn read_object_headers(headers: &HeaderMap) -> Result<Header, (StatusCode, String)> {
 	let Some(object_type) = headers.get("Object-Type").and_then(|v| v.to_str().ok()) else {
 		return Err((StatusCode::BAD_REQUEST, "Missing Object-Type Header".into()));
 	};
@@ -210,80 +466,131 @@ async fn put_object(
 	let Some(object_size): Option<u64> = object_size.parse().ok() else {
 		return Err((StatusCode::BAD_REQUEST, "Invalid Object-Size Header".into()));
 	};
-	let header = Header::new(object_type, object_size);
-	let data_stream = request.into_body().into_data_stream();
 
-	let buffered_reader = data_stream.map_err(std::io::Error::other).into_async_read();
+	Ok(Header::new(object_type, object_size))
+}
 
-	let store_object = StoreObject::new_with_header(header, buffered_reader);
+#[debug_handler]
+async fn get_object(
+	AxumPath(ObjectPath {
+		object_id: object_hash,
+	}): AxumPath<ObjectPath>,
+	namespace: Namespace,
+	State(ServerState {
+		store,
+		namespaces,
+		inventory,
+		..
+	}): State<ServerState>,
+	request_headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+	let key = object_key(&inventory, &namespaces, &namespace, &object_hash).await?;
+	let mut object = store.get_object(&key).await.map_err(internal_error)?;
+	let Header { object_type, size } = object.header;
+	let mut headers = object_headers(&key, object.header);
+
+	let etag = caching::object_etag(&key);
+	if caching::is_not_modified(&request_headers, &etag) {
+		return Ok(not_modified(headers));
+	}
 
-	// Content-addressable writes are idempotent. A concurrent PUT for the
-	// same hash can race past the exists() check above; opendal's Fs
-	// backend then errors with "writer got too much data" for the loser.
-	// Treat any put_object failure as success if the object now exists.
-	if let Err(err) = store.put_object(&object_hash, store_object).await {
-		if !store.exists(&object_hash).await.unwrap_or(false) {
-			return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+	let prefix = object.fill_buf().await.map_err(internal_error)?;
+	let precompressed = compression::is_precompressed(object_type, prefix);
+
+	let (status, body) = match range::requested(&request_headers, &etag, size) {
+		range::Requested::Full => {
+			telemetry::record_download(object_type, size);
+			(
+				StatusCode::OK,
+				Body::from_stream(ReaderStream::new(object.compat())),
+			)
+		}
+		range::Requested::Partial(range) => {
+			range::set_partial(&mut headers, &range, size);
+			telemetry::record_download(object_type, range.end - range.start);
+
+			let object = store
+				.get_object_from(&key, range.start)
+				.await
+				.map_err(internal_error)?
+				.take(range.end - range.start);
+			(
+				StatusCode::PARTIAL_CONTENT,
+				Body::from_stream(ReaderStream::new(object.compat())),
+			)
 		}
-		return Ok(StatusCode::OK);
+		range::Requested::Unsatisfiable => return Ok(range::unsatisfiable(size)),
+	};
+
+	let mut response = Response::new(body);
+	*response.status_mut() = status;
+	*response.headers_mut() = headers;
+	if precompressed {
+		response.extensions_mut().insert(Precompressed);
 	}
 
-	Ok(StatusCode::CREATED)
+	Ok(response)
 }
 
+/// The headers `GET` would respond with, from the inventory rather than the
+/// object itself
 #[debug_handler]
-async fn get_object(
-	AxumPath(object_hash): AxumPath<Hash>,
-	State(ServerState { store, .. }): State<ServerState>,
-) -> Result<Response<Body>, (StatusCode, String)> {
-	match store.exists(&object_hash).await {
-		Ok(true) => Ok(()),
-		Ok(false) => Err((StatusCode::NO_CONTENT, "no object".into())),
-		Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-	}?;
-
-	let object = store
-		.get_object(&object_hash)
+async fn head_object(
+	AxumPath(ObjectPath {
+		object_id: object_hash,
+	}): AxumPath<ObjectPath>,
+	namespace: Namespace,
+	State(ServerState {
+		namespaces,
+		inventory,
+		..
+	}): State<ServerState>,
+) -> Result<HeaderMap, (StatusCode, String)> {
+	let key = object_key(&inventory, &namespaces, &namespace, &object_hash).await?;
+
+	let header = inventory
+		.header(&key)
 		.await
-		.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+		.map_err(internal_error)?
+		.ok_or_else(|| not_found(&object_hash))?;
 
-	let Header { object_type, size } = object.header;
-
-	let reader_stream = futures::stream::unfold(object, |mut reader| async move {
-		let mut buffer = vec![0u8; 8192];
-		match reader.read(&mut buffer).await {
-			Ok(0) => None,
-			Ok(n) => {
-				buffer.truncate(n);
-				Some((Ok(buffer), reader))
-			}
-			Err(e) => Some((Err(e), reader)),
-		}
-	});
-	let mut response = Response::new(Body::from_stream(reader_stream));
+	Ok(object_headers(&key, header))
+}
 
-	let headers = response.headers_mut();
-	headers.insert("Object-Type", object_type.to_str().parse().unwrap());
-	headers.insert("Object-Size", size.to_string().parse().unwrap());
+/// `304 Not Modified` with the validators and caching headers of the full
+/// response
+fn not_modified(mut headers: HeaderMap) -> Response<Body> {
+	headers.remove(header::CONTENT_LENGTH);
 
-	Ok(response)
+	let mut response = Response::new(Body::empty());
+	*response.status_mut() = StatusCode::NOT_MODIFIED;
+	*response.headers_mut() = headers;
+	response
 }
 
 #[debug_handler]
 async fn get_bundle(
-	AxumPath(index_hash): AxumPath<Hash>,
-	State(ServerState { store, config }): State<ServerState>,
+	AxumPath(BundlePath {
+		index_id: index_hash,
+	}): AxumPath<BundlePath>,
+	Query(query): Query<BundleQuery>,
+	namespace: Namespace,
+	State(ServerState {
+		store,
+		namespaces,
+		config,
+		inventory,
+		bundles,
+		..
+	}): State<ServerState>,
+	request_headers: HeaderMap,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-	match store.exists(&index_hash).await {
-		Ok(true) => Ok(()),
-		Ok(false) => Err((StatusCode::NO_CONTENT, "no object".into())),
-		Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-	}?;
+	let (compression, level) = config
+		.archive
+		.bundle_compression(query.compression.as_deref(), query.level.as_deref())
+		.map_err(|err| (StatusCode::BAD_REQUEST, err))?;
 
-	let mut object = store
-		.get_object(&index_hash)
-		.await
-		.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+	let mut object = find_object(&store, &inventory, &namespaces, &namespace, &index_hash).await?;
 
 	if object.header.object_type != ObjectType::Index {
 		return Err((
@@ -292,77 +599,383 @@ async fn get_bundle(
 		));
 	}
 
+	// Bundles are cached across namespaces, so this is checked even when the
+	// bundle has already been built
+	if !namespace.is_default() {
+		let mut data = Vec::new();
+		object
+			.read_to_end(&mut data)
+			.await
+			.map_err(internal_error)?;
+		let index = Index::parse(&data).map_err(|err| malformed(&index_hash, err))?;
+
+		if let Some(hidden) = namespaces
+			.hidden_in_tree(&store, &namespace.0, &index.tree)
+			.await
+			.map_err(walk_error)?
+		{
+			return Err(not_found(&hidden));
+		}
+	}
+
+	let key = BundleKey {
+		index: index_hash.clone(),
+		compression,
+		level,
+	};
+
+	let bundle = bundles
+		.get_or_build(&key, build_bundle(store, key.clone()))
+		.await?;
+
+	let mut response_headers = HeaderMap::new();
+	caching::set_immutable(&mut response_headers, &bundle.etag);
+	response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+	response_headers.insert(header::CONTENT_LENGTH, bundle.length.into());
+
+	if caching::is_not_modified(&request_headers, &bundle.etag) {
+		return Ok(not_modified(response_headers));
+	}
+
+	let length = bundle.length;
+	let (status, range) = match range::requested(&request_headers, &bundle.etag, length) {
+		range::Requested::Full => (StatusCode::OK, 0..length),
+		range::Requested::Partial(range) => {
+			range::set_partial(&mut response_headers, &range, length);
+			(StatusCode::PARTIAL_CONTENT, range)
+		}
+		range::Requested::Unsatisfiable => return Ok(range::unsatisfiable(length)),
+	};
+
+	let body = bundles.body(&bundle, range).await.map_err(internal_error)?;
+
+	let mut response = Response::new(body);
+	*response.status_mut() = status;
+	if compression != CompressionAlgorithm::None {
+		response.extensions_mut().insert(Precompressed);
+	}
+
+	let headers = response.headers_mut();
+	*headers = response_headers;
+	headers.insert(
+		"Content-Type",
+		HeaderValue::from_str("application/arc").unwrap(),
+	);
+	headers.insert(
+		"Content-Disposition",
+		HeaderValue::from_str(&format!("attachment; filename=\"{index_hash}.ar\"")).unwrap(),
+	);
+
+	Ok(response)
+}
+
+/// Build the bundle of an index, holding every object under its tree
+async fn build_bundle(store: Store, key: BundleKey) -> Result<Vec<u8>, (StatusCode, String)> {
+	let start = Instant::now();
+	let index_hash = key.index;
+
 	let mut index_data = Vec::new();
 
-	object
+	store
+		.get_object(&index_hash)
+		.await
+		.map_err(internal_error)?
 		.read_to_end(&mut index_data)
 		.await
-		.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+		.map_err(internal_error)?;
 
-	let index = Index::from_data(&index_data);
+	let index = Index::parse(&index_data).map_err(|err| malformed(&index_hash, err))?;
 
 	let mut headers = HashMap::new();
 
 	println!("Reading objects for index {}", index_hash);
 	read_object_into_headers(&store, &mut headers, &index.tree)
 		.await
-		.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+		.map_err(walk_error)?;
 	println!("Finished reading {} objects from index", headers.len());
 
 	let mut i = 0;
 	let mut header_entries: Vec<ArchiveHeaderEntry> = Vec::new();
-
-	for (hash, header) in &headers {
-		let prefix_length = header.to_string().len() as u64;
-		let total_length = header.size + prefix_length;
+	let mut entries: Vec<BundleEntry> = Vec::new();
+
+	// In a fixed order, so rebuilding a bundle gives the same bytes and
+	// interrupted downloads of it can be resumed
+	let mut sorted: Vec<_> = headers.iter().collect();
+	sorted.sort_unstable_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+	for (hash, header) in sorted {
+		// Deltas are passed through as-is when their base is also in the bundle
+		let delta = store
+			.get_delta(hash)
+			.await
+			.map_err(internal_error)?
+			.filter(|(base, _)| headers.contains_key(base));
+
+		let (entry, total_length) = match delta {
+			Some((_, data)) => {
+				let length = data.len() as u64;
+				(BundleEntry::Delta(RawEntryData::new(data)), length)
+			}
+			None => {
+				let prefix_length = header.to_string().len() as u64;
+				let entry = StoreEntryData {
+					store: store.clone(),
+					hash: hash.clone(),
+				};
+				(BundleEntry::Store(entry), header.size + prefix_length)
+			}
+		};
 
 		header_entries.push(ArchiveHeaderEntry {
 			hash: hash.clone(),
 			index: i,
 			length: total_length,
 		});
+		entries.push(entry);
 
 		i += total_length;
 	}
 
 	let archive = Archive {
 		header: HEADER,
-		compression: config.archive.compression_format,
+		compression: key.compression,
 		hash: index_hash.clone(),
 		index,
 		body: ArchiveBody {
 			header: header_entries,
-			entries: headers
-				.keys()
-				.map(|hash| StoreEntryData {
-					store: store.clone(),
-					hash: hash.clone(),
-				})
-				.collect(),
+			entries,
 		},
+		encryption: None,
 	};
 
 	let mut body = Vec::new();
 
 	archive
-		.to_data(config.archive.compression_level, &mut body)
-		.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+		.to_data(CompressionLevel::Exact(key.level), &mut body)
+		.map_err(internal_error)?;
 
-	let mut response = Response::new(Body::from(body));
-	let headers = response.headers_mut();
-	headers.insert(
-		"Content-Type",
-		HeaderValue::from_str("application/arc").unwrap(),
-	);
-	headers.insert(
-		"Content-Disposition",
-		HeaderValue::from_str(&format!(
-			"Content-Disposition: attachment; filename=\"{index_hash}.ar\""
-		))
-		.unwrap(),
-	);
+	telemetry::record_bundle(start.elapsed(), body.len());
 
-	Ok(response)
+	Ok(body)
+}
+
+#[derive(Serialize)]
+struct IndexListing {
+	hash: Hash,
+	timestamp: String,
+	metadata: HashMap<String, String>,
+}
+
+/// Indexes in the namespace, oldest first, filtered by
+/// `?meta.<key>=<value>&since=<timestamp>&limit=<count>`
+#[debug_handler]
+async fn list_indexes(
+	namespace: Namespace,
+	State(ServerState { catalog, .. }): State<ServerState>,
+	Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<Vec<IndexListing>>, (StatusCode, String)> {
+	let query = IndexQuery::from_params(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+	Ok(Json(index_listings(&catalog, &namespace, &query)))
+}
+
+/// Indexes uploaded to `namespace` matching `query`, oldest first, from the
+/// catalog held in memory
+fn index_listings(
+	catalog: &Catalog,
+	namespace: &Namespace,
+	query: &IndexQuery,
+) -> Vec<IndexListing> {
+	query
+		.apply(catalog.list(&namespace.0))
+		.into_iter()
+		.map(|summary| IndexListing {
+			hash: summary.hash,
+			timestamp: summary.timestamp.to_rfc3339(),
+			metadata: summary.metadata,
+		})
+		.collect()
+}
+
+#[debug_handler]
+async fn list_refs(
+	namespace: Namespace,
+	State(ServerState { namespaces, .. }): State<ServerState>,
+) -> Result<Json<BTreeMap<String, Hash>>, (StatusCode, String)> {
+	Ok(Json(
+		namespaces
+			.refs(&namespace.0)
+			.await
+			.map_err(internal_error)?,
+	))
+}
+
+#[debug_handler]
+async fn get_ref(
+	AxumPath(RefPath { name }): AxumPath<RefPath>,
+	namespace: Namespace,
+	State(ServerState { namespaces, .. }): State<ServerState>,
+) -> Result<String, (StatusCode, String)> {
+	match namespaces.get_ref(&namespace.0, &name).await {
+		Ok(Some(hash)) => Ok(hash.to_string()),
+		Ok(None) => Err((StatusCode::NOT_FOUND, format!("No ref named {name}"))),
+		Err(err) => Err(internal_error(err)),
+	}
+}
+
+/// Point a ref at an index. The body is the hash of an index previously
+/// uploaded to the same namespace.
+#[debug_handler]
+async fn put_ref(
+	AxumPath(RefPath { name }): AxumPath<RefPath>,
+	namespace: Namespace,
+	State(ServerState {
+		store,
+		namespaces,
+		config,
+		trusted_keys,
+		..
+	}): State<ServerState>,
+	body: String,
+) -> Result<StatusCode, (StatusCode, String)> {
+	if !is_valid_ref(&name) {
+		return Err((StatusCode::BAD_REQUEST, format!("Invalid ref name {name}")));
+	}
+
+	let Ok(hash) = Hash::try_from(body.trim()) else {
+		return Err((StatusCode::BAD_REQUEST, "Body is not a valid hash".into()));
+	};
+
+	if !namespaces
+		.contains_index(&namespace.0, &hash)
+		.await
+		.map_err(internal_error)?
+	{
+		return Err((
+			StatusCode::BAD_REQUEST,
+			format!("{hash} is not an index in namespace {}", namespace.0),
+		));
+	}
+
+	if config.signatures.is_protected(&name) {
+		let mut data = Vec::new();
+		store
+			.get_object(&hash)
+			.await
+			.map_err(internal_error)?
+			.read_to_end(&mut data)
+			.await
+			.map_err(internal_error)?;
+
+		let index = Index::parse(&data).map_err(|err| malformed(&hash, err))?;
+		if let Err(err) = verify_index(&index, &trusted_keys) {
+			return Err((
+				StatusCode::FORBIDDEN,
+				format!("Ref {name} only accepts signed indexes: {err}"),
+			));
+		}
+	}
+
+	namespaces
+		.set_ref(&namespace.0, &name, &hash)
+		.await
+		.map_err(internal_error)?;
+
+	Ok(StatusCode::OK)
+}
+
+#[debug_handler]
+async fn get_usage(
+	namespace: Namespace,
+	State(ServerState { namespaces, .. }): State<ServerState>,
+) -> Result<Json<Usage>, (StatusCode, String)> {
+	Ok(Json(
+		namespaces
+			.usage(&namespace.0)
+			.await
+			.map_err(internal_error)?,
+	))
+}
+
+#[derive(Serialize)]
+struct AccountUsage {
+	#[serde(flatten)]
+	usage: Usage,
+	#[serde(flatten)]
+	quota: Quota,
+}
+
+#[derive(Serialize)]
+struct ServerUsage {
+	namespaces: BTreeMap<String, AccountUsage>,
+	tokens: BTreeMap<String, AccountUsage>,
+}
+
+/// Usage and quotas of every namespace and token that has uploaded objects
+#[debug_handler]
+async fn admin_usage(
+	State(ServerState {
+		namespaces, config, ..
+	}): State<ServerState>,
+) -> Result<Json<ServerUsage>, (StatusCode, String)> {
+	let mut usage = ServerUsage {
+		namespaces: BTreeMap::new(),
+		tokens: BTreeMap::new(),
+	};
+
+	for name in namespaces.names().await.map_err(internal_error)? {
+		let account = AccountUsage {
+			usage: namespaces.usage(&name).await.map_err(internal_error)?,
+			quota: config.quotas.namespace(&name),
+		};
+		usage.namespaces.insert(name, account);
+	}
+
+	for subject in namespaces.subjects().await.map_err(internal_error)? {
+		let account = AccountUsage {
+			usage: namespaces
+				.subject_usage(&subject)
+				.await
+				.map_err(internal_error)?,
+			quota: config.quotas.token(&subject),
+		};
+		usage.tokens.insert(subject, account);
+	}
+
+	Ok(Json(usage))
+}
+
+/// Routes served both at the root, for the default namespace, and under
+/// `/ns/{namespace}`
+fn api_routes() -> Router<ServerState> {
+	Router::new()
+		.route(
+			"/object/{object_id}",
+			get(get_object).head(head_object).put(put_object),
+		)
+		.route("/bundle/{index_id}", get(get_bundle))
+		.route("/indexes", get(list_indexes))
+		.route("/refs", get(list_refs))
+		.route("/refs/{*name}", get(get_ref).put(put_ref))
+		.route("/usage", get(get_usage))
+		.merge(browse::routes())
+		.merge(ui::routes())
+}
+
+/// Bundle entry: either an object read in full from the store, or a delta
+/// entry copied straight out of a pack.
+enum BundleEntry {
+	Store(StoreEntryData),
+	Delta(RawEntryData),
+}
+
+impl ArchiveEntryData for BundleEntry {
+	fn turn_into_vec(self) -> Vec<u8> {
+		match self {
+			BundleEntry::Store(data) => data.turn_into_vec(),
+			BundleEntry::Delta(data) => data.turn_into_vec(),
+		}
+	}
 }
 
 #[derive(Parser)]
@@ -389,6 +1002,23 @@ pub struct Cli {
 	/// Path to the object store directory (filesystem backend). Overrides the
 	/// config file. Required unless --config specifies a `[store]` section.
 	pub store: Option<PathBuf>,
+
+	/// Consolidate all loose objects in the store into a pack file and exit
+	#[arg(long)]
+	pub repack: bool,
+
+	/// Print a token for SUBJECT signed with the configured HMAC secret and exit
+	#[arg(long, value_name = "SUBJECT")]
+	pub issue_token: Option<String>,
+
+	/// Scope granted by an issued token, optionally limited to a namespace as
+	/// `read:<namespace>`. Can be used multiple times
+	#[arg(long, requires = "issue_token", default_value = "read")]
+	pub scope: Vec<Grant>,
+
+	/// How long an issued token is valid for, in seconds
+	#[arg(long, requires = "issue_token", default_value_t = 30 * 24 * 60 * 60)]
+	pub token_ttl: u64,
 }
 
 #[tokio::main]
@@ -398,7 +1028,24 @@ async fn main() -> anyhow::Result<()> {
 
 	configure_tracing(&config.logging);
 
+	if let Some(subject) = &args.issue_token {
+		let Some(secret) = &config.auth.hmac_secret else {
+			anyhow::bail!("issuing tokens requires [auth] hmac_secret to be configured");
+		};
+
+		let token = issue_token(
+			secret.as_bytes(),
+			subject,
+			&args.scope,
+			Duration::from_secs(args.token_ttl),
+		)?;
+		println!("{token}");
+		return Ok(());
+	}
+
 	let bind = config.server.bind;
+	let tls = config.server.tls.clone();
+	let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout);
 
 	let store_root: PathBuf = match &config.store {
         Some(StoreConfig::Fs { root }) => PathBuf::from(root),
@@ -412,32 +1059,126 @@ async fn main() -> anyhow::Result<()> {
 	}
 
 	let store = opendal::services::Fs::default().root(store_root.to_str().expect("valid path"));
+	let store = Store::from_builder(store)?.with_compression(config.objects.zstd_level()?);
+	let namespaces = Namespaces::new(store.operator().clone());
+
+	let trusted_keys = match &config.signatures.trusted_keys {
+		Some(path) => read_trusted_keys(path)?,
+		None => Vec::new(),
+	};
+	if !config.signatures.protected_refs.is_empty() && trusted_keys.is_empty() {
+		anyhow::bail!("[signatures] protected_refs requires trusted_keys to list at least one key");
+	}
+
+	if args.repack {
+		match store.repack().await? {
+			Some(summary) => tracing::info!(
+				"Packed {} objects ({} as deltas, {} bytes) into pack {}",
+				summary.objects,
+				summary.deltas,
+				summary.bytes,
+				summary.name
+			),
+			None => tracing::info!("No loose objects to pack"),
+		}
+		return Ok(());
+	}
 
-	// read_cache(&store).await;
+	let inventory = Inventory::new(store.clone());
+	match inventory.load_snapshot().await {
+		Ok(loaded) => tracing::info!("Loaded {loaded} objects from the inventory snapshot"),
+		Err(err) => tracing::warn!("Unable to load the inventory snapshot: {err:#}"),
+	}
+	inventory
+		.clone()
+		.spawn_resync(Duration::from_secs(config.inventory.resync_interval));
+
+	let bundles = BundleCache::new(store.operator().clone(), config.archive.cache_size);
+	match bundles.load().await {
+		Ok(0) => {}
+		Ok(loaded) => tracing::info!("Found {loaded} cached bundles"),
+		Err(err) => tracing::warn!("Unable to load the bundle cache: {err:#}"),
+	}
+
+	let catalog = Catalog::new(store.clone(), namespaces.clone());
+	if let Err(err) = catalog.refresh().await {
+		tracing::warn!("Unable to load the index catalog: {err:#}");
+	}
+	catalog
+		.clone()
+		.spawn_refresh(Duration::from_secs(config.catalog.refresh_interval));
 
-	let comression_layer: CompressionLayer = CompressionLayer::new()
-		.br(true)
-		.deflate(true)
-		.gzip(true)
-		.zstd(true);
+	let metrics = telemetry::install()?;
+	let shutting_down = Arc::new(AtomicBool::new(false));
+	let health = health::routes(store.operator().clone(), shutting_down.clone());
+
+	let auth = Arc::new(Auth::new(&config.auth));
+	if !auth.is_enabled() {
+		tracing::warn!("No auth tokens configured, anyone can upload objects");
+	}
 
 	// build our application with a single route
 	let app = Router::new()
-		.route("/object/{object_id}", put(put_object))
-		.route("/object/{object_id}", get(get_object))
-		.route("/bundle/{index_id}", get(get_bundle))
+		.merge(api_routes())
+		.nest("/ns/{namespace}", api_routes())
+		.route("/admin/usage", get(admin_usage))
+		.route(
+			"/metrics",
+			get(move || std::future::ready(metrics.render())),
+		)
 		.with_state(ServerState {
-			store: Store::from_builder(store).expect("S"),
+			store,
+			namespaces,
 			config,
+			trusted_keys: Arc::new(trusted_keys),
+			catalog,
+			inventory,
+			bundles,
 		})
-		.layer(comression_layer)
+		.layer(middleware::from_fn_with_state(auth, require_auth))
+		.layer(middleware::from_fn(telemetry::track_requests))
+		.layer(compression::layer())
 		.layer(TraceLayer::new_for_http())
 		.layer(DefaultBodyLimit::disable())
-		.route("/", get(|| async { "Hello, World!" }));
+		.route("/", get(|| async { "Hello, World!" }))
+		.merge(health);
+
+	let handle = Handle::new();
+	tokio::spawn(health::shutdown_on_signal(
+		handle.clone(),
+		shutting_down,
+		shutdown_timeout,
+	));
+
+	let scheme = if tls.is_some() { "https" } else { "http" };
+	tokio::spawn({
+		let handle = handle.clone();
+		async move {
+			if let Some(address) = handle.listening().await {
+				tracing::info!("Listening at {scheme}://{address}");
+			}
+		}
+	});
+
+	match tls {
+		None => {
+			axum_server::bind(bind)
+				.handle(handle)
+				.serve(app.into_make_service())
+				.await?
+		}
+		Some(tls) => {
+			let rustls = RustlsConfig::from_config(tls::server_config(&tls)?);
+			tokio::spawn(tls::reload_on_hangup(tls, rustls.clone()));
+
+			axum_server::bind_rustls(bind, rustls)
+				.handle(handle)
+				.serve(app.into_make_service())
+				.await?
+		}
+	}
 
-	let listener = tokio::net::TcpListener::bind(bind).await?;
+	tracing::info!("Shut down");
 
-	tracing::info!("Listening at http://{}", listener.local_addr()?);
-	axum::serve(listener, app).await?;
 	Ok(())
 }
@@ -0,0 +1,578 @@
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+	extract::{FromRequestParts, RawPathParams},
+	http::{request::Parts, StatusCode},
+};
+use common::{read_object_into_headers, store::Store, Hash, Header, ObjectType};
+use futures::{future::ready, stream, StreamExt, TryStreamExt};
+use opendal::{ErrorKind, Operator};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+/// Namespace used by requests that don't go through `/ns/{name}/...`
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Directory (relative to the store root) holding namespace membership and refs
+const NAMESPACE_DIR: &str = "ns/";
+
+/// Directory (relative to the store root) holding the objects uploaded by each
+/// token subject, named by the hex encoded subject
+const SUBJECT_DIR: &str = "subjects/";
+
+/// Namespace names are lowercase so they are safe to use as path segments on
+/// every storage backend.
+pub fn is_valid_name(name: &str) -> bool {
+	let mut chars = name.chars();
+
+	matches!(chars.next(), Some('a'..='z' | '0'..='9'))
+		&& name.len() <= 64
+		&& chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_'))
+}
+
+/// Ref names are `/` separated segments such as `releases/v1.2`
+pub fn is_valid_ref(name: &str) -> bool {
+	name.len() <= 255
+		&& name.split('/').all(|segment| {
+			!segment.is_empty()
+				&& segment != "."
+				&& segment != ".."
+				&& segment
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+		})
+}
+
+/// Namespace a request path addresses, without checking the name is valid
+pub fn namespace_from_path(path: &str) -> &str {
+	path.strip_prefix("/ns/")
+		.and_then(|rest| rest.split('/').next())
+		.unwrap_or(DEFAULT_NAMESPACE)
+}
+
+/// Objects referenced by a namespace and the sum of their sizes. Objects are
+/// counted once per namespace that uploaded them, even though the store only
+/// holds a single copy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Usage {
+	pub objects: u64,
+	pub bytes: u64,
+}
+
+/// Tracks which objects of the shared store belong to each namespace, along
+/// with the namespace's refs and the objects each token subject uploaded.
+///
+/// Membership is recorded with a marker per object at
+/// `ns/{namespace}/objects/{hash}` holding the object's size, and a second
+/// marker at `ns/{namespace}/indexes/{hash}` for indexes. Refs are stored at
+/// `ns/{namespace}/refs/{name}` and contain the hash of an index. Uploads by
+/// a token subject are recorded the same way at `subjects/{hex subject}/{hash}`.
+#[derive(Clone)]
+pub struct Namespaces {
+	operator: Operator,
+	/// Usage of the marker directories that have been summed so far, kept up
+	/// to date as objects are added. The lock also serialises marker changes.
+	usage: Arc<Mutex<HashMap<String, Usage>>>,
+	/// Trees each namespace has been found to see every object under.
+	/// Membership is never removed, so these stay true.
+	visible_trees: Arc<Mutex<HashSet<(String, Hash)>>>,
+}
+
+/// How many membership markers are looked up at once when checking a tree
+const VISIBILITY_CONCURRENCY: usize = 32;
+
+impl Namespaces {
+	pub fn new(operator: Operator) -> Self {
+		Self {
+			operator,
+			usage: Default::default(),
+			visible_trees: Default::default(),
+		}
+	}
+
+	pub async fn contains(&self, namespace: &str, hash: &Hash) -> Result<bool> {
+		Ok(self.operator.exists(&object_path(namespace, hash)).await?)
+	}
+
+	/// The first object under `tree`, the tree included, that `namespace`
+	/// doesn't reference, or `None` if it can see them all. Knowing an
+	/// index's hash only gives a namespace the objects it has itself.
+	pub async fn hidden_in_tree(
+		&self,
+		store: &Store,
+		namespace: &str,
+		tree: &Hash,
+	) -> Result<Option<Hash>> {
+		let key = (namespace.to_string(), tree.clone());
+		if self.visible_trees.lock().await.contains(&key) {
+			return Ok(None);
+		}
+
+		let mut headers = HashMap::new();
+		read_object_into_headers(store, &mut headers, tree).await?;
+
+		let hidden = stream::iter(headers.into_keys())
+			.map(|hash| async move {
+				let visible = self.contains(namespace, &hash).await?;
+				anyhow::Ok((!visible).then_some(hash))
+			})
+			.buffer_unordered(VISIBILITY_CONCURRENCY)
+			.try_filter_map(|hidden| ready(Ok(hidden)))
+			.try_next()
+			.await?;
+
+		if hidden.is_none() {
+			self.visible_trees.lock().await.insert(key);
+		}
+
+		Ok(hidden)
+	}
+
+	pub async fn contains_index(&self, namespace: &str, hash: &Hash) -> Result<bool> {
+		Ok(self.operator.exists(&index_path(namespace, hash)).await?)
+	}
+
+	pub async fn subject_contains(&self, subject: &str, hash: &Hash) -> Result<bool> {
+		Ok(self.operator.exists(&subject_path(subject, hash)).await?)
+	}
+
+	/// Record that `namespace` references the object. Returns `false` if it
+	/// already did.
+	pub async fn add_object(&self, namespace: &str, hash: &Hash, header: &Header) -> Result<bool> {
+		if header.object_type == ObjectType::Index {
+			self.add_index(namespace, hash).await?;
+		}
+
+		self.add_marker(&objects_dir(namespace), hash, header.size)
+			.await
+	}
+
+	/// Record that `namespace` lists the index, without counting it as one of
+	/// the namespace's objects
+	pub async fn add_index(&self, namespace: &str, hash: &Hash) -> Result<()> {
+		self.operator
+			.write(&index_path(namespace, hash), Vec::<u8>::new())
+			.await?;
+
+		Ok(())
+	}
+
+	/// Record that the token `subject` uploaded the object. Returns `false` if
+	/// it already had.
+	pub async fn add_upload(&self, subject: &str, hash: &Hash, header: &Header) -> Result<bool> {
+		self.add_marker(&subject_dir(subject), hash, header.size)
+			.await
+	}
+
+	pub async fn usage(&self, namespace: &str) -> Result<Usage> {
+		self.sum_markers(&objects_dir(namespace)).await
+	}
+
+	pub async fn subject_usage(&self, subject: &str) -> Result<Usage> {
+		self.sum_markers(&subject_dir(subject)).await
+	}
+
+	/// Names of every namespace that has had an object uploaded to it
+	pub async fn names(&self) -> Result<Vec<String>> {
+		Ok(self
+			.list_dirs(NAMESPACE_DIR)
+			.await?
+			.into_iter()
+			.filter(|name| is_valid_name(name))
+			.collect())
+	}
+
+	/// Subjects of every token that has uploaded an object
+	pub async fn subjects(&self) -> Result<Vec<String>> {
+		Ok(self
+			.list_dirs(SUBJECT_DIR)
+			.await?
+			.into_iter()
+			.filter_map(|name| String::from_utf8(hex::decode(name).ok()?).ok())
+			.collect())
+	}
+
+	async fn add_marker(&self, dir: &str, hash: &Hash, size: u64) -> Result<bool> {
+		let mut usage = self.usage.lock().await;
+
+		let path = format!("{dir}{hash}");
+		if self.operator.exists(&path).await? {
+			return Ok(false);
+		}
+
+		self.operator.write(&path, size.to_string()).await?;
+
+		if let Some(usage) = usage.get_mut(dir) {
+			usage.objects += 1;
+			usage.bytes += size;
+		}
+
+		Ok(true)
+	}
+
+	async fn sum_markers(&self, dir: &str) -> Result<Usage> {
+		let mut usage = self.usage.lock().await;
+
+		if let Some(usage) = usage.get(dir) {
+			return Ok(*usage);
+		}
+
+		let mut total = Usage::default();
+		for path in self.list(dir).await? {
+			let size = self.operator.read(&path).await?.to_vec();
+			let size: u64 = std::str::from_utf8(&size)?
+				.parse()
+				.map_err(|_| anyhow!("Invalid object marker {path}"))?;
+
+			total.objects += 1;
+			total.bytes += size;
+		}
+
+		usage.insert(dir.to_string(), total);
+
+		Ok(total)
+	}
+
+	/// Hashes of every index uploaded to `namespace`
+	pub async fn indexes(&self, namespace: &str) -> Result<Vec<Hash>> {
+		let prefix = format!("{NAMESPACE_DIR}{namespace}/indexes/");
+
+		Ok(self
+			.list(&prefix)
+			.await?
+			.iter()
+			.filter_map(|path| Hash::try_from(&path[prefix.len()..]).ok())
+			.collect())
+	}
+
+	pub async fn refs(&self, namespace: &str) -> Result<BTreeMap<String, Hash>> {
+		let prefix = format!("{NAMESPACE_DIR}{namespace}/refs/");
+
+		let mut refs = BTreeMap::new();
+		for path in self.list(&prefix).await? {
+			let name = &path[prefix.len()..];
+			if let Some(hash) = self.get_ref(namespace, name).await? {
+				refs.insert(name.to_string(), hash);
+			}
+		}
+
+		Ok(refs)
+	}
+
+	pub async fn get_ref(&self, namespace: &str, name: &str) -> Result<Option<Hash>> {
+		let data = match self.operator.read(&ref_path(namespace, name)).await {
+			Ok(data) => data.to_vec(),
+			Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+
+		Ok(Some(Hash::try_from(std::str::from_utf8(&data)?.trim())?))
+	}
+
+	pub async fn set_ref(&self, namespace: &str, name: &str, hash: &Hash) -> Result<()> {
+		self.operator
+			.write(&ref_path(namespace, name), hash.as_str().to_string())
+			.await?;
+
+		Ok(())
+	}
+
+	/// Paths of every file below `prefix`
+	async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+		let mut paths = Vec::new();
+
+		let mut lister = self.operator.lister_with(prefix).recursive(true).await?;
+		while let Some(entry) = lister.try_next().await? {
+			if entry.metadata().is_file() {
+				paths.push(entry.path().to_string());
+			}
+		}
+
+		Ok(paths)
+	}
+
+	/// Names of the directories directly below `prefix`
+	async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+		let mut names = Vec::new();
+
+		let mut lister = self.operator.lister(prefix).await?;
+		while let Some(entry) = lister.try_next().await? {
+			if entry.metadata().is_dir() && entry.path() != prefix {
+				names.push(entry.name().trim_end_matches('/').to_string());
+			}
+		}
+
+		Ok(names)
+	}
+}
+
+fn objects_dir(namespace: &str) -> String {
+	format!("{NAMESPACE_DIR}{namespace}/objects/")
+}
+
+fn object_path(namespace: &str, hash: &Hash) -> String {
+	format!("{}{hash}", objects_dir(namespace))
+}
+
+/// Subjects are free form, so they are hex encoded to be safe as a path
+fn subject_dir(subject: &str) -> String {
+	format!("{SUBJECT_DIR}{}/", hex::encode(subject))
+}
+
+fn subject_path(subject: &str, hash: &Hash) -> String {
+	format!("{}{hash}", subject_dir(subject))
+}
+
+fn index_path(namespace: &str, hash: &Hash) -> String {
+	format!("{NAMESPACE_DIR}{namespace}/indexes/{hash}")
+}
+
+fn ref_path(namespace: &str, name: &str) -> String {
+	format!("{NAMESPACE_DIR}{namespace}/refs/{name}")
+}
+
+/// Namespace a request is scoped to, taken from the `/ns/{namespace}` prefix
+pub struct Namespace(pub String);
+
+impl Namespace {
+	pub fn is_default(&self) -> bool {
+		self.0 == DEFAULT_NAMESPACE
+	}
+
+	/// Key an encrypted object uploaded as `hash` is stored under. Its body
+	/// can't be checked against `hash`, so it's kept apart from objects that
+	/// can be, and from other namespaces' copies, rather than claiming `hash`
+	/// before the real object is uploaded.
+	pub fn encrypted_key(&self, hash: &Hash) -> Hash {
+		let mut hasher = Sha256::new();
+		hasher.update(b"encrypted ");
+		hasher.update(self.0.as_bytes());
+		hasher.update(b"\0");
+		hasher.update(hash.as_str());
+		Hash::from(hasher)
+	}
+}
+
+impl<S> FromRequestParts<S> for Namespace
+where
+	S: Send + Sync,
+{
+	type Rejection = (StatusCode, String);
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let params = RawPathParams::from_request_parts(parts, state)
+			.await
+			.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+		let name = params
+			.iter()
+			.find(|(key, _)| *key == "namespace")
+			.map(|(_, value)| value)
+			.unwrap_or(DEFAULT_NAMESPACE);
+
+		if !is_valid_name(name) {
+			return Err((
+				StatusCode::BAD_REQUEST,
+				format!("Invalid namespace \"{name}\""),
+			));
+		}
+
+		Ok(Namespace(name.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn namespaces() -> Namespaces {
+		Namespaces::new(
+			Operator::new(opendal::services::Memory::default())
+				.unwrap()
+				.finish(),
+		)
+	}
+
+	fn hash(byte: u8) -> Hash {
+		Hash::from([byte; 32])
+	}
+
+	#[test]
+	fn names_are_validated() {
+		assert!(is_valid_name("team-a"));
+		assert!(is_valid_name("0_ci"));
+		assert!(!is_valid_name(""));
+		assert!(!is_valid_name("-team"));
+		assert!(!is_valid_name("Team"));
+		assert!(!is_valid_name("a/b"));
+		assert!(!is_valid_name(&"a".repeat(65)));
+
+		assert!(is_valid_ref("releases/v1.2"));
+		assert!(!is_valid_ref("releases//v1"));
+		assert!(!is_valid_ref("../escape"));
+	}
+
+	#[test]
+	fn encrypted_objects_are_kept_apart_per_namespace() {
+		let team = Namespace("team".into());
+		let other = Namespace("other".into());
+
+		assert_ne!(team.encrypted_key(&hash(1)), hash(1));
+		assert_ne!(team.encrypted_key(&hash(1)), other.encrypted_key(&hash(1)));
+		assert_ne!(team.encrypted_key(&hash(1)), team.encrypted_key(&hash(2)));
+	}
+
+	#[test]
+	fn namespace_is_taken_from_the_path() {
+		assert_eq!(namespace_from_path("/ns/team/object/abc"), "team");
+		assert_eq!(namespace_from_path("/object/abc"), DEFAULT_NAMESPACE);
+	}
+
+	#[tokio::test]
+	async fn trees_are_only_visible_with_every_object_under_them() {
+		use common::{
+			object_body::{Object, Tree, TreeEntry},
+			store::StoreObject,
+			Mode,
+		};
+
+		let store = Store::from_builder(opendal::services::Memory::default()).unwrap();
+		let namespaces = Namespaces::new(store.operator().clone());
+		let put = |hash: Hash, object_type: ObjectType, body: Vec<u8>| {
+			let store = store.clone();
+			async move {
+				let header = Header::new(object_type, body.len() as u64);
+				store
+					.put_object(
+						&hash,
+						StoreObject::new_with_header(header, futures::io::Cursor::new(body)),
+					)
+					.await
+					.unwrap();
+				header
+			}
+		};
+
+		let blob = put(hash(1), ObjectType::Blob, b"secret".to_vec()).await;
+		let tree = Tree {
+			contents: vec![TreeEntry {
+				mode: Mode::Normal,
+				path: "secret.txt".into(),
+				hash: hash(1),
+			}],
+		};
+		let tree = put(hash(2), ObjectType::Tree, tree.to_data()).await;
+
+		namespaces
+			.add_object("team", &hash(2), &tree)
+			.await
+			.unwrap();
+		assert_eq!(
+			namespaces
+				.hidden_in_tree(&store, "team", &hash(2))
+				.await
+				.unwrap(),
+			Some(hash(1))
+		);
+
+		namespaces
+			.add_object("team", &hash(1), &blob)
+			.await
+			.unwrap();
+		assert_eq!(
+			namespaces
+				.hidden_in_tree(&store, "team", &hash(2))
+				.await
+				.unwrap(),
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn usage_counts_each_object_once_per_namespace() {
+		let namespaces = namespaces();
+		let blob = Header::new(ObjectType::Blob, 100);
+		let index = Header::new(ObjectType::Index, 10);
+
+		assert!(namespaces.add_object("a", &hash(1), &blob).await.unwrap());
+		assert!(!namespaces.add_object("a", &hash(1), &blob).await.unwrap());
+		assert!(namespaces.add_object("b", &hash(1), &blob).await.unwrap());
+
+		let usage = namespaces.usage("a").await.unwrap();
+		assert_eq!(
+			usage,
+			Usage {
+				objects: 1,
+				bytes: 100
+			}
+		);
+
+		namespaces.add_object("a", &hash(2), &index).await.unwrap();
+		let usage = namespaces.usage("a").await.unwrap();
+		assert_eq!(
+			usage,
+			Usage {
+				objects: 2,
+				bytes: 110
+			}
+		);
+
+		assert_eq!(namespaces.indexes("a").await.unwrap(), vec![hash(2)]);
+		assert!(namespaces.indexes("b").await.unwrap().is_empty());
+		assert!(!namespaces.contains("c", &hash(1)).await.unwrap());
+
+		let mut names = namespaces.names().await.unwrap();
+		names.sort();
+		assert_eq!(names, vec!["a", "b"]);
+	}
+
+	#[tokio::test]
+	async fn uploads_are_tracked_per_subject() {
+		let namespaces = namespaces();
+		let blob = Header::new(ObjectType::Blob, 100);
+
+		namespaces
+			.add_upload("ci/main", &hash(1), &blob)
+			.await
+			.unwrap();
+		assert!(!namespaces
+			.add_upload("ci/main", &hash(1), &blob)
+			.await
+			.unwrap());
+
+		assert_eq!(
+			namespaces.subject_usage("ci/main").await.unwrap(),
+			Usage {
+				objects: 1,
+				bytes: 100
+			}
+		);
+		assert_eq!(namespaces.subjects().await.unwrap(), vec!["ci/main"]);
+		assert_eq!(namespaces.usage("ci").await.unwrap(), Usage::default());
+	}
+
+	#[tokio::test]
+	async fn refs_are_scoped_to_their_namespace() {
+		let namespaces = namespaces();
+
+		namespaces
+			.set_ref("a", "releases/v1", &hash(3))
+			.await
+			.unwrap();
+
+		assert_eq!(
+			namespaces.get_ref("a", "releases/v1").await.unwrap(),
+			Some(hash(3))
+		);
+		assert_eq!(namespaces.get_ref("b", "releases/v1").await.unwrap(), None);
+		assert_eq!(
+			namespaces.refs("a").await.unwrap(),
+			BTreeMap::from([("releases/v1".to_string(), hash(3))])
+		);
+	}
+}
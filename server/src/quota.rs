@@ -0,0 +1,145 @@
+use axum::http::StatusCode;
+use common::Hash;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::QuotasConfig, namespace::Namespaces};
+
+/// Limits on the logical bytes uploaded to a namespace or by a token.
+///
+/// Uploads that take usage past the soft limit are accepted with a warning,
+/// while uploads that would take it past the hard limit are rejected.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Quota {
+	pub soft_limit: Option<u64>,
+	pub hard_limit: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuotaCheck {
+	Within,
+	/// Accepted, but usage is now over the soft limit
+	OverSoft {
+		limit: u64,
+	},
+	/// Would take usage over the hard limit
+	OverHard {
+		limit: u64,
+	},
+	/// Larger than the hard limit on its own, so can never be accepted
+	TooLarge {
+		limit: u64,
+	},
+}
+
+impl Quota {
+	/// Check whether `size` more bytes fit on top of `used`
+	pub fn check(&self, used: u64, size: u64) -> QuotaCheck {
+		let total = used.saturating_add(size);
+
+		match (self.soft_limit, self.hard_limit) {
+			(_, Some(limit)) if size > limit => QuotaCheck::TooLarge { limit },
+			(_, Some(limit)) if total > limit => QuotaCheck::OverHard { limit },
+			(Some(limit), _) if total > limit => QuotaCheck::OverSoft { limit },
+			_ => QuotaCheck::Within,
+		}
+	}
+}
+
+/// Check an upload of `size` bytes to `namespace` by the token `subject`
+/// against both of their quotas. Objects already counted against a quota
+/// aren't counted again. Returns a warning for each soft limit the upload goes
+/// over, or the response to reject it with.
+///
+/// Concurrent uploads are checked independently, so usage can overshoot a
+/// hard limit by the uploads in flight when it was reached.
+///
+/// Usage only counts objects recorded against the namespace or token, not
+/// the objects reachable from the trees they upload. A namespace can only
+/// read objects recorded against it, so nothing it can pull goes uncounted.
+pub async fn check_upload(
+	namespaces: &Namespaces,
+	quotas: &QuotasConfig,
+	namespace: &str,
+	subject: Option<&str>,
+	hash: &Hash,
+	size: u64,
+) -> Result<Vec<String>, (StatusCode, String)> {
+	let internal_error = |err: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+
+	let mut checks = Vec::new();
+
+	let quota = quotas.namespace(namespace);
+	if quota != Quota::default()
+		&& !namespaces
+			.contains(namespace, hash)
+			.await
+			.map_err(internal_error)?
+	{
+		let used = namespaces.usage(namespace).await.map_err(internal_error)?;
+		checks.push((format!("namespace {namespace}"), quota, used.bytes));
+	}
+
+	if let Some(subject) = subject {
+		let quota = quotas.token(subject);
+		if quota != Quota::default()
+			&& !namespaces
+				.subject_contains(subject, hash)
+				.await
+				.map_err(internal_error)?
+		{
+			let used = namespaces
+				.subject_usage(subject)
+				.await
+				.map_err(internal_error)?;
+			checks.push((format!("token {subject}"), quota, used.bytes));
+		}
+	}
+
+	let mut warnings = Vec::new();
+	for (owner, quota, used) in checks {
+		match quota.check(used, size) {
+			QuotaCheck::Within => {}
+			QuotaCheck::OverSoft { limit } => warnings.push(format!(
+				"{owner} is over its soft quota: {} of {limit} bytes used",
+				used + size
+			)),
+			QuotaCheck::OverHard { limit } => {
+				return Err((
+					StatusCode::INSUFFICIENT_STORAGE,
+					format!(
+						"Quota of {owner} exceeded: {used} of {limit} bytes used, object needs {size} more"
+					),
+				))
+			}
+			QuotaCheck::TooLarge { limit } => {
+				return Err((
+					StatusCode::PAYLOAD_TOO_LARGE,
+					format!(
+						"Object of {size} bytes is larger than the {limit} byte quota of {owner}"
+					),
+				))
+			}
+		}
+	}
+
+	Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn limits_are_checked_against_the_new_total() {
+		let quota = Quota {
+			soft_limit: Some(100),
+			hard_limit: Some(200),
+		};
+
+		assert_eq!(quota.check(0, 100), QuotaCheck::Within);
+		assert_eq!(quota.check(50, 100), QuotaCheck::OverSoft { limit: 100 });
+		assert_eq!(quota.check(150, 100), QuotaCheck::OverHard { limit: 200 });
+		assert_eq!(quota.check(0, 201), QuotaCheck::TooLarge { limit: 200 });
+		assert_eq!(Quota::default().check(u64::MAX, 1), QuotaCheck::Within);
+	}
+}
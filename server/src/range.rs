@@ -0,0 +1,137 @@
+use std::ops::Range;
+
+use axum::{
+	body::Body,
+	http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+};
+
+/// How much of a body to send in response to a request's `Range` header
+#[derive(Debug, PartialEq, Eq)]
+pub enum Requested {
+	Full,
+	Partial(Range<u64>),
+	Unsatisfiable,
+}
+
+/// The part of a body of `length` bytes, validated by `etag`, a request asks
+/// for.
+///
+/// Only a single range in bytes is served. Anything else is answered with the
+/// full body, as is a range with an `If-Range` that doesn't strongly match
+/// `etag`, since the client's partial copy would then be of other bytes.
+pub fn requested(request: &HeaderMap, etag: &str, length: u64) -> Requested {
+	let Some(range) = request.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+		return Requested::Full;
+	};
+
+	if let Some(if_range) = request.get(header::IF_RANGE) {
+		if etag.starts_with("W/") || if_range.as_bytes() != etag.as_bytes() {
+			return Requested::Full;
+		}
+	}
+
+	match parse(range, length) {
+		None => Requested::Full,
+		Some(None) => Requested::Unsatisfiable,
+		Some(Some(range)) => Requested::Partial(range),
+	}
+}
+
+/// Parse `bytes=<first>-<last>`, `bytes=<first>-` or `bytes=-<suffix length>`
+/// against a body of `length` bytes. `None` when the header isn't a single
+/// range, and `Some(None)` when the range lies outside of the body.
+fn parse(range: &str, length: u64) -> Option<Option<Range<u64>>> {
+	let (first, last) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+
+	let range = match (first, last) {
+		("", suffix) => {
+			let suffix: u64 = suffix.parse().ok()?;
+			length.saturating_sub(suffix)..length
+		}
+		(first, "") => first.parse().ok()?..length,
+		(first, last) => {
+			let (first, last): (u64, u64) = (first.parse().ok()?, last.parse().ok()?);
+			if last < first {
+				return None;
+			}
+			first..(last.saturating_add(1)).min(length)
+		}
+	};
+
+	Some((range.start < range.end).then_some(range))
+}
+
+/// Turn the headers of a full response into those for `range` of it
+pub fn set_partial(headers: &mut HeaderMap, range: &Range<u64>, length: u64) {
+	let content_range = format!("bytes {}-{}/{length}", range.start, range.end - 1);
+	headers.insert(
+		header::CONTENT_RANGE,
+		HeaderValue::from_str(&content_range).unwrap(),
+	);
+	headers.insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+}
+
+/// `416 Range Not Satisfiable` for a body of `length` bytes
+pub fn unsatisfiable(length: u64) -> Response<Body> {
+	let mut response = Response::new(Body::empty());
+	*response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+	response.headers_mut().insert(
+		header::CONTENT_RANGE,
+		HeaderValue::from_str(&format!("bytes */{length}")).unwrap(),
+	);
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn request(range: &str, if_range: Option<&str>) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+		if let Some(if_range) = if_range {
+			headers.insert(header::IF_RANGE, HeaderValue::from_str(if_range).unwrap());
+		}
+		headers
+	}
+
+	#[test]
+	fn single_byte_ranges_are_resolved_against_the_length() {
+		let etag = "\"abc\"";
+		let requested = |range| super::requested(&request(range, None), etag, 100);
+
+		assert_eq!(requested("bytes=0-9"), Requested::Partial(0..10));
+		assert_eq!(requested("bytes=90-"), Requested::Partial(90..100));
+		assert_eq!(requested("bytes=-10"), Requested::Partial(90..100));
+		assert_eq!(requested("bytes=-500"), Requested::Partial(0..100));
+		assert_eq!(requested("bytes=50-500"), Requested::Partial(50..100));
+		assert_eq!(requested("bytes=100-"), Requested::Unsatisfiable);
+		assert_eq!(requested("bytes=-0"), Requested::Unsatisfiable);
+		assert_eq!(requested("bytes=9-0"), Requested::Full);
+		assert_eq!(requested("bytes=0-1,5-6"), Requested::Full);
+		assert_eq!(requested("items=0-1"), Requested::Full);
+
+		assert_eq!(
+			super::requested(&HeaderMap::new(), etag, 100),
+			Requested::Full
+		);
+	}
+
+	#[test]
+	fn if_range_needs_a_strong_match() {
+		let range = "bytes=10-";
+
+		assert_eq!(
+			requested(&request(range, Some("\"abc\"")), "\"abc\"", 100),
+			Requested::Partial(10..100)
+		);
+		assert_eq!(
+			requested(&request(range, Some("\"old\"")), "\"abc\"", 100),
+			Requested::Full
+		);
+		assert_eq!(
+			requested(&request(range, Some("W/\"abc\"")), "W/\"abc\"", 100),
+			Requested::Full
+		);
+	}
+}
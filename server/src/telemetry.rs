@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+	extract::{MatchedPath, Request},
+	middleware::Next,
+	response::Response,
+};
+use common::ObjectType;
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+/// How often histograms are folded into the buckets `/metrics` reports
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+const LATENCY_BUCKETS: &[f64] = &[
+	0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+const SIZE_BUCKETS: &[f64] = &[
+	1024.0,
+	16.0 * 1024.0,
+	256.0 * 1024.0,
+	1024.0 * 1024.0,
+	16.0 * 1024.0 * 1024.0,
+	256.0 * 1024.0 * 1024.0,
+	1024.0 * 1024.0 * 1024.0,
+	4.0 * 1024.0 * 1024.0 * 1024.0,
+];
+
+fn builder() -> anyhow::Result<PrometheusBuilder> {
+	Ok(PrometheusBuilder::new()
+		.set_buckets_for_metric(Matcher::Suffix("_seconds".into()), LATENCY_BUCKETS)?
+		.set_buckets_for_metric(Matcher::Suffix("_bytes".into()), SIZE_BUCKETS)?)
+}
+
+/// Install the recorder every metric is reported to, returning the handle
+/// `/metrics` renders it with
+pub fn install() -> anyhow::Result<PrometheusHandle> {
+	let handle = builder()?.install_recorder()?;
+
+	tokio::spawn({
+		let handle = handle.clone();
+		async move {
+			let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+			loop {
+				interval.tick().await;
+				handle.run_upkeep();
+			}
+		}
+	});
+
+	Ok(handle)
+}
+
+/// Count and time requests by method, route and status. Routes are the
+/// patterns they matched, so hashes don't create a series per object. The
+/// time is until the response starts, not until its body is sent.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+	let route = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map(|path| path.as_str().to_owned())
+		.unwrap_or_else(|| "unmatched".to_owned());
+	let method = request.method().to_string();
+
+	let start = Instant::now();
+	let response = next.run(request).await;
+
+	counter!(
+		"arx_http_requests_total",
+		"method" => method.clone(),
+		"route" => route.clone(),
+		"status" => response.status().as_u16().to_string()
+	)
+	.increment(1);
+	histogram!(
+		"arx_http_request_duration_seconds",
+		"method" => method,
+		"route" => route
+	)
+	.record(start.elapsed().as_secs_f64());
+
+	response
+}
+
+/// An object upload. Uploads of objects the store already held are
+/// deduplicated, and their bodies are never read.
+pub fn record_upload(object_type: ObjectType, size: u64, deduplicated: bool) {
+	let result = if deduplicated {
+		"deduplicated"
+	} else {
+		"stored"
+	};
+
+	counter!(
+		"arx_object_uploads_total",
+		"type" => object_type.to_str(),
+		"result" => result
+	)
+	.increment(1);
+
+	if !deduplicated {
+		counter!("arx_object_upload_bytes_total", "type" => object_type.to_str()).increment(size);
+	}
+}
+
+pub fn record_download(object_type: ObjectType, size: u64) {
+	counter!("arx_object_downloads_total", "type" => object_type.to_str()).increment(1);
+	counter!("arx_object_download_bytes_total", "type" => object_type.to_str()).increment(size);
+}
+
+pub fn record_bundle(duration: Duration, size: usize) {
+	histogram!("arx_bundle_build_duration_seconds").record(duration.as_secs_f64());
+	histogram!("arx_bundle_size_bytes").record(size as f64);
+}
+
+/// A bundle request, by whether the bundle was already cached
+pub fn record_bundle_cache(hit: bool) {
+	let result = if hit { "hit" } else { "miss" };
+	counter!("arx_bundle_cache_requests_total", "result" => result).increment(1);
+}
+
+pub fn record_store_error() {
+	counter!("arx_store_errors_total").increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn uploads_are_reported_by_type_and_result() {
+		let recorder = builder().unwrap().build_recorder();
+		let handle = recorder.handle();
+
+		metrics::with_local_recorder(&recorder, || {
+			record_upload(ObjectType::Blob, 100, false);
+			record_upload(ObjectType::Blob, 100, true);
+			record_bundle(Duration::from_millis(20), 2048);
+		});
+
+		let rendered = handle.render();
+		assert!(rendered.contains(r#"arx_object_uploads_total{type="blob",result="stored"} 1"#));
+		assert!(
+			rendered.contains(r#"arx_object_uploads_total{type="blob",result="deduplicated"} 1"#)
+		);
+		assert!(rendered.contains(r#"arx_object_upload_bytes_total{type="blob"} 100"#));
+		assert!(rendered.contains(r#"arx_bundle_size_bytes_bucket{le="16384"} 1"#));
+	}
+}
@@ -0,0 +1,153 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+	crypto::ring,
+	pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+	server::WebPkiClientVerifier,
+	RootCertStore,
+};
+
+use crate::config::TlsConfig;
+
+/// Build the rustls config for `tls`, reading its certificates and keys from
+/// disk
+pub fn server_config(tls: &TlsConfig) -> anyhow::Result<Arc<rustls::ServerConfig>> {
+	let provider = Arc::new(ring::default_provider());
+
+	let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()?;
+
+	let builder = match &tls.client_ca {
+		None => builder.with_no_client_auth(),
+		Some(path) => {
+			let mut roots = RootCertStore::empty();
+			for cert in read_certificates(path)? {
+				roots.add(cert).with_context(|| {
+					format!("Invalid client CA certificate in {}", path.display())
+				})?;
+			}
+
+			let verifier =
+				WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+
+			builder.with_client_cert_verifier(verifier)
+		}
+	};
+
+	let key = PrivateKeyDer::from_pem_file(&tls.key)
+		.map_err(|err| anyhow!("Unable to read TLS key {}: {err}", tls.key.display()))?;
+
+	let mut config = builder
+		.with_single_cert(read_certificates(&tls.cert)?, key)
+		.with_context(|| format!("Invalid TLS certificate {}", tls.cert.display()))?;
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(Arc::new(config))
+}
+
+fn read_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+	let certs = CertificateDer::pem_file_iter(path)
+		.and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+		.map_err(|err| anyhow!("Unable to read certificates from {}: {err}", path.display()))?;
+
+	if certs.is_empty() {
+		return Err(anyhow!("No certificates found in {}", path.display()));
+	}
+
+	Ok(certs)
+}
+
+/// Reload the certificates in `tls` into `rustls` whenever the process
+/// receives SIGHUP. New connections use the reloaded certificates, while
+/// existing ones carry on with the certificates they were opened with. The
+/// current certificates are kept if the new ones can't be read.
+#[cfg(unix)]
+pub async fn reload_on_hangup(tls: TlsConfig, rustls: RustlsConfig) {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut hangups = match signal(SignalKind::hangup()) {
+		Ok(hangups) => hangups,
+		Err(err) => {
+			tracing::warn!(
+				"Unable to listen for SIGHUP, TLS certificates won't be reloaded: {err}"
+			);
+			return;
+		}
+	};
+
+	while hangups.recv().await.is_some() {
+		match server_config(&tls) {
+			Ok(config) => {
+				rustls.reload_from_config(config);
+				tracing::info!("Reloaded TLS certificates from {}", tls.cert.display());
+			}
+			Err(err) => tracing::error!(
+				"Unable to reload TLS certificates, keeping the current ones: {err:#}"
+			),
+		}
+	}
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_hangup(_: TlsConfig, _: RustlsConfig) {}
+
+#[cfg(test)]
+mod tests {
+	use rcgen::{generate_simple_self_signed, CertifiedKey};
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn write_certificate(dir: &TempDir, name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+		let CertifiedKey { cert, key_pair } =
+			generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+		let cert_path = dir.path().join(format!("{name}.pem"));
+		let key_path = dir.path().join(format!("{name}.key"));
+		std::fs::write(&cert_path, cert.pem()).unwrap();
+		std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+		(cert_path, key_path)
+	}
+
+	#[test]
+	fn certificates_are_read_with_and_without_client_verification() {
+		let dir = TempDir::new().unwrap();
+		let (cert, key) = write_certificate(&dir, "server");
+		let (client_ca, _) = write_certificate(&dir, "clients");
+
+		let mut tls = TlsConfig {
+			cert,
+			key,
+			client_ca: None,
+		};
+		server_config(&tls).unwrap();
+
+		tls.client_ca = Some(client_ca);
+		server_config(&tls).unwrap();
+	}
+
+	#[test]
+	fn mismatched_or_missing_files_are_rejected() {
+		let dir = TempDir::new().unwrap();
+		let (cert, _) = write_certificate(&dir, "server");
+		let (_, other_key) = write_certificate(&dir, "other");
+
+		let tls = TlsConfig {
+			cert: cert.clone(),
+			key: other_key,
+			client_ca: None,
+		};
+		assert!(server_config(&tls).is_err());
+
+		let tls = TlsConfig {
+			cert,
+			key: dir.path().join("missing.key"),
+			client_ca: None,
+		};
+		let err = server_config(&tls).unwrap_err();
+		assert!(err.to_string().contains("missing.key"), "{err}");
+	}
+}
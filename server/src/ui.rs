@@ -0,0 +1,369 @@
+use std::fmt::Write;
+
+use axum::{
+	extract::{Path as AxumPath, State},
+	http::{header, StatusCode},
+	response::{Html, IntoResponse, Redirect, Response},
+	routing::get,
+	Router,
+};
+use common::{signature::SIGNATURE_KEY, Hash, Mode};
+
+use crate::{
+	browse::{read_index, resolve, tree_entries},
+	catalog::IndexQuery,
+	find_object, index_listings, internal_error,
+	namespace::Namespace,
+	ServerState,
+};
+
+/// Most indexes listed on the home page, newest first
+const RECENT_INDEXES: usize = 100;
+
+const STYLESHEET: &str = include_str!("../assets/ui.css");
+
+/// Server rendered pages for browsing indexes, behind the same auth as the
+/// rest of the API
+pub fn routes() -> Router<ServerState> {
+	Router::new()
+		.route("/ui", get(home))
+		.route("/ui/", get(home))
+		.route("/ui/style.css", get(stylesheet))
+		.route("/ui/index/{index_id}", get(index_root))
+		.route("/ui/index/{index_id}/tree/{*path}", get(index_tree))
+}
+
+#[derive(serde::Deserialize)]
+struct IndexRoot {
+	index_id: Hash,
+}
+
+#[derive(serde::Deserialize)]
+struct IndexPath {
+	index_id: Hash,
+	path: String,
+}
+
+async fn stylesheet() -> impl IntoResponse {
+	([(header::CONTENT_TYPE, "text/css")], STYLESHEET)
+}
+
+/// Refs and the most recent indexes of the namespace
+async fn home(namespace: Namespace, State(state): State<ServerState>) -> Response {
+	let prefix = prefix(&namespace);
+
+	let result = async {
+		let refs = state
+			.namespaces
+			.refs(&namespace.0)
+			.await
+			.map_err(internal_error)?;
+		let query = IndexQuery {
+			limit: Some(RECENT_INDEXES),
+			..Default::default()
+		};
+		let indexes = index_listings(&state.catalog, &namespace, &query);
+
+		let mut body = String::from("<h2>Refs</h2>");
+		if refs.is_empty() {
+			body.push_str("<p class=\"muted\">No refs</p>");
+		} else {
+			body.push_str("<table><tr><th>Name</th><th>Index</th></tr>");
+			for (name, hash) in refs {
+				let _ = write!(
+					body,
+					"<tr><td>{}</td><td>{}</td></tr>",
+					escape(&name),
+					index_link(&prefix, &hash)
+				);
+			}
+			body.push_str("</table>");
+		}
+
+		body.push_str("<h2>Recent indexes</h2>");
+		if indexes.is_empty() {
+			body.push_str("<p class=\"muted\">No indexes</p>");
+		} else {
+			body.push_str("<table><tr><th>Uploaded</th><th>Index</th><th>Metadata</th></tr>");
+			for listing in indexes.iter().rev() {
+				let mut metadata: Vec<_> = listing
+					.metadata
+					.iter()
+					.filter(|(key, _)| key.as_str() != SIGNATURE_KEY)
+					.collect();
+				metadata.sort();
+
+				let _ = write!(
+					body,
+					"<tr><td>{}</td><td>{}</td><td class=\"metadata\">",
+					escape(&listing.timestamp),
+					index_link(&prefix, &listing.hash)
+				);
+				for (key, value) in metadata {
+					let _ = write!(body, "<span>{}: {}</span>", escape(key), escape(value));
+				}
+				body.push_str("</td></tr>");
+			}
+			body.push_str("</table>");
+		}
+
+		Ok(page("Indexes", &namespace, body))
+	}
+	.await;
+
+	result.unwrap_or_else(|err| error_page(&namespace, err))
+}
+
+async fn index_root(
+	AxumPath(IndexRoot { index_id }): AxumPath<IndexRoot>,
+	namespace: Namespace,
+	state: State<ServerState>,
+) -> Response {
+	tree_page(index_id, String::new(), namespace, state).await
+}
+
+async fn index_tree(
+	AxumPath(IndexPath { index_id, path }): AxumPath<IndexPath>,
+	namespace: Namespace,
+	state: State<ServerState>,
+) -> Response {
+	tree_page(index_id, path, namespace, state).await
+}
+
+/// An index's metadata and the directory at `path` in it. Files redirect to
+/// their contents.
+async fn tree_page(
+	index_hash: Hash,
+	path: String,
+	namespace: Namespace,
+	State(ServerState {
+		store,
+		namespaces,
+		inventory,
+		..
+	}): State<ServerState>,
+) -> Response {
+	let prefix = prefix(&namespace);
+	let path = path.trim_matches('/').to_string();
+
+	let result = async {
+		let object = find_object(&store, &inventory, &namespaces, &namespace, &index_hash).await?;
+		let index = read_index(&store, &index_hash, object.header).await?;
+		let (mode, tree) = resolve(
+			&store,
+			&namespaces,
+			&namespace,
+			&index_hash,
+			object.header,
+			&path,
+		)
+		.await?;
+
+		if !matches!(mode, Mode::Tree) {
+			return Ok(Redirect::to(&file_url(&prefix, &index_hash, &path)).into_response());
+		}
+
+		let entries = tree_entries(&store, &namespaces, &namespace, &tree).await?;
+
+		let mut body = format!(
+			"<h2 class=\"hash\">{index_hash}</h2><p><a href=\"{prefix}/bundle/{index_hash}\">Download bundle</a></p>"
+		);
+
+		let mut metadata: Vec<_> = index.metadata.iter().collect();
+		metadata.sort();
+		let _ = write!(
+			body,
+			"<table><tr><th>Uploaded</th><td>{}</td></tr>",
+			index.timestamp.to_rfc3339()
+		);
+		for (key, value) in metadata {
+			let value = match key.as_str() {
+				SIGNATURE_KEY => "signed".to_string(),
+				_ => escape(value),
+			};
+			let _ = write!(body, "<tr><th>{}</th><td>{value}</td></tr>", escape(key));
+		}
+		body.push_str("</table>");
+
+		let _ = write!(
+			body,
+			"<h3><a href=\"{}\">/</a>",
+			tree_url(&prefix, &index_hash, "")
+		);
+		let mut parent = String::new();
+		for name in path.split('/').filter(|name| !name.is_empty()) {
+			parent = join(&parent, name);
+			let _ = write!(
+				body,
+				"<a href=\"{}\">{}</a>/",
+				tree_url(&prefix, &index_hash, &parent),
+				escape(name)
+			);
+		}
+		body.push_str("</h3>");
+
+		body.push_str("<table><tr><th>Name</th><th class=\"size\">Size</th><th>Mode</th></tr>");
+		for entry in entries {
+			let entry_path = join(&path, &entry.name);
+			let (url, name) = match entry.size {
+				None => (
+					tree_url(&prefix, &index_hash, &entry_path),
+					format!("{}/", entry.name),
+				),
+				Some(_) => (file_url(&prefix, &index_hash, &entry_path), entry.name),
+			};
+
+			let _ = write!(
+				body,
+				"<tr><td><a href=\"{url}\">{}</a></td><td class=\"size\">{}</td><td><code>{}</code></td></tr>",
+				escape(&name),
+				entry.size.map(format_size).unwrap_or_default(),
+				entry.mode
+			);
+		}
+		body.push_str("</table>");
+
+		Ok(page(&short(&index_hash), &namespace, body))
+	}
+	.await;
+
+	result.unwrap_or_else(|err| error_page(&namespace, err))
+}
+
+fn page(title: &str, namespace: &Namespace, body: String) -> Response {
+	let prefix = prefix(namespace);
+
+	Html(format!(
+		"<!DOCTYPE html>\
+		<html><head><meta charset=\"utf-8\">\
+		<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+		<title>{} - arx</title>\
+		<link rel=\"stylesheet\" href=\"{prefix}/ui/style.css\">\
+		</head><body>\
+		<header><a href=\"{prefix}/ui/\">arx</a><span class=\"muted\">{}</span></header>\
+		{body}\
+		</body></html>",
+		escape(title),
+		escape(&namespace.0)
+	))
+	.into_response()
+}
+
+fn error_page(namespace: &Namespace, (status, message): (StatusCode, String)) -> Response {
+	let body = format!("<p class=\"error\">{}</p>", escape(&message));
+
+	(status, page(status.as_str(), namespace, body)).into_response()
+}
+
+/// Start of every URL in the namespace. The default namespace is served at
+/// the root.
+fn prefix(namespace: &Namespace) -> String {
+	match namespace.is_default() {
+		true => String::new(),
+		false => format!("/ns/{}", namespace.0),
+	}
+}
+
+fn index_link(prefix: &str, hash: &Hash) -> String {
+	format!(
+		"<a class=\"hash\" href=\"{}\">{}</a>",
+		tree_url(prefix, hash, ""),
+		short(hash)
+	)
+}
+
+fn tree_url(prefix: &str, hash: &Hash, path: &str) -> String {
+	match path {
+		"" => format!("{prefix}/ui/index/{hash}"),
+		path => format!("{prefix}/ui/index/{hash}/tree/{}/", encode_path(path)),
+	}
+}
+
+fn file_url(prefix: &str, hash: &Hash, path: &str) -> String {
+	format!("{prefix}/index/{hash}/file/{}", encode_path(path))
+}
+
+fn join(parent: &str, name: &str) -> String {
+	match parent {
+		"" => name.to_string(),
+		parent => format!("{parent}/{name}"),
+	}
+}
+
+fn short(hash: &Hash) -> String {
+	hash.as_str()[..12].to_string()
+}
+
+fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+	if bytes < 1024 {
+		return format!("{bytes} B");
+	}
+
+	let mut size = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	format!("{size:.1} {}", UNITS[unit])
+}
+
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Percent-encode a path for use in a URL, keeping its `/` separators
+fn encode_path(path: &str) -> String {
+	let mut encoded = String::with_capacity(path.len());
+	for byte in path.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+				encoded.push(byte as char)
+			}
+			byte => {
+				let _ = write!(encoded, "%{byte:02X}");
+			}
+		}
+	}
+	encoded
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_are_escaped_for_html_and_urls() {
+		assert_eq!(
+			escape("<script>\"a\" & 'b'"),
+			"&lt;script&gt;&quot;a&quot; &amp; &#39;b&#39;"
+		);
+		assert_eq!(
+			encode_path("reports/test #1?.html"),
+			"reports/test%20%231%3F.html"
+		);
+		assert_eq!(encode_path("ü"), "%C3%BC");
+	}
+
+	#[test]
+	fn sizes_are_shown_in_binary_units() {
+		assert_eq!(format_size(512), "512 B");
+		assert_eq!(format_size(1536), "1.5 KiB");
+		assert_eq!(format_size(200_000), "195.3 KiB");
+		assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+	}
+}
@@ -0,0 +1,146 @@
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	Arc, Mutex,
+};
+
+use axum::{body::Body, http::StatusCode};
+use common::{Hash, Header};
+use futures::{future::ready, AsyncBufRead, TryStreamExt};
+use sha2::{Digest, Sha256};
+
+/// Checks the body of an upload as it streams in. Quotas are checked against
+/// the declared `Object-Size`, so only uploads whose body turns out to be that
+/// long are kept and counted. The body is hashed along the way, so it can be
+/// checked against the hash it was uploaded as.
+#[derive(Clone)]
+pub struct UploadCheck {
+	size: u64,
+	received: Arc<AtomicU64>,
+	hasher: Arc<Mutex<Sha256>>,
+}
+
+impl UploadCheck {
+	pub fn new(header: Header) -> Self {
+		Self {
+			size: header.size,
+			received: Default::default(),
+			hasher: Arc::new(Mutex::new(Sha256::new_with_prefix(header.to_string()))),
+		}
+	}
+
+	/// The body as a reader, which fails as soon as it runs past the declared
+	/// size rather than storing however much the client sends
+	pub fn reader(&self, body: Body) -> impl AsyncBufRead + Unpin + Send {
+		let check = self.clone();
+
+		body.into_data_stream()
+			.map_err(std::io::Error::other)
+			.and_then(move |chunk| {
+				let received = check
+					.received
+					.fetch_add(chunk.len() as u64, Ordering::Relaxed)
+					+ chunk.len() as u64;
+
+				ready(match received > check.size {
+					true => Err(std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						"Body is longer than its Object-Size",
+					)),
+					false => {
+						check.hasher().update(&chunk);
+						Ok(chunk)
+					}
+				})
+			})
+			.into_async_read()
+	}
+
+	/// Whether the body read through [`UploadCheck::reader`] was exactly as
+	/// long as declared
+	pub fn finish(&self) -> Result<(), (StatusCode, String)> {
+		let received = self.received.load(Ordering::Relaxed);
+
+		match received == self.size {
+			true => Ok(()),
+			false if received > self.size => Err((
+				StatusCode::BAD_REQUEST,
+				format!("Body is longer than its Object-Size of {}", self.size),
+			)),
+			false => Err((
+				StatusCode::BAD_REQUEST,
+				format!(
+					"Body of {received} bytes is shorter than its Object-Size of {}",
+					self.size
+				),
+			)),
+		}
+	}
+
+	/// [`UploadCheck::finish`], and whether the object, header included,
+	/// hashes to `expected`
+	pub fn verify(&self, expected: &Hash) -> Result<(), (StatusCode, String)> {
+		self.finish()?;
+
+		let digest = Hash::from(self.hasher().clone());
+		match digest == *expected {
+			true => Ok(()),
+			false => Err((
+				StatusCode::BAD_REQUEST,
+				format!("Body hashes to {digest}, not {expected}"),
+			)),
+		}
+	}
+
+	fn hasher(&self) -> std::sync::MutexGuard<'_, Sha256> {
+		self.hasher
+			.lock()
+			.expect("upload hasher lock to not be poisoned")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use common::ObjectType;
+	use futures::AsyncReadExt;
+
+	use super::*;
+
+	async fn upload(size: u64, body: &'static [u8]) -> (std::io::Result<Vec<u8>>, UploadCheck) {
+		let check = UploadCheck::new(Header::new(ObjectType::Blob, size));
+		let mut data = Vec::new();
+		let read = check
+			.reader(Body::from(body))
+			.read_to_end(&mut data)
+			.await
+			.map(|_| data);
+
+		(read, check)
+	}
+
+	#[tokio::test]
+	async fn bodies_must_be_as_long_as_declared() {
+		let (read, check) = upload(5, b"hello").await;
+		assert_eq!(read.unwrap(), b"hello");
+		assert!(check.finish().is_ok());
+
+		let (read, check) = upload(0, b"hello").await;
+		assert!(read.is_err());
+		assert_eq!(check.finish().unwrap_err().0, StatusCode::BAD_REQUEST);
+
+		let (read, check) = upload(10, b"hello").await;
+		assert!(read.is_ok());
+		assert_eq!(check.finish().unwrap_err().0, StatusCode::BAD_REQUEST);
+	}
+
+	#[tokio::test]
+	async fn bodies_must_hash_to_their_object() {
+		let hash = Hash::from(Sha256::new_with_prefix(b"blob 5\0hello"));
+
+		let (_, check) = upload(5, b"hello").await;
+		assert!(check.verify(&hash).is_ok());
+
+		let (_, check) = upload(5, b"world").await;
+		assert!(check.finish().is_ok());
+		assert_eq!(check.verify(&hash).unwrap_err().0, StatusCode::BAD_REQUEST);
+	}
+}